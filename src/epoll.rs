@@ -0,0 +1,190 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+// event bits, as in <sys/epoll.h>
+pub const READABLE: u32 = 0x001;
+pub const WRITABLE: u32 = 0x004;
+pub const ERROR: u32 = 0x008;
+pub const HANGUP: u32 = 0x010;
+
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+const EFD_CLOEXEC: i32 = 0o2000000;
+const EFD_NONBLOCK: i32 = 0o4000;
+const EINTR: i32 = 4;
+
+// struct epoll_event, which the kernel packs on x86_64
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+pub struct Event {
+    events: u32,
+    token: u64,
+}
+
+impl Event {
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    pub fn is(&self, events: u32) -> bool {
+        let ready = self.events;
+        ready & events != 0
+    }
+}
+
+unsafe extern "C" {
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut Event) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut Event, maxevents: i32, timeout: i32) -> i32;
+    fn eventfd(initval: u32, flags: i32) -> i32;
+}
+
+fn check(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// Level-triggered readiness for file descriptors, each registered with a token.
+pub struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    pub fn new() -> io::Result<Self> {
+        let fd = check(unsafe { epoll_create1(EPOLL_CLOEXEC) })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    pub fn modify(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    pub fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: i32, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = Event { events, token };
+        check(unsafe { epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    // Fills `events` with what is ready, waiting at most `timeout`. An interrupted
+    // wait returns no events.
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let result = unsafe {
+            epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity().min(i32::MAX as usize) as i32,
+                timeout,
+            )
+        };
+        match check(result) {
+            Ok(n) => {
+                unsafe { events.set_len(n as usize) };
+                Ok(())
+            }
+            Err(e) if e.raw_os_error() == Some(EINTR) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// A counter other threads can bump to make an Epoll waiting on it return.
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        let fd = check(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) })?;
+        Ok(Self {
+            file: File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    pub fn notify(&self) -> io::Result<()> {
+        (&self.file).write_all(&1u64.to_ne_bytes())
+    }
+
+    // resets the counter, so the descriptor is no longer readable
+    pub fn clear(&self) -> io::Result<()> {
+        let mut count = [0; 8];
+        match (&self.file).read(&mut count) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn readiness_test() {
+        let epoll = Epoll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        epoll.add(server.as_raw_fd(), 7, READABLE).unwrap();
+        let mut events = Vec::with_capacity(8);
+
+        epoll.wait(&mut events, Duration::from_millis(10)).unwrap();
+        assert!(events.is_empty());
+        (&client).write_all(b"x").unwrap();
+        epoll.wait(&mut events, Duration::from_secs(1)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(7, events[0].token());
+        assert!(events[0].is(READABLE));
+        assert!(!events[0].is(WRITABLE));
+
+        epoll.modify(server.as_raw_fd(), 8, WRITABLE).unwrap();
+        epoll.wait(&mut events, Duration::from_secs(1)).unwrap();
+        assert_eq!(8, events[0].token());
+        assert!(events[0].is(WRITABLE));
+        epoll.delete(server.as_raw_fd()).unwrap();
+        epoll.wait(&mut events, Duration::from_millis(10)).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn event_fd_test() {
+        let epoll = Epoll::new().unwrap();
+        let event_fd = EventFd::new().unwrap();
+        epoll.add(event_fd.as_raw_fd(), 1, READABLE).unwrap();
+        let mut events = Vec::with_capacity(8);
+        event_fd.notify().unwrap();
+        event_fd.notify().unwrap();
+        epoll.wait(&mut events, Duration::from_secs(1)).unwrap();
+        assert_eq!(1, events.len());
+        event_fd.clear().unwrap();
+        epoll.wait(&mut events, Duration::from_millis(10)).unwrap();
+        assert!(events.is_empty());
+    }
+}
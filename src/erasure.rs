@@ -0,0 +1,310 @@
+use crate::checksum::Crc32c;
+use crate::reed_solomon::ReedSolomon;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+
+// Shard file layout:
+//   header: magic (4) | shard index (1) | k (1) | m (1) | reserved (1) | object size u64 le (8)
+//   blocks: for every stripe, crc32c u32 le (4) | BLOCK_SIZE bytes
+// A stripe is k * BLOCK_SIZE bytes of object data, the last one zero padded.
+const MAGIC: &[u8; 4] = b"LIOS";
+const HEADER_LEN: u64 = 16;
+pub const BLOCK_SIZE: usize = 64 * 1024;
+const RECORD_LEN: u64 = 4 + BLOCK_SIZE as u64;
+
+fn block_crc(block: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(block);
+    crc.finish()
+}
+
+pub struct ShardWriter {
+    rs: ReedSolomon,
+    shards: Vec<File>,
+    stripe: Vec<u8>,
+    size: u64,
+}
+
+impl ShardWriter {
+    pub fn create(rs: &ReedSolomon, paths: &[PathBuf]) -> io::Result<Self> {
+        let mut shards = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut file = File::create(path)?;
+            file.write_all(&[0; HEADER_LEN as usize])?;
+            shards.push(file);
+        }
+        Ok(Self {
+            rs: rs.clone(),
+            shards,
+            stripe: Vec::with_capacity(rs.data_shards() * BLOCK_SIZE),
+            size: 0,
+        })
+    }
+
+    // writes the buffered partial stripe and the headers, must be called once all data is written
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.stripe.is_empty() {
+            self.flush_stripe()?;
+        }
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            let mut header = [0u8; HEADER_LEN as usize];
+            header[0..4].copy_from_slice(MAGIC);
+            header[4] = i as u8;
+            header[5] = self.rs.data_shards() as u8;
+            header[6] = (self.rs.total_shards() - self.rs.data_shards()) as u8;
+            header[8..16].copy_from_slice(&self.size.to_le_bytes());
+            shard.seek(SeekFrom::Start(0))?;
+            shard.write_all(&header)?;
+            shard.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn flush_stripe(&mut self) -> io::Result<()> {
+        self.stripe.resize(self.rs.data_shards() * BLOCK_SIZE, 0);
+        let mut blocks = self
+            .stripe
+            .chunks(BLOCK_SIZE)
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        blocks.resize(self.rs.total_shards(), vec![0; BLOCK_SIZE]);
+        self.rs.encode(&mut blocks);
+        for (shard, block) in self.shards.iter_mut().zip(&blocks) {
+            shard.write_all(&block_crc(block).to_le_bytes())?;
+            shard.write_all(block)?;
+        }
+        self.stripe.clear();
+        Ok(())
+    }
+}
+
+impl Write for ShardWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let stripe_len = self.rs.data_shards() * BLOCK_SIZE;
+        let take = buf.len().min(stripe_len - self.stripe.len());
+        self.stripe.extend_from_slice(&buf[..take]);
+        self.size += take as u64;
+        if self.stripe.len() == stripe_len {
+            self.flush_stripe()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ShardReader {
+    rs: ReedSolomon,
+    shards: Vec<Option<File>>,
+    size: u64,
+    next_stripe: u64,
+    stripe: Vec<u8>,
+    stripe_pos: usize,
+    degraded: bool,
+    // told about the object the first time a missing or corrupt shard is found
+    on_degraded: Option<(Sender<PathBuf>, PathBuf)>,
+}
+
+impl ShardReader {
+    pub fn open(
+        rs: &ReedSolomon,
+        paths: &[PathBuf],
+        on_degraded: Option<(Sender<PathBuf>, PathBuf)>,
+    ) -> io::Result<Self> {
+        let mut shards = Vec::with_capacity(paths.len());
+        let mut size = None;
+        let mut degraded = false;
+        for (i, path) in paths.iter().enumerate() {
+            match Self::open_shard(path, i) {
+                Ok((file, shard_size)) => {
+                    size = size.or(Some(shard_size));
+                    shards.push(Some(file));
+                }
+                Err(e) => {
+                    if e.kind() != ErrorKind::NotFound {
+                        println!("shard {:?} is not readable: {}", path, e);
+                    }
+                    degraded = true;
+                    shards.push(None);
+                }
+            }
+        }
+        let present = shards.iter().filter(|s| s.is_some()).count();
+        if present == 0 {
+            return Err(io::Error::new(ErrorKind::NotFound, "object does not exist"));
+        }
+        if present < rs.data_shards() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} of {} shards available", present, rs.total_shards()),
+            ));
+        }
+        let mut reader = Self {
+            rs: rs.clone(),
+            shards,
+            size: size.unwrap_or(0),
+            next_stripe: 0,
+            stripe: Vec::new(),
+            stripe_pos: 0,
+            degraded: false,
+            on_degraded,
+        };
+        if degraded {
+            reader.mark_degraded();
+        }
+        Ok(reader)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    fn open_shard(path: &PathBuf, index: usize) -> io::Result<(File, u64)> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC || header[4] as usize != index {
+            return Err(io::Error::new(ErrorKind::InvalidData, "bad shard header"));
+        }
+        let size = u64::from_le_bytes(header[8..16].try_into().expect("8 bytes"));
+        Ok((file, size))
+    }
+
+    fn mark_degraded(&mut self) {
+        if !self.degraded {
+            self.degraded = true;
+            if let Some((sender, path)) = &self.on_degraded {
+                sender.send(path.clone()).unwrap_or_else(|e| {
+                    println!("cannot queue object for healing: {}", e);
+                });
+            }
+        }
+    }
+
+    fn read_block(file: &mut File, stripe: u64) -> io::Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(HEADER_LEN + stripe * RECORD_LEN))?;
+        let mut crc = [0u8; 4];
+        file.read_exact(&mut crc)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        file.read_exact(&mut block)?;
+        if u32::from_le_bytes(crc) != block_crc(&block) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "shard block checksum mismatch"));
+        }
+        Ok(block)
+    }
+
+    fn read_stripe(&mut self) -> io::Result<()> {
+        let stripe = self.next_stripe;
+        let mut blocks = Vec::with_capacity(self.shards.len());
+        let mut missing = false;
+        for shard in self.shards.iter_mut() {
+            let block = match shard {
+                Some(file) => Self::read_block(file, stripe).ok(),
+                None => None,
+            };
+            missing |= block.is_none();
+            blocks.push(block);
+        }
+        if missing {
+            self.mark_degraded();
+            self.rs
+                .reconstruct(&mut blocks)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+        let stripe_len = (self.rs.data_shards() * BLOCK_SIZE) as u64;
+        let remaining = self.size - stripe * stripe_len;
+        self.stripe.clear();
+        for block in blocks.into_iter().take(self.rs.data_shards()) {
+            self.stripe.extend_from_slice(&block.expect("reconstructed block"));
+        }
+        self.stripe.truncate(remaining.min(stripe_len) as usize);
+        self.stripe_pos = 0;
+        self.next_stripe += 1;
+        Ok(())
+    }
+}
+
+impl Read for ShardReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.stripe_pos == self.stripe.len() {
+            let stripe_len = (self.rs.data_shards() * BLOCK_SIZE) as u64;
+            if self.next_stripe * stripe_len >= self.size {
+                return Ok(0);
+            }
+            self.read_stripe()?;
+        }
+        let n = buf.len().min(self.stripe.len() - self.stripe_pos);
+        buf[..n].copy_from_slice(&self.stripe[self.stripe_pos..self.stripe_pos + n]);
+        self.stripe_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn shard_paths(dir: &str, n: usize) -> Vec<PathBuf> {
+        let dir = std::env::temp_dir().join(dir);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        (0..n).map(|i| dir.join(format!("shard{}", i))).collect()
+    }
+
+    fn write(rs: &ReedSolomon, paths: &[PathBuf], data: &[u8]) {
+        let mut writer = ShardWriter::create(rs, paths).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn read(rs: &ReedSolomon, paths: &[PathBuf]) -> (Vec<u8>, bool) {
+        let mut reader = ShardReader::open(rs, paths, None).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        (out, reader.degraded())
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let rs = ReedSolomon::new(3, 2).unwrap();
+        let paths = shard_paths("lightio_erasure_round_trip", 5);
+        let data = (0..BLOCK_SIZE * 7 + 123).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        write(&rs, &paths, &data);
+
+        assert_eq!((data, false), read(&rs, &paths));
+    }
+
+    #[test]
+    fn missing_and_corrupt_shards_test() {
+        let rs = ReedSolomon::new(3, 2).unwrap();
+        let paths = shard_paths("lightio_erasure_degraded", 5);
+        let data = (0..BLOCK_SIZE * 4 + 5).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        write(&rs, &paths, &data);
+
+        fs::remove_file(&paths[0]).unwrap();
+        let mut corrupt = fs::read(&paths[3]).unwrap();
+        corrupt[HEADER_LEN as usize + 10] ^= 0xff;
+        fs::write(&paths[3], corrupt).unwrap();
+
+        assert_eq!((data, true), read(&rs, &paths));
+    }
+
+    #[test]
+    fn empty_object_test() {
+        let rs = ReedSolomon::new(2, 1).unwrap();
+        let paths = shard_paths("lightio_erasure_empty", 3);
+        write(&rs, &paths, b"");
+
+        assert_eq!((Vec::new(), false), read(&rs, &paths));
+    }
+}
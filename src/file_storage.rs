@@ -1,66 +1,1410 @@
+use crate::checksum::{base64_decode, ChecksumAlgorithm};
+use crate::erasure::{ShardReader, ShardWriter};
+use crate::hash_ring::HashRing;
+use crate::object_cache::{CacheStats, CachedObject, ObjectCache};
+use crate::reed_solomon::ReedSolomon;
+use crate::storage_io::{UringIo, UringWriter, BUFFER_SIZE};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io, thread};
 use std::fs::File;
-use std::ops::Deref;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const SYSTEM_DIR: &str = ".lightio";
+const META_DIR: &str = ".lightio/meta";
+// files being written next to the object they replace, scans skip them
+pub const TEMP_EXTENSION: &str = "lightio-tmp";
 
 pub struct FileStorageConfig {
-    data_path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    erasure: Option<(usize, usize)>,
+    heal_interval: Duration,
+    cold_tier: Option<ColdTier>,
+    cache: Option<(u64, u64)>,
+    io_engine: IoEngine,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum IoEngine {
+    // plain blocking reads and writes on the calling thread
+    #[default]
+    Blocking,
+    // object files are written, and small ones read, through io_uring, see storage_io
+    IoUring,
 }
 
 impl FileStorageConfig {
     pub fn new() -> Self {
         Self {
-            data_path: PathBuf::from("./data"),
+            data_paths: vec![PathBuf::from("./data")],
+            erasure: None,
+            heal_interval: Duration::from_secs(600),
+            cold_tier: None,
+            cache: Some((64 * 1024 * 1024, 256 * 1024)),
+            io_engine: IoEngine::Blocking,
         }
     }
 
     #[allow(dead_code)]
     pub fn data_path(mut self, data_path: String) -> Self {
-        self.data_path = PathBuf::from(data_path);
+        self.data_paths = vec![PathBuf::from(data_path)];
+        self
+    }
+
+    pub fn data_paths(mut self, data_paths: Vec<String>) -> Self {
+        self.data_paths = data_paths.into_iter().map(PathBuf::from).collect();
         self
     }
+
+    // splits every object into data + parity shards, one per data path
+    pub fn erasure_coding(mut self, data_shards: usize, parity_shards: usize) -> Self {
+        self.erasure = Some((data_shards, parity_shards));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn heal_interval(mut self, heal_interval: Duration) -> Self {
+        self.heal_interval = heal_interval;
+        self
+    }
+
+    // objects not read for max_idle move from the data paths (hot tier) to cold_path
+    pub fn cold_tier(mut self, cold_path: String, max_idle: Duration) -> Self {
+        self.cold_tier = Some(ColdTier {
+            path: PathBuf::from(cold_path),
+            max_idle,
+            promote_on_read: false,
+            scan_interval: Duration::from_secs(3600),
+        });
+        self
+    }
+
+    // moves cold objects back to the hot tier when they are read, needs cold_tier
+    pub fn promote_on_read(mut self, promote_on_read: bool) -> Self {
+        if let Some(cold_tier) = self.cold_tier.as_mut() {
+            cold_tier.promote_on_read = promote_on_read;
+        }
+        self
+    }
+
+    // keeps objects up to max_object_size in memory, max_bytes of 0 disables the cache
+    pub fn cache(mut self, max_bytes: u64, max_object_size: u64) -> Self {
+        self.cache = (max_bytes > 0).then_some((max_bytes, max_object_size));
+        self
+    }
+
+    // falls back to blocking I/O if the kernel does not offer io_uring
+    pub fn io_engine(mut self, io_engine: IoEngine) -> Self {
+        self.io_engine = io_engine;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn tier_scan_interval(mut self, scan_interval: Duration) -> Self {
+        if let Some(cold_tier) = self.cold_tier.as_mut() {
+            cold_tier.scan_interval = scan_interval;
+        }
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ColdTier {
+    path: PathBuf,
+    max_idle: Duration,
+    promote_on_read: bool,
+    scan_interval: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum StorageTier {
+    #[default]
+    Hot,
+    Cold,
+}
+
+impl StorageTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageTier::Hot => "hot",
+            StorageTier::Cold => "cold",
+        }
+    }
+
+    fn from_str(tier: &str) -> Option<StorageTier> {
+        match tier {
+            "hot" => Some(StorageTier::Hot),
+            "cold" => Some(StorageTier::Cold),
+            _ => None,
+        }
+    }
+}
+
+// access times are rewritten at most this often to keep reads cheap
+const ACCESS_TIME_RESOLUTION: u64 = 3600;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// A temporary file next to `path`, unique to this process and call.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}-{}.{}", name, std::process::id(), n, TEMP_EXTENSION))
+}
+
+enum Writer {
+    File(File),
+    Erasure(ShardWriter),
+    Uring(UringWriter),
+}
+
+impl Writer {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Writer::File(_) => Ok(()),
+            Writer::Erasure(writer) => writer.finish(),
+            Writer::Uring(writer) => writer.finish(),
+        }
+    }
+}
+
+// Writes a new object into temporary files. FileStorage::commit_file moves them
+// over the object, an object writer dropped before that removes them.
+pub struct ObjectWriter {
+    // None once finished
+    writer: Option<Writer>,
+    // temporary and final path of each file not renamed yet
+    staged: Vec<(PathBuf, PathBuf)>,
+}
+
+impl ObjectWriter {
+    fn commit(mut self) -> io::Result<()> {
+        self.writer.take().expect("object writer committed once").finish()?;
+        while let Some((temp, target)) = self.staged.last() {
+            fs::rename(temp, target)?;
+            self.staged.pop();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        for (temp, _) in &self.staged {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer.as_mut().expect("object writer not committed") {
+            Writer::File(file) => file.write(buf),
+            Writer::Erasure(writer) => writer.write(buf),
+            Writer::Uring(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut().expect("object writer not committed") {
+            Writer::File(file) => file.flush(),
+            Writer::Erasure(writer) => writer.flush(),
+            Writer::Uring(writer) => writer.flush(),
+        }
+    }
+}
+
+pub enum ObjectReader {
+    File(File),
+    Erasure(ShardReader),
+    Memory(Cursor<Arc<[u8]>>),
+}
+
+impl ObjectReader {
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            ObjectReader::File(file) => Ok(file.metadata()?.len()),
+            ObjectReader::Erasure(reader) => Ok(reader.len()),
+            ObjectReader::Memory(cursor) => Ok(cursor.get_ref().len() as u64),
+        }
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ObjectReader::File(file) => file.read(buf),
+            ObjectReader::Erasure(reader) => reader.read(buf),
+            ObjectReader::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+struct ObjectLock<'a> {
+    storage: &'a FileStorage,
+    path: PathBuf,
 }
 
+impl Drop for ObjectLock<'_> {
+    fn drop(&mut self) {
+        self.storage.locked.lock().unwrap().remove(&self.path);
+        self.storage.unlocked.notify_all();
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ObjectMeta {
+    pub size: u64,
+    pub checksums: Vec<(ChecksumAlgorithm, String)>,
+    // unix seconds of the last read, 0 if unknown
+    pub accessed: u64,
+    pub tier: StorageTier,
+}
+
+impl ObjectMeta {
+    pub fn new(size: u64, checksums: Vec<(ChecksumAlgorithm, String)>) -> Self {
+        Self {
+            size,
+            checksums,
+            accessed: now_secs(),
+            tier: StorageTier::Hot,
+        }
+    }
+
+    // hex md5 of the content, as S3 does for single part uploads
+    pub fn etag(&self) -> Option<String> {
+        let (_, md5) = self.checksums.iter().find(|(alg, _)| *alg == ChecksumAlgorithm::Md5)?;
+        let md5 = base64_decode(md5)?;
+        Some(md5.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = format!("size: {}\n", self.size);
+        out.push_str(&format!("accessed: {}\n", self.accessed));
+        out.push_str(&format!("tier: {}\n", self.tier.as_str()));
+        for (alg, value) in &self.checksums {
+            out.push_str(&format!("{}: {}\n", alg.name(), value));
+        }
+        out
+    }
+
+    fn parse(data: &str) -> ObjectMeta {
+        let mut meta = ObjectMeta::default();
+        for line in data.lines() {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            if key == "size" {
+                meta.size = value.parse().unwrap_or(0);
+            } else if key == "accessed" {
+                meta.accessed = value.parse().unwrap_or(0);
+            } else if key == "tier" {
+                meta.tier = StorageTier::from_str(value).unwrap_or_default();
+            } else if let Some(alg) = ChecksumAlgorithm::from_name(key) {
+                meta.checksums.push((alg, value.to_string()));
+            }
+        }
+        meta
+    }
+}
+
+// Objects are spread over data_paths by consistent hashing of "bucket/object".
+// With erasure coding the ring picks the data path of the first shard and the
+// other shards follow on the next data paths. Buckets exist in every data path.
+// With a cold tier, idle objects are moved out of the data paths into a single
+// cold folder as plain files and read from there until promoted back.
 #[derive(Debug)]
 pub struct FileStorage {
-    data_path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    ring: HashRing,
+    erasure: Option<ReedSolomon>,
+    heal_interval: Duration,
+    heal_sender: Sender<PathBuf>,
+    heal_receiver: Mutex<Option<Receiver<PathBuf>>>,
+    cold_tier: Option<ColdTier>,
+    // objects being copied to the hot tier, read from the cold tier until done
+    promoting: Mutex<HashSet<PathBuf>>,
+    // objects being replaced, deleted, healed or moved between tiers, see lock_object
+    locked: Mutex<HashSet<PathBuf>>,
+    unlocked: Condvar,
+    tier_sender: Sender<PathBuf>,
+    tier_receiver: Mutex<Option<Receiver<PathBuf>>>,
+    cache: Option<ObjectCache>,
+    uring: Option<Arc<UringIo>>,
 }
 
 impl FileStorage {
-    pub fn new(FileStorageConfig { data_path }: FileStorageConfig) -> Result<Self, io::Error> {
-        let path = Path::new(&data_path);
-        if !path.exists() {
-            Self::create_dir(&path)?
+    pub fn new(
+        FileStorageConfig { data_paths, erasure, heal_interval, cold_tier, cache, io_engine }: FileStorageConfig,
+    ) -> Result<Self, io::Error> {
+        if data_paths.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "no data paths configured"));
+        }
+        let erasure = match erasure {
+            Some((data_shards, parity_shards)) => {
+                let rs = ReedSolomon::new(data_shards, parity_shards)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+                if rs.total_shards() > data_paths.len() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} shards need as many data paths", rs.total_shards()),
+                    ));
+                }
+                Some(rs)
+            }
+            None => None,
+        };
+        for path in data_paths.iter().chain(cold_tier.as_ref().map(|c| &c.path)) {
+            if !path.exists() {
+                Self::create_dir(path)?
+            }
+        }
+        let nodes = data_paths
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let (heal_sender, heal_receiver) = mpsc::channel();
+        let (tier_sender, tier_receiver) = mpsc::channel();
+        let uring = match io_engine {
+            IoEngine::Blocking => None,
+            IoEngine::IoUring => match UringIo::new() {
+                Ok(uring) => Some(Arc::new(uring)),
+                Err(e) => {
+                    println!("io_uring is not available, using blocking I/O: {}", e);
+                    None
+                }
+            },
+        };
+        Ok(Self {
+            ring: HashRing::new(&nodes),
+            data_paths,
+            erasure,
+            heal_interval,
+            heal_sender,
+            heal_receiver: Mutex::new(Some(heal_receiver)),
+            cold_tier,
+            promoting: Mutex::new(HashSet::new()),
+            locked: Mutex::new(HashSet::new()),
+            unlocked: Condvar::new(),
+            tier_sender,
+            tier_receiver: Mutex::new(Some(tier_receiver)),
+            cache: cache.map(|(max_bytes, max_object_size)| ObjectCache::new(max_bytes, max_object_size)),
+            uring,
+        })
+    }
+
+    // The object stays as it was until the writer is passed to commit_file.
+    pub fn create_file(&self, new_file_path: &Path) -> Result<ObjectWriter, io::Error> {
+        self.create_hot(new_file_path)
+    }
+
+    // Replaces the object and its metadata with what was written, once the caller
+    // checked it.
+    pub fn commit_file(&self, path: &Path, writer: ObjectWriter, meta: &ObjectMeta) -> io::Result<()> {
+        let _lock = self.lock_object(path);
+        writer.commit()?;
+        self.invalidate(path);
+        self.remove_stray_copies(path)?;
+        if let Some(cold_tier) = &self.cold_tier {
+            Self::remove_if_exists(&cold_tier.path.join(path))?;
+            Self::remove_if_exists(&cold_tier.path.join(META_DIR).join(path))?;
+        }
+        self.write_hot_meta(path, meta)
+    }
+
+    // Keeps uploads, deletes, heals and tier moves of one object apart until the
+    // guard is dropped.
+    fn lock_object(&self, path: &Path) -> ObjectLock<'_> {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(path) {
+            locked = self.unlocked.wait(locked).unwrap();
         }
-        Ok(Self { data_path: PathBuf::from(path) })
+        locked.insert(path.to_path_buf());
+        ObjectLock { storage: self, path: path.to_path_buf() }
     }
 
-    pub fn create_file(&self, new_file_path: &Path) -> Result<File, io::Error> {
-        let real_path = self.data_path.join(new_file_path);
-        File::create(real_path)
+    fn create_hot(&self, new_file_path: &Path) -> Result<ObjectWriter, io::Error> {
+        let real_paths = match &self.erasure {
+            Some(_) => self.shard_paths(new_file_path),
+            None => vec![self.placement(new_file_path).join(new_file_path)],
+        };
+        // the bucket folder may be missing on a data path added after the bucket was created
+        if let Some(bucket) = new_file_path.components().next()
+            && self.bucket_exists(Path::new(&bucket))
+        {
+            for parent in real_paths.iter().filter_map(|p| p.parent()) {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let staged = real_paths.into_iter().map(|path| (temp_path(&path), path)).collect::<Vec<_>>();
+        let temp_paths = staged.iter().map(|(temp, _)| temp.clone()).collect::<Vec<_>>();
+        // made first, so the files created so far go if one cannot be
+        let mut writer = ObjectWriter { writer: None, staged };
+        writer.writer = Some(match &self.erasure {
+            Some(rs) => Writer::Erasure(ShardWriter::create(rs, &temp_paths)?),
+            None => {
+                let file = File::create(&temp_paths[0])?;
+                match &self.uring {
+                    Some(uring) => Writer::Uring(UringWriter::new(file, Arc::clone(uring))),
+                    None => Writer::File(file),
+                }
+            }
+        });
+        Ok(writer)
+    }
+
+    #[allow(dead_code)]
+    pub fn delete_file(&self, path: &Path) -> io::Result<()> {
+        let _lock = self.lock_object(path);
+        let hot = self.delete_hot(path);
+        let Some(cold_tier) = &self.cold_tier else {
+            return hot;
+        };
+        let cold = cold_tier.path.join(path);
+        if !cold.exists() {
+            return hot;
+        }
+        Self::remove_if_exists(&cold_tier.path.join(META_DIR).join(path))?;
+        fs::remove_file(cold)
+    }
+
+    fn delete_hot(&self, path: &Path) -> io::Result<()> {
+        self.invalidate(path);
+        if self.erasure.is_some() {
+            let mut found = false;
+            for data_path in self.shard_dirs(path) {
+                let _ = fs::remove_file(data_path.join(META_DIR).join(path));
+                found |= fs::remove_file(data_path.join(path)).is_ok();
+            }
+            return if found {
+                Ok(())
+            } else {
+                Err(io::Error::new(ErrorKind::NotFound, "object does not exist"))
+            };
+        }
+        // copies a rebalance has not moved yet would come back otherwise
+        let mut found = false;
+        for data_path in &self.data_paths {
+            Self::remove_if_exists(&data_path.join(META_DIR).join(path))?;
+            match fs::remove_file(data_path.join(path)) {
+                Ok(()) => found = true,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(io::Error::new(ErrorKind::NotFound, "object does not exist"))
+        }
     }
+
+    // Removes the copies of an object on data paths other than its placement, left
+    // there when a data path was added and the object was not rebalanced yet.
+    fn remove_stray_copies(&self, path: &Path) -> io::Result<()> {
+        if self.erasure.is_some() {
+            return Ok(());
+        }
+        let placement = self.placement(path);
+        for data_path in self.data_paths.iter().filter(|data_path| *data_path != placement) {
+            Self::remove_if_exists(&data_path.join(path))?;
+            Self::remove_if_exists(&data_path.join(META_DIR).join(path))?;
+        }
+        Ok(())
+    }
+
     pub fn create_bucket(&self, name: &Path) -> io::Result<()> {
-        Self::create_dir(&self.data_path.join(name).deref())
+        for data_path in self.all_paths() {
+            Self::create_dir(&data_path.join(name))?;
+        }
+        Ok(())
     }
 
     pub fn bucket_exists(&self, path: &Path) -> bool {
-        self.data_path.join(path).exists()
+        self.data_paths.iter().any(|data_path| data_path.join(path).exists())
     }
 
     pub fn delete_bucket(&self, name: &Path) -> io::Result<()> {
-        fs::remove_dir_all(self.data_path.join(name).deref())
+        if !self.bucket_exists(name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "bucket does not exist"));
+        }
+        if let Some(cache) = &self.cache {
+            cache.invalidate_prefix(name);
+        }
+        for data_path in self.all_paths() {
+            for dir in [data_path.join(META_DIR).join(name), data_path.join(name)] {
+                if dir.exists() {
+                    fs::remove_dir_all(dir)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn open_file(&self, path: &Path) -> io::Result<ObjectReader> {
+        let Some(cache) = &self.cache else {
+            return self.read_small(self.open_uncached(path)?);
+        };
+        if let Some(cached) = cache.get(path) {
+            if self.cold_tier.is_some() {
+                self.touch(path, &Ok(cached.meta));
+            }
+            return Ok(ObjectReader::Memory(Cursor::new(cached.data)));
+        }
+        // an upload committed while this reads makes the insert below a no-op
+        let generation = cache.generation(path);
+        let mut reader = self.open_uncached(path)?;
+        let Ok(meta) = self.read_meta(path) else {
+            return self.read_small(reader);
+        };
+        // a concurrent upload may have changed the file since the metadata was written
+        if !cache.cacheable(meta.size) || reader.len()? != meta.size {
+            return self.read_small(reader);
+        }
+        let data = match (&self.uring, &mut reader) {
+            (Some(uring), ObjectReader::File(file)) => uring.read_at(file, 0, meta.size as usize)?,
+            _ => {
+                let mut data = Vec::with_capacity(meta.size as usize);
+                reader.read_to_end(&mut data)?;
+                data
+            }
+        };
+        if data.len() as u64 != meta.size {
+            return Ok(ObjectReader::Memory(Cursor::new(data.into())));
+        }
+        let data: Arc<[u8]> = data.into();
+        cache.insert(path, CachedObject { meta, data: Arc::clone(&data) }, generation);
+        Ok(ObjectReader::Memory(Cursor::new(data)))
+    }
+
+    // With io_uring, plain files that fit one operation are read whole, so reads
+    // from concurrent requests go to the kernel together. Larger files stay open
+    // and are sent with sendfile, which already skips user space.
+    fn read_small(&self, reader: ObjectReader) -> io::Result<ObjectReader> {
+        let (Some(uring), ObjectReader::File(file)) = (&self.uring, &reader) else {
+            return Ok(reader);
+        };
+        let len = file.metadata()?.len();
+        if len > BUFFER_SIZE as u64 {
+            return Ok(reader);
+        }
+        let data = uring.read_at(file, 0, len as usize)?;
+        Ok(ObjectReader::Memory(Cursor::new(data.into())))
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    fn open_uncached(&self, path: &Path) -> io::Result<ObjectReader> {
+        let Some(cold_tier) = &self.cold_tier else {
+            return self.open_hot(path);
+        };
+        let promoting = self.promoting.lock().unwrap().contains(path);
+        if !promoting {
+            match self.open_hot(path) {
+                Ok(reader) => {
+                    self.touch(path, &self.read_hot_meta(path));
+                    return Ok(reader);
+                }
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                Err(_) => {}
+            }
+        }
+        let reader = File::open(cold_tier.path.join(path))?;
+        if cold_tier.promote_on_read && !promoting {
+            self.tier_sender.send(path.to_path_buf()).unwrap_or_else(|e| {
+                println!("cannot queue object for promotion: {}", e);
+            });
+        } else if !promoting {
+            self.touch(path, &self.read_cold_meta(path));
+        }
+        Ok(ObjectReader::File(reader))
+    }
+
+    fn open_hot(&self, path: &Path) -> io::Result<ObjectReader> {
+        match &self.erasure {
+            Some(rs) => {
+                let notify = (self.heal_sender.clone(), path.to_path_buf());
+                let reader = ShardReader::open(rs, &self.shard_paths(path), Some(notify))?;
+                Ok(ObjectReader::Erasure(reader))
+            }
+            None => Ok(ObjectReader::File(File::open(self.locate(path).join(path))?)),
+        }
+    }
+
+    pub fn write_meta(&self, path: &Path, meta: &ObjectMeta) -> io::Result<()> {
+        self.invalidate(path);
+        if meta.tier == StorageTier::Cold
+            && let Some(cold_tier) = &self.cold_tier
+        {
+            return Self::write_meta_file(&cold_tier.path.join(META_DIR).join(path), meta);
+        }
+        self.write_hot_meta(path, meta)
+    }
+
+    pub fn read_meta(&self, path: &Path) -> io::Result<ObjectMeta> {
+        if let Some(cached) = self.cache.as_ref().and_then(|cache| cache.peek(path)) {
+            return Ok(cached.meta);
+        }
+        match self.read_hot_meta(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound && self.cold_tier.is_some() => {
+                self.read_cold_meta(path)
+            }
+            result => result,
+        }
+    }
+
+    fn write_meta_file(meta_path: &Path, meta: &ObjectMeta) -> io::Result<()> {
+        if let Some(parent) = meta_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(meta_path, meta.serialize())
+    }
+
+    fn write_hot_meta(&self, path: &Path, meta: &ObjectMeta) -> io::Result<()> {
+        self.invalidate(path);
+        let data_paths = match &self.erasure {
+            Some(_) => self.shard_dirs(path),
+            None => vec![self.locate(path)],
+        };
+        for data_path in data_paths {
+            Self::write_meta_file(&data_path.join(META_DIR).join(path), meta)?;
+        }
+        Ok(())
+    }
+
+    fn read_cold_meta(&self, path: &Path) -> io::Result<ObjectMeta> {
+        let cold_tier = self.cold_tier.as_ref().expect("cold tier configured");
+        fs::read_to_string(cold_tier.path.join(META_DIR).join(path))
+            .map(|data| ObjectMeta::parse(&data))
+    }
+
+    fn read_hot_meta(&self, path: &Path) -> io::Result<ObjectMeta> {
+        let data_paths = match &self.erasure {
+            Some(_) => self.shard_dirs(path),
+            None => vec![self.locate(path)],
+        };
+        let mut result = Err(io::Error::new(ErrorKind::NotFound, "metadata does not exist"));
+        for data_path in data_paths {
+            result = fs::read_to_string(data_path.join(META_DIR).join(path));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result.map(|data| ObjectMeta::parse(&data))
+    }
+
+    // Rewrites the shards of an object when any of them is missing or corrupt.
+    // Returns whether the object needed healing.
+    pub fn heal_object(&self, path: &Path) -> io::Result<bool> {
+        let Some(rs) = &self.erasure else {
+            return Ok(false);
+        };
+        let shard_paths = self.shard_paths(path);
+        // most objects are intact, checking them does not hold up their uploads
+        if !Self::degraded(rs, &shard_paths)? {
+            return Ok(false);
+        }
+        // an upload or delete may have run since, its shards must not be overwritten
+        let _lock = self.lock_object(path);
+        let Ok(meta) = self.read_hot_meta(path) else {
+            return Ok(false);
+        };
+        match Self::degraded(rs, &shard_paths) {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        println!("healing object {:?}", path);
+        let tmp_paths = shard_paths
+            .iter()
+            .map(|p| temp_path(p))
+            .collect::<Vec<_>>();
+        for parent in tmp_paths.iter().filter_map(|p| p.parent()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut reader = ShardReader::open(rs, &shard_paths, None)?;
+        let mut writer = ShardWriter::create(rs, &tmp_paths)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+        for (tmp, shard) in tmp_paths.iter().zip(&shard_paths) {
+            fs::rename(tmp, shard)?;
+        }
+        self.write_hot_meta(path, &meta)?;
+        Ok(true)
+    }
+
+    // whether any shard of an object is missing or corrupt, read through to the end
+    fn degraded(rs: &ReedSolomon, shard_paths: &[PathBuf]) -> io::Result<bool> {
+        let mut reader = ShardReader::open(rs, shard_paths, None)?;
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(reader.degraded())
+    }
+
+    pub fn heal_all(&self) -> io::Result<usize> {
+        let mut objects = HashSet::new();
+        for data_path in &self.data_paths {
+            let mut found = Vec::new();
+            Self::collect_objects(data_path, Path::new(""), &mut found)?;
+            objects.extend(found);
+        }
+        let mut healed = 0;
+        for object in objects {
+            match self.heal_object(&object) {
+                Ok(true) => healed += 1,
+                Ok(false) => {}
+                Err(e) => eprintln!("cannot heal object {:?}: {}", object, e),
+            }
+        }
+        Ok(healed)
+    }
+
+    // Heals objects reported by degraded reads right away and scans every object
+    // each heal_interval. Does nothing without erasure coding.
+    pub fn start_healer(&'static self) {
+        if self.erasure.is_none() {
+            return;
+        }
+        let Some(receiver) = self.heal_receiver.lock().unwrap().take() else {
+            return;
+        };
+        thread::spawn(move || {
+            loop {
+                match receiver.recv_timeout(self.heal_interval) {
+                    Ok(path) => {
+                        if let Err(e) = self.heal_object(&path) {
+                            eprintln!("cannot heal object {:?}: {}", path, e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => match self.heal_all() {
+                        Ok(healed) => println!("heal scan finished, {} objects healed", healed),
+                        Err(e) => eprintln!("heal scan failed: {}", e),
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    }
+
+    // Moves objects that were not read for max_idle to the cold tier.
+    // Returns the number of objects moved.
+    pub fn demote_idle(&self) -> io::Result<usize> {
+        let Some(cold_tier) = &self.cold_tier else {
+            return Ok(0);
+        };
+        let mut objects = HashSet::new();
+        for data_path in &self.data_paths {
+            let mut found = Vec::new();
+            Self::collect_objects(data_path, Path::new(""), &mut found)?;
+            objects.extend(found);
+        }
+        let now = now_secs();
+        let mut demoted = 0;
+        for object in objects {
+            let meta = self.read_hot_meta(&object).unwrap_or_default();
+            let accessed = match meta.accessed {
+                0 => self.modified_secs(&object),
+                accessed => accessed,
+            };
+            if now.saturating_sub(accessed) < cold_tier.max_idle.as_secs() {
+                continue;
+            }
+            match self.demote(&object, meta) {
+                Ok(true) => demoted += 1,
+                Ok(false) => {}
+                Err(e) => eprintln!("cannot move {:?} to the cold tier: {}", object, e),
+            }
+        }
+        Ok(demoted)
+    }
+
+    // False if the object changed since the scan found it idle, it stays hot then.
+    fn demote(&self, path: &Path, meta: ObjectMeta) -> io::Result<bool> {
+        let cold_tier = self.cold_tier.as_ref().expect("cold tier configured");
+        let _lock = self.lock_object(path);
+        let modified = self.modified(path);
+        // read again, as writes that do not take the lock only touch the metadata
+        let unchanged = || self.read_hot_meta(path).unwrap_or_default() == meta && self.modified(path) == modified;
+        if !unchanged() {
+            return Ok(false);
+        }
+        println!("moving {:?} to the cold tier", path);
+        let mut reader = self.open_hot(path)?;
+        let cold_path = cold_tier.path.join(path);
+        if let Some(parent) = cold_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = temp_path(&cold_path);
+        let copied = (|| {
+            let mut cold_file = File::create(&temp)?;
+            io::copy(&mut reader, &mut cold_file)?;
+            cold_file.sync_all()
+        })();
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        if !unchanged() {
+            println!("{:?} changed while moving it to the cold tier", path);
+            fs::remove_file(&temp)?;
+            return Ok(false);
+        }
+        fs::rename(&temp, &cold_path)?;
+        let meta = ObjectMeta { tier: StorageTier::Cold, ..meta };
+        Self::write_meta_file(&cold_tier.path.join(META_DIR).join(path), &meta)?;
+        // readers look at the hot tier first, so the cold copy is complete before this
+        self.delete_hot(path)?;
+        Ok(true)
+    }
+
+    fn promote(&self, path: &Path) -> io::Result<()> {
+        let cold_tier = self.cold_tier.as_ref().expect("cold tier configured");
+        let cold_path = cold_tier.path.join(path);
+        // an upload may have replaced the object while this waited
+        let _lock = self.lock_object(path);
+        if !cold_path.exists() {
+            return Ok(());
+        }
+        println!("moving {:?} to the hot tier", path);
+        self.promoting.lock().unwrap().insert(path.to_path_buf());
+        let result = (|| {
+            let mut meta = self.read_cold_meta(path).unwrap_or_default();
+            let mut writer = self.create_hot(path)?;
+            io::copy(&mut File::open(&cold_path)?, &mut writer)?;
+            writer.commit()?;
+            self.invalidate(path);
+            self.remove_stray_copies(path)?;
+            meta.tier = StorageTier::Hot;
+            meta.accessed = now_secs();
+            self.write_hot_meta(path, &meta)
+        })();
+        self.promoting.lock().unwrap().remove(path);
+        result?;
+        Self::remove_if_exists(&cold_tier.path.join(META_DIR).join(path))?;
+        fs::remove_file(cold_path)
+    }
+
+    // Promotes objects queued by reads right away and moves idle objects to the
+    // cold tier each scan interval. Does nothing without a cold tier.
+    pub fn start_tiering(&'static self) {
+        let Some(cold_tier) = &self.cold_tier else {
+            return;
+        };
+        let Some(receiver) = self.tier_receiver.lock().unwrap().take() else {
+            return;
+        };
+        thread::spawn(move || {
+            loop {
+                match receiver.recv_timeout(cold_tier.scan_interval) {
+                    Ok(path) => {
+                        if let Err(e) = self.promote(&path) {
+                            eprintln!("cannot move {:?} to the hot tier: {}", path, e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => match self.demote_idle() {
+                        Ok(moved) => println!("tier scan finished, {} objects moved to cold", moved),
+                        Err(e) => eprintln!("tier scan failed: {}", e),
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    }
+
+    // Records a read in the metadata, at most once per ACCESS_TIME_RESOLUTION.
+    // `seen` is the metadata read without the lock, an upload or tier move may
+    // have replaced it since.
+    fn touch(&self, path: &Path, seen: &io::Result<ObjectMeta>) {
+        let Ok(seen) = seen else {
+            return;
+        };
+        let now = now_secs();
+        if now.saturating_sub(seen.accessed) < ACCESS_TIME_RESOLUTION {
+            return;
+        }
+        let _lock = self.lock_object(path);
+        let current = match seen.tier {
+            StorageTier::Hot => self.read_hot_meta(path),
+            StorageTier::Cold => self.read_cold_meta(path),
+        };
+        let Ok(current) = current else {
+            return;
+        };
+        if current.tier != seen.tier
+            || current.size != seen.size
+            || now.saturating_sub(current.accessed) < ACCESS_TIME_RESOLUTION
+        {
+            return;
+        }
+        let meta = ObjectMeta { accessed: now, ..current };
+        self.write_meta(path, &meta).unwrap_or_else(|e| {
+            println!("cannot update access time of {:?}: {}", path, e);
+        });
+    }
+
+    // latest modification time of the object's files in the hot tier
+    fn modified(&self, path: &Path) -> Option<SystemTime> {
+        let dirs = match &self.erasure {
+            Some(_) => self.shard_dirs(path),
+            None => vec![self.locate(path)],
+        };
+        dirs.iter()
+            .filter_map(|dir| fs::metadata(dir.join(path)).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    fn modified_secs(&self, path: &Path) -> u64 {
+        self.modified(path)
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs())
+    }
+
+    // Moves every object to the data path chosen by the ring. `drain` lists data
+    // paths that were removed from the configuration and must be emptied.
+    pub fn rebalance(&self, drain: &[PathBuf]) -> io::Result<usize> {
+        if self.erasure.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "rebalance is not supported with erasure coding",
+            ));
+        }
+        let mut moved = 0;
+        for source in self.data_paths.iter().chain(drain.iter()) {
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() && entry.file_name() != SYSTEM_DIR {
+                    self.create_bucket(Path::new(&entry.file_name()))?;
+                }
+            }
+            let mut objects = Vec::new();
+            Self::collect_objects(source, Path::new(""), &mut objects)?;
+            for object in objects {
+                let target = self.placement(&object);
+                if target == source {
+                    continue;
+                }
+                let _lock = self.lock_object(&object);
+                // an upload may have replaced it while this waited
+                if !source.join(&object).exists() {
+                    continue;
+                }
+                let meta = source.join(META_DIR).join(&object);
+                // uploads go to the placement, so a copy there is the newer one
+                if target.join(&object).exists() {
+                    println!("dropping stale copy of {:?} on {:?}", object, source);
+                    Self::remove_if_exists(&source.join(&object))?;
+                    Self::remove_if_exists(&meta)?;
+                    continue;
+                }
+                println!("moving {:?} from {:?} to {:?}", object, source, target);
+                Self::move_file(&source.join(&object), &target.join(&object))?;
+                if meta.exists() {
+                    Self::move_file(&meta, &target.join(META_DIR).join(&object))?;
+                }
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    fn invalidate(&self, path: &Path) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(path);
+        }
+    }
+
+    // data paths plus the cold tier folder
+    fn all_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.data_paths
+            .iter()
+            .chain(self.cold_tier.as_ref().map(|c| &c.path))
+    }
+
+    fn remove_if_exists(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // data path the ring assigns to an object
+    fn placement(&self, path: &Path) -> &PathBuf {
+        &self.data_paths[self.ring.locate(&path.to_string_lossy())]
+    }
+
+    // data paths holding the shards of an object, in shard order
+    fn shard_dirs(&self, path: &Path) -> Vec<&PathBuf> {
+        let total = self.erasure.as_ref().map_or(1, |rs| rs.total_shards());
+        let first = self.ring.locate(&path.to_string_lossy());
+        (0..total)
+            .map(|i| &self.data_paths[(first + i) % self.data_paths.len()])
+            .collect()
+    }
+
+    fn shard_paths(&self, path: &Path) -> Vec<PathBuf> {
+        self.shard_dirs(path).iter().map(|dir| dir.join(path)).collect()
+    }
+
+    // data path currently holding an object: its placement, or any other data path
+    // while a rebalance has not moved it yet
+    fn locate(&self, path: &Path) -> &PathBuf {
+        let placement = self.placement(path);
+        if placement.join(path).exists() {
+            return placement;
+        }
+        self.data_paths
+            .iter()
+            .find(|data_path| data_path.join(path).is_file())
+            .unwrap_or(placement)
     }
 
-    pub fn open_file(&self, path: &Path) -> io::Result<File> {
-       let path = self.data_path.join(path); 
-       File::open(path)
+    fn collect_objects(root: &Path, dir: &Path, objects: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(root.join(dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            if relative == Path::new(SYSTEM_DIR) || relative.extension() == Some(TEMP_EXTENSION.as_ref()) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                Self::collect_objects(root, &relative, objects)?;
+            } else if relative.parent() != Some(Path::new("")) {
+                objects.push(relative);
+            }
+        }
+        Ok(())
     }
-   
+
+    fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // rename fails across file systems, which is the usual case for separate disks
+        if fs::rename(from, to).is_err() {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+        }
+        Ok(())
+    }
+
     fn create_dir(path: &Path) -> io::Result<()> {
         if !&path.exists() {
             println!("creating data folder {:?}", path);
-            fs::create_dir(&path)?;
+            fs::create_dir(path)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(name: &str, max_idle: Duration) -> FileStorage {
+        let root = test_root(name);
+        let path = |dir: &str| root.join(dir).to_string_lossy().to_string();
+        let config = FileStorageConfig::new()
+            .data_paths(vec![path("hot")])
+            .cold_tier(path("cold"), max_idle);
+        let storage = FileStorage::new(config).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        storage
+    }
+
+    fn put(storage: &FileStorage, path: &Path, data: &[u8]) {
+        let mut writer = storage.create_file(path).unwrap();
+        writer.write_all(data).unwrap();
+        storage.commit_file(path, writer, &ObjectMeta::new(data.len() as u64, Vec::new())).unwrap();
+    }
+
+    fn get(storage: &FileStorage, path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // storage over the given data paths under a fresh test folder
+    fn spread(root: &Path, dirs: &[&str]) -> FileStorageConfig {
+        let paths = dirs.iter().map(|dir| root.join(dir).to_string_lossy().to_string());
+        FileStorageConfig::new().data_paths(paths.collect())
+    }
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn count_objects(data_path: &Path) -> usize {
+        let mut objects = Vec::new();
+        FileStorage::collect_objects(data_path, Path::new(""), &mut objects).unwrap();
+        objects.len()
+    }
+
+    #[test]
+    fn rebalance_after_adding_and_draining_data_paths_test() {
+        let root = test_root("lightio_rebalance");
+        let objects = (0..40)
+            .map(|i| (PathBuf::from(format!("b/o{}", i)), format!("object {}", i).into_bytes()))
+            .collect::<Vec<_>>();
+        let storage = FileStorage::new(spread(&root, &["d0", "d1", "d2"])).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        for (path, data) in &objects {
+            put(&storage, path, data);
+            assert!(storage.placement(path).join(path).is_file());
+        }
+        assert!(storage.data_paths.iter().all(|dir| count_objects(dir) > 0));
+
+        // objects are found where they are until the rebalance moves them
+        let storage = FileStorage::new(spread(&root, &["d0", "d1", "d2", "d3"])).unwrap();
+        for (path, data) in &objects {
+            assert_eq!(*data, get(&storage, path));
+        }
+        let moved = storage.rebalance(&[]).unwrap();
+        assert!(moved > 0);
+        // only objects placed on the new data path move
+        assert_eq!(moved, count_objects(&root.join("d3")));
+        assert_eq!(0, storage.rebalance(&[]).unwrap());
+        for (path, data) in &objects {
+            assert!(storage.placement(path).join(path).is_file());
+            assert_eq!(*data, get(&storage, path));
+            assert_eq!(data.len() as u64, storage.read_meta(path).unwrap().size);
+        }
+
+        let storage = FileStorage::new(spread(&root, &["d0", "d2", "d3"])).unwrap();
+        let drained = root.join("d1");
+        let remaining = count_objects(&drained);
+        assert_eq!(remaining, storage.rebalance(std::slice::from_ref(&drained)).unwrap());
+        assert_eq!(0, count_objects(&drained));
+        for (path, data) in &objects {
+            assert!(storage.placement(path).join(path).is_file());
+            assert_eq!(*data, get(&storage, path));
+            assert_eq!(data.len() as u64, storage.read_meta(path).unwrap().size);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn uploads_and_deletes_before_a_rebalance_test() {
+        let root = test_root("lightio_rebalance_pending");
+        let paths = (0..40).map(|i| PathBuf::from(format!("b/o{}", i))).collect::<Vec<_>>();
+        let storage = FileStorage::new(spread(&root, &["d0", "d1"])).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        for path in &paths {
+            put(&storage, path, b"old");
+        }
+
+        let storage = FileStorage::new(spread(&root, &["d0", "d1", "d2"])).unwrap();
+        let mut moving = paths.iter().filter(|path| *storage.placement(path) == root.join("d2"));
+        let (replaced, deleted) = (moving.next().unwrap(), moving.next().unwrap());
+        put(&storage, replaced, b"new");
+        put(&storage, deleted, b"new");
+        storage.delete_file(deleted).unwrap();
+        let copies = |path: &Path| storage.data_paths.iter().filter(|dir| dir.join(path).exists()).count();
+        assert_eq!(1, copies(replaced));
+        assert_eq!(0, copies(deleted));
+        assert_eq!(b"new".to_vec(), get(&storage, replaced));
+        assert_eq!(ErrorKind::NotFound, storage.open_file(deleted).err().unwrap().kind());
+
+        storage.rebalance(&[]).unwrap();
+        assert_eq!(b"new".to_vec(), get(&storage, replaced));
+        assert_eq!(3, storage.read_meta(replaced).unwrap().size);
+        assert_eq!(ErrorKind::NotFound, storage.open_file(deleted).err().unwrap().kind());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn heal_restores_lost_and_corrupt_shards_test() {
+        let root = test_root("lightio_heal");
+        let config = spread(&root, &["d0", "d1", "d2"]).erasure_coding(2, 1).cache(0, 0);
+        let storage = FileStorage::new(config).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        let data = (0..200_003).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (lost, corrupt, intact) = (Path::new("b/lost"), Path::new("b/corrupt"), Path::new("b/intact"));
+        for path in [lost, corrupt, intact] {
+            put(&storage, path, &data);
+        }
+
+        fs::remove_file(&storage.shard_paths(lost)[0]).unwrap();
+        // the last block is zero padded and checksummed like the others
+        let shard = &storage.shard_paths(corrupt)[2];
+        let mut bytes = fs::read(shard).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(shard, bytes).unwrap();
+        assert_eq!(data, get(&storage, lost));
+        assert_eq!(data, get(&storage, corrupt));
+
+        assert!(storage.heal_object(lost).unwrap());
+        assert!(!storage.heal_object(intact).unwrap());
+        assert_eq!(1, storage.heal_all().unwrap());
+        assert_eq!(0, storage.heal_all().unwrap());
+
+        // with another shard gone reads need the healed ones
+        for path in [lost, corrupt, intact] {
+            fs::remove_file(&storage.shard_paths(path)[1]).unwrap();
+            assert_eq!(data, get(&storage, path));
+            assert_eq!(data.len() as u64, storage.read_meta(path).unwrap().size);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn heal_leaves_replaced_and_deleted_objects_alone_test() {
+        let root = test_root("lightio_heal_race");
+        let config = spread(&root, &["d0", "d1", "d2"]).erasure_coding(2, 1).cache(0, 0);
+        let storage = FileStorage::new(config).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        let (replaced, deleted) = (Path::new("b/replaced"), Path::new("b/deleted"));
+        for path in [replaced, deleted] {
+            put(&storage, path, b"old");
+            fs::remove_file(&storage.shard_paths(path)[0]).unwrap();
+        }
+
+        // the upload and the delete finish while heal waits for the object
+        thread::scope(|scope| {
+            let lock = storage.lock_object(replaced);
+            let healer = scope.spawn(|| storage.heal_object(replaced).unwrap());
+            thread::sleep(Duration::from_millis(100));
+            let mut writer = storage.create_file(replaced).unwrap();
+            writer.write_all(b"new").unwrap();
+            writer.commit().unwrap();
+            storage.write_hot_meta(replaced, &ObjectMeta::new(3, Vec::new())).unwrap();
+            drop(lock);
+            assert!(!healer.join().unwrap());
+
+            let lock = storage.lock_object(deleted);
+            let healer = scope.spawn(|| storage.heal_object(deleted).unwrap());
+            thread::sleep(Duration::from_millis(100));
+            storage.delete_hot(deleted).unwrap();
+            drop(lock);
+            assert!(!healer.join().unwrap());
+        });
+        assert_eq!(b"new".to_vec(), get(&storage, replaced));
+        assert!(storage.shard_paths(deleted).iter().all(|shard| !shard.exists()));
+        assert_eq!(ErrorKind::NotFound, storage.open_file(deleted).err().unwrap().kind());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn idle_objects_move_to_cold_tier_test() {
+        let storage = storage("lightio_tier_demote", Duration::ZERO);
+        let path = Path::new("b/o");
+        put(&storage, path, b"hello");
+
+        assert_eq!(1, storage.demote_idle().unwrap());
+        assert_eq!(StorageTier::Cold, storage.read_meta(path).unwrap().tier);
+        assert_eq!(b"hello".to_vec(), get(&storage, path));
+
+        storage.promote(path).unwrap();
+        assert_eq!(StorageTier::Hot, storage.read_meta(path).unwrap().tier);
+        assert_eq!(b"hello".to_vec(), get(&storage, path));
+    }
+
+    #[test]
+    fn uploads_replace_objects_on_commit_test() {
+        let storage = storage("lightio_upload_commit", Duration::from_secs(3600));
+        let path = Path::new("b/o");
+        put(&storage, path, b"hello");
+
+        let mut writer = storage.create_file(path).unwrap();
+        writer.write_all(b"half written").unwrap();
+        // readers keep getting the old object until the upload is committed
+        assert_eq!(b"hello".to_vec(), get(&storage, path));
+        drop(writer);
+        assert_eq!(b"hello".to_vec(), get(&storage, path));
+
+        let mut writer = storage.create_file(path).unwrap();
+        writer.write_all(b"new").unwrap();
+        storage.commit_file(path, writer, &ObjectMeta::new(3, Vec::new())).unwrap();
+        assert_eq!(b"new".to_vec(), get(&storage, path));
+        let mut objects = Vec::new();
+        FileStorage::collect_objects(&storage.data_paths[0], Path::new(""), &mut objects).unwrap();
+        assert_eq!(vec![path.to_path_buf()], objects);
+        assert_eq!(1, fs::read_dir(storage.data_paths[0].join("b")).unwrap().count());
+    }
+
+    #[test]
+    fn objects_replaced_after_the_scan_stay_hot_test() {
+        let storage = storage("lightio_tier_replaced", Duration::ZERO);
+        let path = Path::new("b/o");
+        put(&storage, path, b"hello");
+        let scanned = storage.read_hot_meta(path).unwrap();
+
+        put(&storage, path, b"replaced");
+        assert!(!storage.demote(path, scanned).unwrap());
+        assert_eq!(StorageTier::Hot, storage.read_meta(path).unwrap().tier);
+        assert_eq!(b"replaced".to_vec(), get(&storage, path));
+        let cold = storage.cold_tier.as_ref().unwrap().path.join("b");
+        assert_eq!(0, fs::read_dir(cold).map_or(0, |dir| dir.count()));
+    }
+
+    #[test]
+    fn access_times_keep_newer_metadata_test() {
+        let storage = storage("lightio_tier_touch", Duration::ZERO);
+        let path = Path::new("b/o");
+        put(&storage, path, b"hello");
+        let stale = ObjectMeta { accessed: 0, ..storage.read_meta(path).unwrap() };
+
+        // a read that saw the old metadata finishes after the upload
+        let checksums = vec![(ChecksumAlgorithm::Md5, "fw==".to_string())];
+        let mut writer = storage.create_file(path).unwrap();
+        writer.write_all(b"world").unwrap();
+        storage.commit_file(path, writer, &ObjectMeta::new(5, checksums.clone())).unwrap();
+        storage.touch(path, &Ok(stale));
+        let meta = storage.read_meta(path).unwrap();
+        assert_eq!(checksums, meta.checksums);
+
+        // and after a move to the cold tier
+        let stale = ObjectMeta { accessed: 0, ..meta };
+        assert!(storage.demote(path, storage.read_hot_meta(path).unwrap()).unwrap());
+        storage.touch(path, &Ok(stale));
+        assert_eq!(ErrorKind::NotFound, storage.read_hot_meta(path).err().unwrap().kind());
+        assert_eq!(StorageTier::Cold, storage.read_meta(path).unwrap().tier);
+        assert_eq!(b"world".to_vec(), get(&storage, path));
+    }
+
+    #[test]
+    fn recently_read_objects_stay_hot_test() {
+        let storage = storage("lightio_tier_keep", Duration::from_secs(3600));
+        let path = Path::new("b/o");
+        put(&storage, path, b"hello");
+
+        assert_eq!(0, storage.demote_idle().unwrap());
+        assert_eq!(StorageTier::Hot, storage.read_meta(path).unwrap().tier);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn io_uring_engine_test() {
+        let root = std::env::temp_dir().join("lightio_io_uring_engine");
+        let _ = fs::remove_dir_all(&root);
+        let config = FileStorageConfig::new()
+            .data_path(root.to_string_lossy().to_string())
+            .cache(0, 0)
+            .io_engine(IoEngine::IoUring);
+        let storage = FileStorage::new(config).unwrap();
+        storage.create_bucket(Path::new("b")).unwrap();
+        let small = b"small object".to_vec();
+        let large = (0..BUFFER_SIZE * 5 + 7).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        put(&storage, Path::new("b/small"), &small);
+        put(&storage, Path::new("b/large"), &large);
+
+        assert!(matches!(storage.open_file(Path::new("b/small")).unwrap(), ObjectReader::Memory(_)));
+        assert_eq!(small, get(&storage, Path::new("b/small")));
+        assert!(matches!(storage.open_file(Path::new("b/large")).unwrap(), ObjectReader::File(_)));
+        assert!(get(&storage, Path::new("b/large")) == large);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+const VIRTUAL_NODES: u32 = 128;
+
+// consistent hash ring: each node owns VIRTUAL_NODES points, a key belongs to the
+// first point clockwise from its hash, so adding or removing a node only moves
+// the keys that land on that node's points
+#[derive(Debug)]
+pub struct HashRing {
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String]) -> Self {
+        let mut points = Vec::with_capacity(nodes.len() * VIRTUAL_NODES as usize);
+        for (i, node) in nodes.iter().enumerate() {
+            for v in 0..VIRTUAL_NODES {
+                points.push((hash(format!("{}#{}", node, v).as_bytes()), i));
+            }
+        }
+        points.sort();
+        Self { points }
+    }
+
+    pub fn locate(&self, key: &str) -> usize {
+        let h = hash(key.as_bytes());
+        let idx = self.points.partition_point(|(point, _)| *point < h);
+        self.points[idx % self.points.len()].1
+    }
+}
+
+// fnv-1a followed by a murmur3 finalizer to spread short keys over the whole ring
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("/data{}", i)).collect()
+    }
+
+    #[test]
+    fn spreads_keys_test() {
+        let ring = HashRing::new(&nodes(4));
+        let mut counts = [0; 4];
+        for i in 0..10_000 {
+            counts[ring.locate(&format!("bucket/object-{}", i))] += 1;
+        }
+        for count in counts {
+            assert!(count > 1_500, "unbalanced ring: {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn adding_node_moves_few_keys_test() {
+        let before = HashRing::new(&nodes(4));
+        let after = HashRing::new(&nodes(5));
+        let mut moved = 0;
+        for i in 0..10_000 {
+            let key = format!("bucket/object-{}", i);
+            let (old, new) = (before.locate(&key), after.locate(&key));
+            if old != new {
+                assert_eq!(4, new);
+                moved += 1;
+            }
+        }
+        assert!(moved < 3_000, "too many keys moved: {}", moved);
+    }
+}
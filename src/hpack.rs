@@ -0,0 +1,472 @@
+// HPACK header compression for HTTP/2 (RFC 7541). The decoder keeps the dynamic
+// table the peer's encoder builds. The encoder never adds to a table, so what it
+// produces does not depend on what was sent before and needs no shared state.
+
+use std::collections::VecDeque;
+
+// a header field as sent, names and values are not necessarily UTF-8
+pub type Field = (Vec<u8>, Vec<u8>);
+
+// the table size both sides start with, and the most the decoder accepts
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+// what an entry costs in the table besides its bytes
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// code lengths of the canonical Huffman code, symbol 256 is end of string
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+const MAX_CODE_LENGTH: usize = 30;
+
+// how many codes each length has, and the symbols ordered by code
+const HUFFMAN_COUNTS: [u16; MAX_CODE_LENGTH + 1] = {
+    let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+    let mut symbol = 0;
+    while symbol < 257 {
+        counts[HUFFMAN_LENGTHS[symbol] as usize] += 1;
+        symbol += 1;
+    }
+    counts
+};
+
+const HUFFMAN_SYMBOLS: [u16; 257] = {
+    let mut symbols = [0u16; 257];
+    let mut next = 0;
+    let mut len = 1;
+    while len <= MAX_CODE_LENGTH {
+        let mut symbol = 0;
+        while symbol < 257 {
+            if HUFFMAN_LENGTHS[symbol] as usize == len {
+                symbols[next] = symbol as u16;
+                next += 1;
+            }
+            symbol += 1;
+        }
+        len += 1;
+    }
+    symbols
+};
+
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(input.len() * 8 / 5);
+    // the code read so far, the first code of its length and where that length's
+    // symbols start
+    let (mut code, mut first, mut index, mut len) = (0u32, 0u32, 0usize, 0usize);
+    for byte in input {
+        for shift in (0..8).rev() {
+            code = code << 1 | (byte >> shift & 1) as u32;
+            len += 1;
+            let count = HUFFMAN_COUNTS[len] as u32;
+            if code < first + count {
+                let symbol = HUFFMAN_SYMBOLS[index + (code - first) as usize];
+                if symbol == 256 {
+                    return Err("end of string symbol inside a string");
+                }
+                out.push(symbol as u8);
+                (code, first, index, len) = (0, 0, 0, 0);
+            } else {
+                index += count as usize;
+                first = (first + count) << 1;
+                if len == MAX_CODE_LENGTH {
+                    return Err("invalid Huffman code");
+                }
+            }
+        }
+    }
+    // the last byte is padded with the start of the end of string code, all ones
+    if len > 7 || code != (1 << len) - 1 {
+        return Err("invalid Huffman padding");
+    }
+    Ok(out)
+}
+
+// Reads an integer whose first byte keeps `prefix` bits for it.
+fn decode_integer(input: &mut &[u8], prefix: u32) -> Result<usize, &'static str> {
+    let (&first, rest) = input.split_first().ok_or("truncated integer")?;
+    *input = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first().ok_or("truncated integer")?;
+        *input = rest;
+        if shift > 28 {
+            return Err("integer too large");
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(input: &mut &[u8]) -> Result<Vec<u8>, &'static str> {
+    let huffman = input.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_integer(input, 7)?;
+    if len > input.len() {
+        return Err("truncated string");
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    if huffman { huffman_decode(bytes) } else { Ok(bytes.to_vec()) }
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u32, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn encode_string(out: &mut Vec<u8>, value: &[u8]) {
+    encode_integer(out, 0, 7, value.len());
+    out.extend_from_slice(value);
+}
+
+// What one header block may decode to. Without it a few bytes of references to a
+// large table entry expand to megabytes of fields.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // names and values together
+    pub size: usize,
+    pub fields: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Malformed(&'static str),
+    // over the Limits, the block itself may be valid
+    TooLarge(&'static str),
+}
+
+impl From<&'static str> for Error {
+    fn from(e: &'static str) -> Self {
+        Error::Malformed(e)
+    }
+}
+
+// Decodes the header blocks of one connection, in the order they were sent.
+pub struct Decoder {
+    table: VecDeque<Field>,
+    size: usize,
+    // set by the encoder with size updates, up to DEFAULT_TABLE_SIZE
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    fn entry(&self, index: usize) -> Result<Field, &'static str> {
+        match index {
+            0 => Err("index 0"),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index - 62).cloned().ok_or("index past the table"),
+        }
+    }
+
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let len = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(len);
+        // an entry larger than the table empties it and is not added
+        if len <= self.max_size {
+            self.size += len;
+            self.table.push_front(field);
+        }
+    }
+
+    // The fields of a complete header block. An error leaves the table unusable, it
+    // is a connection error.
+    pub fn decode(&mut self, mut input: &[u8], limits: Limits) -> Result<Vec<Field>, Error> {
+        let mut fields = Vec::new();
+        let mut decoded = 0;
+        while let Some(&first) = input.first() {
+            if first & 0x80 != 0 {
+                let index = decode_integer(&mut input, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0xe0 == 0x20 {
+                if !fields.is_empty() {
+                    return Err("table size update after a field".into());
+                }
+                let size = decode_integer(&mut input, 5)?;
+                if size > DEFAULT_TABLE_SIZE {
+                    return Err("table size over the limit".into());
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // with incremental indexing, without indexing, or never indexed
+                let (prefix, indexed) = if first & 0x40 != 0 { (6, true) } else { (4, false) };
+                let name = match decode_integer(&mut input, prefix)? {
+                    0 => decode_string(&mut input)?,
+                    index => self.entry(index)?.0,
+                };
+                let field = (name, decode_string(&mut input)?);
+                if indexed {
+                    self.insert(field.clone());
+                }
+                fields.push(field);
+            }
+            if let Some((name, value)) = fields.last() {
+                decoded += name.len() + value.len();
+                if decoded > limits.size {
+                    return Err(Error::TooLarge("header block decodes too large"));
+                }
+                if fields.len() > limits.fields {
+                    return Err(Error::TooLarge("too many fields in a header block"));
+                }
+            }
+        }
+        Ok(fields)
+    }
+}
+
+// Encodes a header block with static table references and literals, never indexed
+// into the decoder's table.
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in fields {
+        let found = STATIC_TABLE.iter().position(|entry| entry == &(*name, *value));
+        if let Some(index) = found {
+            encode_integer(&mut out, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(entry, _)| entry == name) {
+            Some(index) => encode_integer(&mut out, 0, 4, index + 1),
+            None => {
+                out.push(0);
+                encode_string(&mut out, name.as_bytes());
+            }
+        }
+        encode_string(&mut out, value.as_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNLIMITED: Limits = Limits {
+        size: usize::MAX,
+        fields: usize::MAX,
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<Field> {
+        pairs.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn integer_test() {
+        // RFC 7541 C.1
+        let mut out = Vec::new();
+        encode_integer(&mut out, 0, 5, 10);
+        encode_integer(&mut out, 0, 5, 1337);
+        encode_integer(&mut out, 0, 8, 42);
+        assert_eq!(vec![0x0a, 0x1f, 0x9a, 0x0a, 0x2a], out);
+        let mut input = &out[..];
+        assert_eq!(Ok(10), decode_integer(&mut input, 5));
+        assert_eq!(Ok(1337), decode_integer(&mut input, 5));
+        assert_eq!(Ok(42), decode_integer(&mut input, 8));
+        assert!(decode_integer(&mut &[0x1f, 0x9a][..], 5).is_err());
+        assert!(decode_integer(&mut &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f][..], 5).is_err());
+    }
+
+    #[test]
+    fn huffman_test() {
+        let all = concat!(
+            "ffc7fffd8fffffe2fffffe3fffffe4fffffe5fffffe6fffffe7fffffe8ffffeafffffff3fffffa7fffffabffffffdfff",
+            "ffebfffffecfffffedfffffeefffffefffffff0ffffff1ffffff2fffffffbfffffcffffffd3fffffd7fffffdbfffffdf",
+            "fffffe3fffffe7fffffebfffffed4fe3f9ffaffcabf1febfafefe7fdfd2cbb00089969b71d79fb9f7fff20ffbff3ff50",
+            "ddbd7f061c58f265cd9f469d5af66dddbf871e5f9cff7ff7fffc3ff9ffe45fff4719242cb34e6e9d68a6a3d7dac426de",
+            "fe3cfaf7fffbfe7ffbffdffffffcfffe6ffff4bfff9ffffa3fffd3ffff53fffd5ffffb3fffeb7fffdaffffb7ffff73ff",
+            "feeffffdeffffebffffbfffffd9ffffdbfffebffffe0ffffeeffffc3ffff8bffff1ffffe4fffee7fffb1ffff97fffd9f",
+            "fffcdffff9fffffbffffdafffeeffff4ffffb7fffee7fffe8ffffd3fffdeffffd5fffeeffffbdffffe1fffdfffff7fff",
+            "ff5ffffecffff07fff87fffe0ffff17fffedffff87ffff77fffeffffeaffff8bfffe3ffff93ffff87fffcbffff37ffff",
+            "1fffff83ffffe1fffebfffe3ffff3fffff2ffffa3ffffd9fffff17ffffc7fffff27ffffdefffffbffffff2fffff8ffff",
+            "fb7fff97fff8fffffe6fffffc1fffff87ffffe7fffffc5ffffe5fffe4ffff2fffffd1fffff4ffffffefffffe3fffffc9",
+            "fffff97fffb3ffffcffffb7fffcdffff4ffff9ffffd1ffffcffffeaffffafffffddffffeffffff4fffff5fffffabffff",
+            "a7ffffd7fffff9bffffecfffffb7fffff3fffffe8fffffd3fffffabfffff5fffffff7ffffecfffffdbfffffbbfffff7f",
+            "fffff0fffffbbf"
+        );
+        assert_eq!((0..=255).collect::<Vec<u8>>(), huffman_decode(&hex(all)).unwrap());
+        assert_eq!(b"www.example.com".to_vec(), huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f4ff")).unwrap());
+        // "a" is 00011, padded with zeros instead of ones, then with a whole byte
+        assert!(huffman_decode(&[0x18]).is_err());
+        assert!(huffman_decode(&[0x1f, 0xff]).is_err());
+        // the end of string code itself
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn decode_test() {
+        // RFC 7541 C.4, requests with Huffman coding sharing a dynamic table
+        let mut decoder = Decoder::new();
+        let block = hex("828684418cf1e3c2e5f23a6ba0ab90f4ff");
+        let expected = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        assert_eq!(fields(&expected), decoder.decode(&block, UNLIMITED).unwrap());
+        let block = hex("828684be5886a8eb10649cbf");
+        let expected = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ];
+        assert_eq!(fields(&expected), decoder.decode(&block, UNLIMITED).unwrap());
+        let block = hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf");
+        let expected = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        assert_eq!(fields(&expected), decoder.decode(&block, UNLIMITED).unwrap());
+        assert_eq!(164, decoder.size);
+        assert_eq!(fields(&[("custom-key", "custom-value")]), decoder.decode(&[0xbe], UNLIMITED).unwrap());
+
+        // shrinking the table evicts the oldest entries
+        assert_eq!(Vec::<Field>::new(), decoder.decode(&[0x3f, 0x21], UNLIMITED).unwrap());
+        assert_eq!(fields(&[("custom-key", "custom-value")]), decoder.decode(&[0xbe], UNLIMITED).unwrap());
+        assert!(decoder.decode(&[0xbf], UNLIMITED).is_err());
+        assert!(decoder.decode(&[0x82, 0x3f, 0x21], UNLIMITED).is_err());
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f], UNLIMITED).is_err());
+        assert!(decoder.decode(&[0x80], UNLIMITED).is_err());
+    }
+
+    #[test]
+    fn limits_test() {
+        let limits = Limits { size: 64, fields: 4 };
+        let mut decoder = Decoder::new();
+        // a 60 byte value added to the table, then referenced by one byte each
+        let mut block = vec![0x40, 0x01, b'x', 0x3c];
+        block.extend_from_slice(&[b'v'; 60]);
+        assert_eq!(1, decoder.decode(&block, limits).unwrap().len());
+        assert_eq!(Err(Error::TooLarge("header block decodes too large")), decoder.decode(&[0xbe, 0xbe], limits));
+
+        let mut decoder = Decoder::new();
+        assert_eq!(4, decoder.decode(&[0x82; 4], limits).unwrap().len());
+        assert_eq!(Err(Error::TooLarge("too many fields in a header block")), decoder.decode(&[0x82; 5], limits));
+        assert_eq!(Err(Error::Malformed("index 0")), decoder.decode(&[0x80], limits));
+    }
+
+    #[test]
+    fn encode_test() {
+        let expected = [
+            (":status", "200"),
+            (":status", "201"),
+            ("content-length", "5"),
+            ("x-request-id", "abc"),
+        ];
+        let block = encode(&expected);
+        assert_eq!(0x88, block[0]);
+        assert_eq!(fields(&expected), Decoder::new().decode(&block, UNLIMITED).unwrap());
+    }
+}
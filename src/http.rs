@@ -1,101 +1,844 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
-use std::net::TcpStream;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[allow(dead_code)]
-pub const TEMPLATE_CLIENT_ERROR: &str = "HTTP/1.1 {} BAD REQUEST\r\nContent-Length: 0\r\n\r\n";
-
-#[allow(dead_code)]
-pub const TEMPLATE_OK: &str = "HTTP/1.1 {} OK\r\nContent-Length: 0\r\n\r\n";
+pub const SERVER_NAME: &str = concat!("lightio/", env!("CARGO_PKG_VERSION"));
 
-#[allow(dead_code)]
-pub const TEMPLATE_SERVER_ERROR: &str = "HTTP/1.1 {} INTERNAL ERROR\r\nContent-Length: 0\r\n\r\n";
-pub const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Length: 0\r\n\r\n";
-pub const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n";
-pub const SERVER_ERROR: &str =
-    "HTTP/1.1 500 INTERNAL ERROR\r\nContent-Length: 0\r\n\r\n";
-pub const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
-
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
 pub enum HttpMethod {
     POST,
     GET,
     DELETE,
+    PUT,
+    HEAD,
+    OPTIONS,
+    PATCH,
 }
 
-impl HttpMethod {
-    pub fn from_str(method: &str) -> HttpMethod {
+impl FromStr for HttpMethod {
+    type Err = ParseError;
+
+    fn from_str(method: &str) -> Result<HttpMethod, ParseError> {
         match method {
-            "POST" => HttpMethod::POST,
-            "GET" => HttpMethod::GET,
-            "DELETE" => HttpMethod::DELETE,
-            unknown => panic!("Unknown HTTP method: {}", unknown),
+            "POST" => Ok(HttpMethod::POST),
+            "GET" => Ok(HttpMethod::GET),
+            "DELETE" => Ok(HttpMethod::DELETE),
+            "PUT" => Ok(HttpMethod::PUT),
+            "HEAD" => Ok(HttpMethod::HEAD),
+            "OPTIONS" => Ok(HttpMethod::OPTIONS),
+            "PATCH" => Ok(HttpMethod::PATCH),
+            unknown => Err(ParseError::UnknownMethod(unknown.to_string())),
         }
     }
-   
+}
+
+impl HttpMethod {
     #[allow(dead_code)]
     pub fn as_str(&self) -> &str {
-        match self { 
+        match self {
             HttpMethod::POST => "POST",
             HttpMethod::GET => "GET",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::PATCH => "PATCH",
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    MalformedStartLine,
+    UnknownMethod(String),
+    UnsupportedVersion(String),
+    RequestLineTooLong,
+    MalformedHeader,
+    HeadersTooLarge,
+    Io(io::ErrorKind),
+}
+
+impl ParseError {
+    // the response status for a request that failed to parse, None if the
+    // connection is unusable
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::MalformedStartLine | ParseError::MalformedHeader => Some(400),
+            ParseError::UnknownMethod(_) => Some(501),
+            ParseError::UnsupportedVersion(_) => Some(505),
+            ParseError::RequestLineTooLong => Some(414),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e.kind())
+    }
+}
+
+// Bounds on the request head, a request over them is answered 414 or 431.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    // request line, line ending included
+    pub max_request_line: usize,
+    // all header lines together, line endings included
+    pub max_header_size: usize,
+    pub max_headers: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_size: 64 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+// Header fields with lowercase names. A field sent several times reads as its
+// values joined by ", ", the way repeated fields are defined to combine.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Headers {
+    // field lines in the order received
+    fields: Vec<(String, String)>,
+    combined: HashMap<String, String>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.combined.get(name)
+    }
+
+    // each value of a repeated field
+    #[allow(dead_code)]
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.combined.contains_key(name)
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        let name = name.to_lowercase();
+        self.combined
+            .entry(name.clone())
+            .and_modify(|combined| {
+                combined.push_str(", ");
+                combined.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+        self.fields.push((name, value.to_string()));
+    }
+
+    // replaces every value of the field
+    pub fn insert(&mut self, name: &str, value: &str) {
+        let name = name.to_lowercase();
+        self.fields.retain(|(key, _)| *key != name);
+        self.combined.remove(&name);
+        self.append(&name, value);
+    }
+
+    // number of field lines
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+}
+
+// Decoded query string pairs in their order, a key may appear several times.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryParams {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    // the first value of the key
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.pairs
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+impl std::ops::Index<&str> for QueryParams {
+    type Output = String;
+
+    fn index(&self, key: &str) -> &String {
+        self.get(key).expect("query parameter is missing")
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: HttpMethod,
+    pub target: String,
+    pub version: HttpVersion,
+    pub headers: Headers,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+            HttpVersion::Http2 => "HTTP/2",
+        }
+    }
+}
+
+// longest chunk size line accepted, extensions included
+const MAX_CHUNK_LINE: u64 = 4096;
+
+enum Framing {
+    Length(u64),
+    Chunked {
+        // bytes left in the current chunk
+        remaining: u64,
+        started: bool,
+        done: bool,
+        // the trailers are bounded like the request head
+        trailer_limits: ParseLimits,
+    },
+    // whatever the reader has, HTTP/2 frames delimit the body instead
+    ToEnd {
+        done: bool,
+    },
+}
+
+// The request body as framed by Content-Length or chunked Transfer-Encoding, reads
+// stop at its end so the next request on the connection is left untouched.
+pub struct RequestBody<'a> {
+    reader: &'a mut dyn BufRead,
+    framing: Framing,
+    trailers: Headers,
+    // where to send 100 Continue once the body is first read
+    expect_continue: Option<&'a mut dyn Write>,
+}
+
+impl<'a> RequestBody<'a> {
+    pub fn new(reader: &'a mut dyn BufRead, len: u64) -> Self {
+        Self {
+            reader,
+            framing: Framing::Length(len),
+            trailers: Headers::new(),
+            expect_continue: None,
+        }
+    }
+
+    pub fn chunked(reader: &'a mut dyn BufRead, limits: &ParseLimits) -> Self {
+        Self {
+            reader,
+            framing: Framing::Chunked {
+                remaining: 0,
+                started: false,
+                done: false,
+                trailer_limits: *limits,
+            },
+            trailers: Headers::new(),
+            expect_continue: None,
+        }
+    }
+
+    // a body of unknown length that ends where the reader does
+    pub fn to_end(reader: &'a mut dyn BufRead) -> Self {
+        Self {
+            reader,
+            framing: Framing::ToEnd { done: false },
+            trailers: Headers::new(),
+            expect_continue: None,
+        }
+    }
+
+    // The client waits for 100 Continue before sending the body. It is sent when the
+    // body is first read, so a handler returning without reading rejects the upload.
+    pub fn expect_continue(mut self, output: &'a mut dyn Write) -> Self {
+        if self.remaining() != Some(0) {
+            self.expect_continue = Some(output);
+        }
+        self
+    }
+
+    // the client was told neither to send the body nor that it is rejected
+    pub fn awaiting_continue(&self) -> bool {
+        self.expect_continue.is_some()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.framing, Framing::Chunked { .. })
+    }
+
+    // bytes of the body not read yet, unknown for chunked bodies until the last chunk
+    pub fn remaining(&self) -> Option<u64> {
+        match self.framing {
+            Framing::Length(remaining) => Some(remaining),
+            Framing::Chunked { done: true, .. } | Framing::ToEnd { done: true } => Some(0),
+            Framing::Chunked { .. } | Framing::ToEnd { .. } => None,
+        }
+    }
+
+    // trailer fields of a chunked body, available once it has been read to the end
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    // discards what the handler did not read
+    pub fn drain(&mut self) -> io::Result<u64> {
+        let drained = io::copy(self, &mut io::sink())?;
+        if self.remaining() != Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("body ended after {} bytes", drained),
+            ));
+        }
+        Ok(drained)
+    }
+
+    // reads the next chunk size line, and the trailers after the last chunk
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let Framing::Chunked { remaining, started, done, trailer_limits } = &mut self.framing else {
+            return Ok(());
+        };
+        let mut line = String::new();
+        if *started {
+            (&mut self.reader).take(2).read_line(&mut line)?;
+            if line != "\r\n" && line != "\n" {
+                return Err(invalid("chunk data not followed by CRLF"));
+            }
+            line.clear();
+        }
+        *started = true;
+        (&mut self.reader).take(MAX_CHUNK_LINE).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(invalid("chunk size line truncated or too long"));
+        }
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            *done = true;
+            self.trailers = parse_headers(&mut self.reader, trailer_limits)
+                .map_err(|e| invalid(&format!("invalid trailers: {:?}", e)))?;
+        } else {
+            *remaining = size;
+        }
+        Ok(())
+    }
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for RequestBody<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if let Some(output) = self.expect_continue.take() {
+            write!(output, "HTTP/1.1 100 {}\r\n\r\n", reason_phrase(100))?;
+            output.flush()?;
+        }
+        if let Framing::Chunked { remaining: 0, done: false, .. } = self.framing {
+            self.next_chunk()?;
+        }
+        let remaining = match self.framing {
+            Framing::Length(remaining) | Framing::Chunked { remaining, .. } => remaining,
+            Framing::ToEnd { done } => if done { 0 } else { u64::MAX },
+        };
+        if remaining == 0 {
+            return Ok(&[]);
+        }
+        let available = self.reader.fill_buf()?;
+        if let (Framing::ToEnd { done }, true) = (&mut self.framing, available.is_empty()) {
+            *done = true;
+        }
+        let n = (available.len() as u64).min(remaining) as usize;
+        Ok(&available[..n])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        match &mut self.framing {
+            Framing::Length(remaining) | Framing::Chunked { remaining, .. } => {
+                *remaining -= amt as u64
+            }
+            Framing::ToEnd { .. } => {}
+        }
+    }
+}
+
+// Frames a response body of unknown length as chunks, `finish` writes the last chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl fmt::Debug for RequestBody<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("remaining", &self.remaining())
+            .field("chunked", &self.is_chunked())
+            .field("awaiting_continue", &self.awaiting_continue())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpReq<'a> {
     pub method: HttpMethod,
     pub path: String,
-    pub headers: HashMap<String, String>,
-    pub body: BufReader<&'a TcpStream>,
-    pub query_params: HashMap<String, String>,
+    pub version: HttpVersion,
+    pub headers: Headers,
+    pub body: RequestBody<'a>,
+    pub query_params: QueryParams,
+    pub path_params: HashMap<String, String>,
+}
+
+impl HttpReq<'_> {
+    // HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only on request
+    pub fn keep_alive(&self) -> bool {
+        let has = |token: &str| {
+            self.headers.get("connection").is_some_and(|value| {
+                value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        match self.version {
+            HttpVersion::Http11 => !has("close"),
+            HttpVersion::Http10 => has("keep-alive"),
+            // streams end, the connection stays
+            HttpVersion::Http2 => true,
+        }
+    }
+
+    // path parameter extracted by the router, or query parameter of the same name
+    pub fn param(&self, name: &str) -> Option<&String> {
+        self.path_params
+            .get(name)
+            .or_else(|| self.query_params.get(name))
+    }
+}
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    File(File, u64),
+    Reader(Box<dyn Read>, u64),
+    // length unknown upfront, sent chunked
+    Stream(Box<dyn Read>),
+}
+
+impl Body {
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, len) | Body::Reader(_, len) => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+}
+
+// Returned by handlers, the server adds Content-Length, Date, Server and Connection.
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn file(mut self, file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        self.body = Body::File(file, len);
+        Ok(self)
+    }
+
+    pub fn reader(mut self, reader: impl Read + 'static, len: u64) -> Self {
+        self.body = Body::Reader(Box::new(reader), len);
+        self
+    }
+
+    // `len` bytes of the file from `start` on
+    pub fn file_range(mut self, mut file: File, start: u64, len: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        self.body = Body::File(file, len);
+        Ok(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn stream(mut self, reader: impl Read + 'static) -> Self {
+        self.body = Body::Stream(Box::new(reader));
+        self
+    }
 }
 
-pub fn parse_start_line(line: &str) -> Option<(HttpMethod, String)> {
-    let parts = line.split_whitespace().collect::<Vec<&str>>();
-    match parts[..] {
-        [method, path, ..] => Some((HttpMethod::from_str(method), path.to_string())),
-        _ => None,
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "CONTINUE",
+        200 => "OK",
+        201 => "CREATED",
+        204 => "NO CONTENT",
+        206 => "PARTIAL CONTENT",
+        304 => "NOT MODIFIED",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        403 => "FORBIDDEN",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        408 => "REQUEST TIMEOUT",
+        411 => "LENGTH REQUIRED",
+        413 => "PAYLOAD TOO LARGE",
+        414 => "URI TOO LONG",
+        416 => "RANGE NOT SATISFIABLE",
+        417 => "EXPECTATION FAILED",
+        431 => "REQUEST HEADER FIELDS TOO LARGE",
+        500 => "INTERNAL ERROR",
+        501 => "NOT IMPLEMENTED",
+        503 => "SERVICE UNAVAILABLE",
+        505 => "HTTP VERSION NOT SUPPORTED",
+        _ => "UNKNOWN",
     }
 }
-pub fn parse_headers(reader: &mut BufReader<&TcpStream>) -> Option<HashMap<String, String>> {
-    let mut headers = HashMap::<String, String>::new();
+
+// IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+// Reads the request line and the header fields. Empty lines before the request line
+// are skipped, None means the connection was closed before a request started.
+pub fn parse_request_head<R: BufRead + ?Sized>(
+    reader: &mut R,
+    limits: &ParseLimits,
+) -> Result<Option<RequestHead>, ParseError> {
+    let line = loop {
+        let line = read_line(reader, limits.max_request_line)?;
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if !line.ends_with(b"\n") {
+            return Err(if line.len() >= limits.max_request_line {
+                ParseError::RequestLineTooLong
+            } else {
+                ParseError::MalformedStartLine
+            });
+        }
+        if line != b"\r\n" && line != b"\n" {
+            break line;
+        }
+    };
+    let line = std::str::from_utf8(&line).map_err(|_| ParseError::MalformedStartLine)?;
+    let (method, target, version) = parse_start_line(line)?;
+    let headers = parse_headers(reader, limits)?;
+    Ok(Some(RequestHead {
+        method,
+        target,
+        version,
+        headers,
+    }))
+}
+
+// "METHOD target HTTP/x.y", single spaces, line ending optional
+pub fn parse_start_line(line: &str) -> Result<(HttpMethod, String, HttpVersion), ParseError> {
+    let line = strip_line_ending(line.as_bytes());
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::MalformedStartLine)?;
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    let [method, target, version] = parts[..] else {
+        return Err(ParseError::MalformedStartLine);
+    };
+    if method.is_empty() || !method.bytes().all(is_token) {
+        return Err(ParseError::MalformedStartLine);
+    }
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::MalformedStartLine);
+    }
+    let version = match version.as_bytes() {
+        b"HTTP/1.1" => HttpVersion::Http11,
+        b"HTTP/1.0" => HttpVersion::Http10,
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+            if major.is_ascii_digit() && minor.is_ascii_digit() =>
+        {
+            return Err(ParseError::UnsupportedVersion(version.to_string()));
+        }
+        _ => return Err(ParseError::MalformedStartLine),
+    };
+    Ok((method.parse()?, target.to_string(), version))
+}
+
+// Header lines up to the empty line ending the head. Values are trimmed, repeated
+// fields kept, and a Content-Length must be a number repeated with the same value only.
+pub fn parse_headers<R: BufRead + ?Sized>(
+    reader: &mut R,
+    limits: &ParseLimits,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    let mut budget = limits.max_header_size;
     loop {
-        let mut line = String::new();
-        if let Err(e) = reader.read_line(&mut line) {
-            eprintln!("http request read header error: {}", e);
-            return None;
+        let line = read_line(reader, budget)?;
+        if !line.ends_with(b"\n") {
+            return Err(if line.len() >= budget {
+                ParseError::HeadersTooLarge
+            } else {
+                ParseError::MalformedHeader
+            });
         }
-        if line == "\r\n" || line.trim().is_empty() {
-            println!("consumed request");
-            return Some(headers);
+        budget -= line.len();
+        let line = strip_line_ending(&line);
+        if line.is_empty() {
+            return Ok(headers);
         }
-        match line.split_once(":") {
-            Some((header, value)) => {
-                headers.insert(header.to_string().to_lowercase(), value.to_string());
-            }
-            None if line.trim().is_empty() => {
-                //do nothing
+        if headers.len() >= limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        // obsolete line folding is rejected, as is whitespace before the colon
+        let Some(colon) = line.iter().position(|b| *b == b':') else {
+            return Err(ParseError::MalformedHeader);
+        };
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if name.is_empty() || !name.iter().copied().all(is_token) {
+            return Err(ParseError::MalformedHeader);
+        }
+        if value.iter().any(|b| b.is_ascii_control() && *b != b'\t') {
+            return Err(ParseError::MalformedHeader);
+        }
+        let name = String::from_utf8_lossy(name).to_lowercase();
+        let value = String::from_utf8_lossy(value);
+        let value = value.trim_matches([' ', '\t']);
+        if name == "content-length" {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::MalformedHeader);
             }
-            None => {
-                eprintln!("not valid header: {}", line);
+            match headers.get(&name) {
+                Some(previous) if previous == value => continue,
+                Some(_) => return Err(ParseError::MalformedHeader),
+                None => {}
             }
-        };
+        }
+        headers.append(&name, value);
     }
 }
 
-pub fn parse_query_params(path: String) -> (String, HashMap<String, String>) {
-    if let Some((path, params)) = path.split_once("?") {
-        let mut params_map = HashMap::<String, String>::new();
-        for key_val in params.split("&") {
-            if let Some((key, val)) = key_val.split_once("=") {
-                params_map.insert(key.to_string(), val.to_string());
-            }
+// one line including its ending, at most max bytes
+fn read_line<R: BufRead + ?Sized>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader.take(max as u64).read_until(b'\n', &mut line)?;
+    Ok(line)
+}
+
+fn strip_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// characters allowed in methods and header names
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Splits the target into its path and decoded query pairs. Keys without "=" get an
+// empty value.
+pub fn parse_query_params(path: String) -> (String, QueryParams) {
+    let Some((path, query)) = path.split_once("?") else {
+        return (path, QueryParams::default());
+    };
+    let pairs = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect();
+    (path.to_string(), QueryParams { pairs })
+}
+
+// RFC 3986 percent-decoding, with "+" as a space for form-encoded query strings.
+// Malformed escapes are kept as they are and invalid UTF-8 is replaced.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    out.push((high * 16 + low) as u8);
+                    i += 3;
+                    continue;
+                }
+                _ => out.push(b'%'),
+            },
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// percent-encodes every byte but unreserved characters and those in `keep`
+#[allow(dead_code)]
+pub fn percent_encode(input: &str, keep: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || keep.contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
         }
-        (path.to_string(), params_map)
-    } else {
-        (path.to_string(), HashMap::new())
+    }
+    out
+}
+
+// What a Range header asks of a representation `len` bytes long. Only a single
+// "bytes=" range is served, anything else is ignored and gets the full body.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    Full,
+    // start and end, the end exclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+pub fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some((unit, spec)) = header.trim().split_once('=') else {
+        return ByteRange::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    // digits only, a sign is not allowed
+    let number = |s: &str| {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse::<u64>().ok()
+    };
+    match (first.is_empty(), number(first), number(last)) {
+        // the last n bytes
+        (true, _, Some(suffix)) if suffix > 0 && len > 0 => ByteRange::Partial(len - suffix.min(len), len),
+        (true, _, Some(_)) => ByteRange::Unsatisfiable,
+        (false, Some(start), _) if start >= len => ByteRange::Unsatisfiable,
+        (false, Some(start), None) if last.is_empty() => ByteRange::Partial(start, len),
+        (false, Some(start), Some(end)) if start <= end => ByteRange::Partial(start, end.saturating_add(1).min(len)),
+        _ => ByteRange::Full,
     }
 }
 
@@ -103,6 +846,230 @@ pub fn parse_query_params(path: String) -> (String, HashMap<String, String>) {
 mod test {
     use super::*;
 
+    #[test]
+    fn parse_start_line_test() {
+        assert_eq!(
+            Ok((HttpMethod::PUT, "/object".to_string(), HttpVersion::Http11)),
+            parse_start_line("PUT /object HTTP/1.1\r\n")
+        );
+        assert_eq!(
+            Ok((HttpMethod::GET, "/".to_string(), HttpVersion::Http10)),
+            parse_start_line("GET / HTTP/1.0\r\n")
+        );
+        assert_eq!(
+            Err(ParseError::UnknownMethod("BREW".to_string())),
+            parse_start_line("BREW /pot HTTP/1.1\r\n")
+        );
+        assert_eq!(Err(ParseError::MalformedStartLine), parse_start_line("GET\r\n"));
+    }
+
+    fn parse_head(input: &str, limits: &ParseLimits) -> Result<Option<RequestHead>, ParseError> {
+        parse_request_head(&mut input.as_bytes(), limits)
+    }
+
+    #[test]
+    fn parse_request_head_test() {
+        let limits = ParseLimits::default();
+        let mut input = "\r\nGET /a?b=c HTTP/1.1\r\nHost:  x \r\nAccept: a\r\naccept:\tb\r\n\r\nbody".as_bytes();
+        let head = parse_request_head(&mut input, &limits).unwrap().unwrap();
+        assert_eq!((HttpMethod::GET, "/a?b=c", HttpVersion::Http11), (head.method, head.target.as_str(), head.version));
+        assert_eq!("x", head.headers.get("host").unwrap());
+        assert_eq!("a, b", head.headers.get("accept").unwrap());
+        assert_eq!(vec!["a", "b"], head.headers.get_all("accept").collect::<Vec<_>>());
+        assert_eq!(b"body", input);
+
+        assert_eq!(None, parse_head("", &limits).unwrap().map(|h| h.target));
+        let bad_header = |header: &str| parse_head(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header), &limits).err();
+        assert_eq!(Some(ParseError::MalformedHeader), bad_header("Host x"));
+        assert_eq!(Some(ParseError::MalformedHeader), bad_header("Host : x"));
+        assert_eq!(Some(ParseError::MalformedHeader), bad_header("Host: x\r\n folded"));
+        assert_eq!(Some(ParseError::MalformedHeader), bad_header("Content-Length: 1\r\nContent-Length: 2"));
+        assert_eq!(Some(ParseError::MalformedHeader), bad_header("Content-Length: -1"));
+        assert_eq!(None, bad_header("Content-Length: 1\r\nContent-Length: 1"));
+        assert_eq!(Some(ParseError::MalformedHeader), parse_head("GET / HTTP/1.1\r\nHost: x", &limits).err());
+    }
+
+    #[test]
+    fn parse_limits_test() {
+        let limits = ParseLimits {
+            max_request_line: 20,
+            max_header_size: 30,
+            max_headers: 2,
+        };
+        assert_eq!(Some(ParseError::RequestLineTooLong), parse_head("GET /aaaaaaaaaaaa HTTP/1.1\r\n\r\n", &limits).err());
+        assert!(parse_head("GET /aaaa HTTP/1.1\r\n\r\n", &limits).is_ok());
+        let headers = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(Some(ParseError::HeadersTooLarge), parse_head(headers, &limits).err());
+        let headers = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(30));
+        assert_eq!(Some(ParseError::HeadersTooLarge), parse_head(&headers, &limits).err());
+    }
+
+    #[test]
+    fn parse_version_test() {
+        let limits = ParseLimits::default();
+        let error = |line: &str| parse_head(line, &limits).err();
+        assert_eq!(Some(ParseError::UnsupportedVersion("HTTP/2.0".to_string())), error("GET / HTTP/2.0\r\n\r\n"));
+        assert_eq!(Some(ParseError::UnsupportedVersion("HTTP/0.9".to_string())), error("GET / HTTP/0.9\r\n\r\n"));
+        assert_eq!(Some(ParseError::MalformedStartLine), error("GET / HTTP/1.1x\r\n\r\n"));
+        assert_eq!(Some(ParseError::MalformedStartLine), error("GET /\r\n\r\n"));
+        assert_eq!(Some(ParseError::MalformedStartLine), error("GET  / HTTP/1.1\r\n\r\n"));
+        assert_eq!(Some(505), ParseError::UnsupportedVersion(String::new()).status());
+    }
+
+    // Feeds mutated requests to the parser and checks it neither panics nor goes past
+    // its limits. LIGHTIO_FUZZ_ITERATIONS runs it longer, LIGHTIO_FUZZ_SEED varies inputs.
+    #[test]
+    fn fuzz_parse_request_head() {
+        let env = |name: &str, default: u64| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let iterations = env("LIGHTIO_FUZZ_ITERATIONS", 20_000);
+        let mut state = env("LIGHTIO_FUZZ_SEED", 0x9e3779b97f4a7c15) | 1;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let seeds = [
+            "GET /buckets/b/objects/x?y=1 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n",
+            "PUT /o HTTP/1.0\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\nhello",
+            "POST /e HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTrailer: x\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            "\r\nOPTIONS * HTTP/1.1\nA:b\n\n",
+        ];
+        let interesting = b"\r\n: \t\0\x7f\xffHTTP/1.";
+        let limits = ParseLimits {
+            max_request_line: 64,
+            max_header_size: 128,
+            max_headers: 4,
+        };
+        for _ in 0..iterations {
+            let mut input = seeds[next() as usize % seeds.len()].as_bytes().to_vec();
+            for _ in 0..1 + next() % 4 {
+                let at = next() as usize % (input.len() + 1);
+                match next() % 5 {
+                    0 if at < input.len() => input[at] = next() as u8,
+                    1 => input.insert(at, interesting[next() as usize % interesting.len()]),
+                    2 if at < input.len() => drop(input.remove(at)),
+                    3 => input.truncate(at),
+                    _ => {
+                        let end = (at + next() as usize % 16).min(input.len());
+                        let slice = input[at..end].to_vec();
+                        input.splice(at..at, slice);
+                    }
+                }
+            }
+            let mut reader = input.as_slice();
+            let result = parse_request_head(&mut reader, &limits);
+            let consumed = input.len() - reader.len();
+            // blank lines before the request line are not counted in the limits
+            let blank = input.iter().take_while(|b| **b == b'\r' || **b == b'\n').count();
+            assert!(
+                consumed <= blank + limits.max_request_line + limits.max_header_size,
+                "consumed {} bytes of {:?}",
+                consumed,
+                String::from_utf8_lossy(&input)
+            );
+            if let Ok(Some(head)) = result {
+                assert!(head.headers.len() <= limits.max_headers);
+                assert!(!head.target.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn request_body_test() {
+        let mut input = "hello\r\nworld GET / HTTP/1.1\r\n".as_bytes();
+        let mut body = RequestBody::new(&mut input, 12);
+        let mut line = String::new();
+        body.read_line(&mut line).unwrap();
+        assert_eq!("hello\r\n", line);
+        assert_eq!(Some(5), body.remaining());
+        assert_eq!(5, body.drain().unwrap());
+        assert_eq!(0, body.read(&mut [0; 8]).unwrap());
+        assert_eq!(" GET / HTTP/1.1\r\n", String::from_utf8_lossy(input));
+
+        let mut input = "short".as_bytes();
+        assert!(RequestBody::new(&mut input, 10).drain().is_err());
+    }
+
+    #[test]
+    fn chunked_body_test() {
+        let mut input = "5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Sum: abc\r\n\r\nGET".as_bytes();
+        let mut body = RequestBody::chunked(&mut input, &ParseLimits::default());
+        assert_eq!(None, body.remaining());
+        let mut data = String::new();
+        body.read_to_string(&mut data).unwrap();
+        assert_eq!("hello, world", data);
+        assert_eq!(Some(0), body.remaining());
+        assert_eq!("abc", body.trailers().get("x-sum").unwrap());
+        assert_eq!("GET", String::from_utf8_lossy(input));
+
+        for malformed in ["5\r\nhello0\r\n\r\n", "zz\r\n", "5\r\nhel"] {
+            let mut input = malformed.as_bytes();
+            let mut body = RequestBody::chunked(&mut input, &ParseLimits::default());
+            assert!(body.drain().is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn expect_continue_test() {
+        let mut input = "hello".as_bytes();
+        let mut output = Vec::new();
+        let mut body = RequestBody::new(&mut input, 5).expect_continue(&mut output);
+        assert!(body.awaiting_continue());
+        let mut data = String::new();
+        body.read_to_string(&mut data).unwrap();
+        assert!(!body.awaiting_continue());
+        assert_eq!("hello", data);
+        assert_eq!("HTTP/1.1 100 CONTINUE\r\n\r\n", String::from_utf8(output).unwrap());
+
+        let mut input = "".as_bytes();
+        let mut output = Vec::new();
+        let body = RequestBody::new(&mut input, 0).expect_continue(&mut output);
+        assert!(!body.awaiting_continue());
+    }
+
+    #[test]
+    fn chunked_writer_test() {
+        let mut chunked = ChunkedWriter::new(Vec::new());
+        chunked.write_all(b"hello").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(b", world").unwrap();
+        let out = chunked.finish().unwrap();
+
+        let mut input = out.as_slice();
+        let mut data = String::new();
+        RequestBody::chunked(&mut input, &ParseLimits::default()).read_to_string(&mut data).unwrap();
+        assert_eq!("hello, world", data);
+        assert_eq!("5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn http_date_test() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(time));
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(951782400);
+        assert_eq!("Tue, 29 Feb 2000 00:00:00 GMT", http_date(time));
+    }
+
+    #[test]
+    fn parse_range_test() {
+        assert_eq!(ByteRange::Partial(0, 10), parse_range("bytes=0-9", 100));
+        assert_eq!(ByteRange::Partial(90, 100), parse_range("bytes=90-200", 100));
+        assert_eq!(ByteRange::Partial(50, 100), parse_range("Bytes = 50-", 100));
+        assert_eq!(ByteRange::Partial(80, 100), parse_range("bytes=-20", 100));
+        assert_eq!(ByteRange::Partial(0, 100), parse_range("bytes=-200", 100));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=100-", 100));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=-0", 100));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=-5", 0));
+        assert_eq!(ByteRange::Full, parse_range("bytes=9-0", 100));
+        assert_eq!(ByteRange::Full, parse_range("bytes=0-1,5-6", 100));
+        assert_eq!(ByteRange::Full, parse_range("items=0-9", 100));
+        assert_eq!(ByteRange::Full, parse_range("bytes=+1-9", 100));
+        assert_eq!(ByteRange::Full, parse_range("bytes=-", 100));
+    }
+
     #[test]
     fn parse_query_params_test() {
         let (path, params) = parse_query_params("/hello?hello=world&test=1".to_string());
@@ -113,6 +1080,28 @@ mod test {
         assert_eq!("1", params["test"]);
     }
 
+    #[test]
+    fn parse_query_params_decode_test() {
+        let (path, params) =
+            parse_query_params("/o?name=a%20b%26c.txt&tag=x&tag=y+z&flag&bad=%zz%4".to_string());
+
+        assert_eq!("/o", path);
+        assert_eq!("a b&c.txt", params["name"]);
+        assert_eq!(vec!["x", "y z"], params.get_all("tag").collect::<Vec<_>>());
+        assert_eq!("", params["flag"]);
+        assert_eq!("%zz%4", params["bad"]);
+        assert_eq!(5, params.len());
+    }
+
+    #[test]
+    fn percent_encode_test() {
+        let name = "dir/a b&c=+%é.txt";
+        assert_eq!("dir%2Fa%20b%26c%3D%2B%25%C3%A9.txt", percent_encode(name, b""));
+        assert_eq!("dir/a%20b%26c%3D%2B%25%C3%A9.txt", percent_encode(name, b"/"));
+        assert_eq!(name, percent_decode(&percent_encode(name, b""), true));
+        assert_eq!("a+b", percent_decode("a+b", false));
+    }
+
     #[test]
     fn parse_query_params_test_4() {
         let (path, _params) = parse_query_params("/hello?hello=world?&test=1".to_string());
@@ -1,4 +1,4 @@
-use crate::http::HttpMethod;
+use crate::http::{percent_encode, HttpMethod};
 use std::collections::HashMap;
 use std::io::ErrorKind::InvalidInput;
 use std::io::{Error, Read, Write};
@@ -74,6 +74,20 @@ impl RequestBuilder {
         self
     }
 
+    // appends a query parameter, percent-encoded
+    #[allow(dead_code)]
+    pub fn query(mut self, key: &str, value: &str) -> Self {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        self.url = format!(
+            "{}{}{}={}",
+            self.url,
+            separator,
+            percent_encode(key, b""),
+            percent_encode(value, b"")
+        );
+        self
+    }
+
     #[allow(dead_code)]
     pub fn body(mut self, data: &str) -> Self {
         self.body = data.as_bytes().to_vec();
@@ -101,6 +115,8 @@ impl RequestBuilder {
             conn.write_all(format!("{}: {}\r\n", header_key, header_val).as_bytes())?;
         }
         conn.write_all(format!("Host: {}\r\n", &host).as_bytes())?;
+        // the response is read until the server closes the connection
+        conn.write_all("Connection: close\r\n".as_bytes())?;
         if !&self.body.is_empty() {
             conn.write_all(format!("Content-Length: {}\r\n\r\n", &self.body.len()).as_bytes())?;
             conn.write_all(&self.body)?;
@@ -126,16 +142,19 @@ impl RequestBuilder {
         let terms = response.trim().split(" ");
         for (i, term) in terms.enumerate() {
             if i == 1 {
-                return term.parse::<u16>().map_or(None, |r| Some(r));
+                return term.parse::<u16>().ok();
             }
         }
         None
     }
 
+    // characters not allowed in a request target are percent-encoded, so the
+    // url may name an object with spaces as is
     fn parse_url(uri: &str) -> Option<(String, String)> {
         let (protocol, url) = uri.trim().split_once("//")?;
         if protocol == "http:" {
             let (host, path) = url.trim().split_once("/")?;
+            let path = percent_encode(path, b":/?#[]@!$&'()*+,;=%");
             Some((host.to_owned(), format!("/{}", path)))
         } else {
             None
@@ -153,4 +172,14 @@ mod tests {
         assert_eq!(host, "localhost:8084");
         assert_eq!(path, "/hello?hello=world&test=1");
     }
+
+    #[test]
+    fn query_test() {
+        let request = HttpClient::new()
+            .get("http://localhost:8084/buckets/b/objects/my file.txt")
+            .query("tag", "a&b c")
+            .query("tag", "2");
+        let (_, path) = RequestBuilder::parse_url(&request.url).unwrap();
+        assert_eq!(path, "/buckets/b/objects/my%20file.txt?tag=a%26b%20c&tag=2");
+    }
 }
@@ -1,22 +1,72 @@
-use crate::file_storage::FileStorage;
-use crate::http;
-use crate::http::{HttpMethod, HttpReq};
-use std::cell::RefCell;
-use std::fs::File;
+use crate::checksum::{base64_decode, base64_encode, ChecksumAlgorithm};
+use crate::file_storage::{FileStorage, ObjectMeta, ObjectReader, SYSTEM_DIR, TEMP_EXTENSION};
+use crate::http::{self, ByteRange, HttpMethod, HttpReq, HttpResponse};
+use std::io;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::ffi::OsStr;
 use std::ops::{Deref};
-use std::path::{Path};
-use std::rc::Rc;
+use std::path::{Component, Path, PathBuf};
 
 pub trait HttpHandler {
-    fn handle_request(&self, req: &mut HttpReq, tcp_stream: Rc<RefCell<&TcpStream>>);
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse;
     fn path(&self) -> &str;
     fn method(&self) -> HttpMethod;
 }
 
 const BUCKET_PATH: &str = "/bucket";
 const OBJECT_PATH: &str = "/object";
+const STATS_PATH: &str = "/stats";
+pub const REST_BUCKET_PATH: &str = "/buckets/{bucket_name}";
+pub const REST_OBJECT_PATH: &str = "/buckets/{bucket_name}/objects/{object_name...}";
+
+// Bucket and object names come percent-decoded from the request, so they can hold
+// "..", "/" or an absolute path. Only plain names may become storage paths.
+fn bucket_path(bucket_name: &str) -> Option<&Path> {
+    let path = Path::new(bucket_name);
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name != SYSTEM_DIR && !temporary(name) => Some(path),
+        _ => None,
+    }
+}
+
+fn object_path(bucket_name: &str, object_name: &str) -> Option<PathBuf> {
+    let object = Path::new(object_name);
+    let mut components = object.components().peekable();
+    components.peek()?;
+    if !components.all(|component| matches!(component, Component::Normal(name) if !temporary(name))) {
+        return None;
+    }
+    bucket_path(bucket_name).map(|bucket| bucket.join(object))
+}
+
+// names the storage uses for uploads in progress, which it skips when listing objects
+fn temporary(name: &OsStr) -> bool {
+    Path::new(name).extension() == Some(TEMP_EXTENSION.as_ref())
+}
+
+// serves a handler on another path pattern and method, e.g. a RESTful alias
+pub struct Routed<H> {
+    path: &'static str,
+    method: HttpMethod,
+    handler: H,
+}
+impl<H: HttpHandler> Routed<H> {
+    pub fn new(path: &'static str, method: HttpMethod, handler: H) -> Self {
+        Routed { path, method, handler }
+    }
+}
+impl<H: HttpHandler> HttpHandler for Routed<H> {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        self.handler.handle_request(req)
+    }
+    fn path(&self) -> &str {
+        self.path
+    }
+    fn method(&self) -> HttpMethod {
+        self.method.clone()
+    }
+}
 
 // create bucket
 pub struct BucketCreateHandler {
@@ -28,27 +78,21 @@ impl BucketCreateHandler {
     }
 }
 impl HttpHandler for BucketCreateHandler {
-    fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>)  {
-        let query_params = &req.query_params;
-        match query_params.get("bucket_name") {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        match req.param("bucket_name") {
             Some(bucket_name) => {
-                if let Err(e) = self.file_storage.create_bucket(Path::new(bucket_name)) {
+                let Some(bucket) = bucket_path(bucket_name) else {
+                    println!("invalid bucket name: {}", bucket_name);
+                    return HttpResponse::new(400);
+                };
+                if let Err(e) = self.file_storage.create_bucket(bucket) {
                     eprintln!("Failed to create bucket {}: {:?}", bucket_name, e);
-                    output
-                        .borrow_mut()
-                        .write_all(http::SERVER_ERROR.as_bytes())
-                        .unwrap()
+                    HttpResponse::new(500)
                 } else {
-                    output
-                        .borrow_mut()
-                        .write_all(http::OK_RESPONSE.as_bytes())
-                        .unwrap()
+                    HttpResponse::new(200)
                 }
             }
-            None => output
-                .borrow_mut()
-                .write_all(http::BAD_REQUEST.as_bytes())
-                .unwrap(),
+            None => HttpResponse::new(400),
         }
     }
     fn path(&self) -> &str {
@@ -69,29 +113,21 @@ impl BucketDeleteHandler {
     }
 }
 impl HttpHandler for BucketDeleteHandler {
-    fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>) {
-        let query_params = &req.query_params;
-        match query_params.get("bucket_name") {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        match req.param("bucket_name") {
             Some(bucket_name) => {
-                if let Err(e) = self.file_storage.delete_bucket(Path::new(bucket_name)) {
+                let Some(bucket) = bucket_path(bucket_name) else {
+                    println!("invalid bucket name: {}", bucket_name);
+                    return HttpResponse::new(400);
+                };
+                if let Err(e) = self.file_storage.delete_bucket(bucket) {
                     eprintln!("Failed to delete bucket {}: {:?}", bucket_name, e);
-                    output
-                        .borrow_mut()
-                        .write_all(http::SERVER_ERROR.as_bytes())
-                        .unwrap();
+                    HttpResponse::new(500)
                 } else {
-                    output
-                        .borrow_mut()
-                        .write_all(http::OK_RESPONSE.as_bytes())
-                        .unwrap();
+                    HttpResponse::new(200)
                 }
             }
-            None => {
-                output
-                    .borrow_mut()
-                    .write_all(http::OK_RESPONSE.as_bytes())
-                    .unwrap();
-            }
+            None => HttpResponse::new(200),
         }
     }
     fn path(&self) -> &str {
@@ -112,28 +148,20 @@ impl BucketExistsHandler {
     }
 }
 impl HttpHandler for BucketExistsHandler {
-    fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>) {
-        let query_params = &req.query_params;
-        match query_params.get("bucket_name") {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        match req.param("bucket_name") {
             Some(bucket_name) => {
-                if self.file_storage.bucket_exists(Path::new(bucket_name)) {
-                    output
-                        .borrow_mut()
-                        .write_all(http::OK_RESPONSE.as_bytes())
-                        .unwrap();
+                let Some(bucket) = bucket_path(bucket_name) else {
+                    println!("invalid bucket name: {}", bucket_name);
+                    return HttpResponse::new(400);
+                };
+                if self.file_storage.bucket_exists(bucket) {
+                    HttpResponse::new(200)
                 } else {
-                    output
-                        .borrow_mut()
-                        .write_all(http::NOT_FOUND.as_bytes())
-                        .unwrap();
+                    HttpResponse::new(404)
                 }
             }
-            None => {
-                output
-                    .borrow_mut()
-                    .write_all(http::BAD_REQUEST.as_bytes())
-                    .unwrap();
-            }
+            None => HttpResponse::new(400),
         }
     }
     fn path(&self) -> &str {
@@ -154,63 +182,79 @@ impl ReadObjectHandler {
     }
 }
 
-impl ReadObjectHandler {
-    fn file_size(file: &File) -> u64 {
-        file.metadata().expect("file must be exist").len()
-    }
-}
-
 impl HttpHandler for ReadObjectHandler {
-    fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>) {
-        let query_params = &req.query_params;
-        let object_name = query_params.get("object_name");
-        let bucket_name = query_params.get("bucket_name");
-        let mut output = output.borrow_mut();
-        if object_name.is_none() || bucket_name.is_none() {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        let object_name = req.param("object_name");
+        let bucket_name = req.param("bucket_name");
+        let (Some(bucket_name), Some(object_name)) = (bucket_name, object_name) else {
             println!("object_name and bucket_name are required");
-            output
-                .write_all(http::BAD_REQUEST.as_bytes())
-                .unwrap_or_else(|e| {
-                    println!("cannot write response: {}", e);
-                });
-        } else {
-            let bucket_name = bucket_name.unwrap();
-            let object_name = object_name.unwrap();
-            let obj_result = self
-                .file_storage
-                .open_file(Path::new(bucket_name).join(object_name).deref());
-            if obj_result.is_err() {
-                println!(
-                    "object_name does not exist: {}, {}",
-                    bucket_name,
-                    &obj_result.unwrap_err()
-                );
-                output
-                    .write_all(http::NOT_FOUND.as_bytes())
-                    .expect("file is not found write panic");
-                return;
+            return HttpResponse::new(400);
+        };
+        let Some(object_path) = object_path(bucket_name, object_name) else {
+            println!("invalid object name: {}, {}", bucket_name, object_name);
+            return HttpResponse::new(400);
+        };
+        let obj = match self.file_storage.open_file(object_path.deref()) {
+            Ok(obj) => obj,
+            Err(e) => {
+                println!("object_name does not exist: {}, {}", bucket_name, &e);
+                return HttpResponse::new(404);
             }
+        };
 
-            let mut obj = obj_result.unwrap();
-            let len = Self::file_size(&obj);
-            output.write_all("HTTP/1.1 200 OK\r\n".as_bytes()).unwrap_or_else(|e| {
-                println!("cannot write response status: {}", e);
-            });
-            output.write_all("Content-type: application/octet-stream\r\n".as_bytes()).unwrap_or_else(|e| {
-                println!("cannot write content-type: {}", e);
-            });
-            let content_len = format!("Content-length: {}\r\n\r\n", len);
-            output.write_all(content_len.as_bytes()).unwrap();
-            let mut buff = [0; 1024 * 1024];
-            loop {
-                let read_bytes = obj.read(&mut buff).expect("read file panic");
-                if read_bytes == 0 {
-                    break;
-                } else {
-                    output.write_all(&buff[0..read_bytes]).unwrap();
+        let len = match obj.len() {
+            Ok(len) => len,
+            Err(e) => {
+                println!("cannot get object size: {}", e);
+                return HttpResponse::new(500);
+            }
+        };
+        let range = req
+            .headers
+            .get("range")
+            .map_or(ByteRange::Full, |value| http::parse_range(value, len));
+        let (status, start, end) = match range {
+            ByteRange::Full => (200, 0, len),
+            ByteRange::Partial(start, end) => (206, start, end),
+            ByteRange::Unsatisfiable => {
+                return HttpResponse::new(416)
+                    .header("Accept-Ranges", "bytes")
+                    .header("Content-Range", &format!("bytes */{}", len));
+            }
+        };
+        let mut response = HttpResponse::new(status)
+            .header("Content-Type", "application/octet-stream")
+            .header("Accept-Ranges", "bytes");
+        if status == 206 {
+            response = response.header("Content-Range", &format!("bytes {}-{}/{}", start, end - 1, len));
+        }
+        if let Ok(meta) = self.file_storage.read_meta(object_path.deref()) {
+            response = response.header("x-lightio-storage-tier", meta.tier.as_str());
+            if let Some(etag) = meta.etag() {
+                response = response.header("ETag", &format!("\"{}\"", etag));
+            }
+            // checksums are of the whole object, not of a range
+            if status == 200 {
+                for (alg, value) in &meta.checksums {
+                    response = response.header(alg.header(), value);
                 }
             }
         }
+        match obj {
+            // sent straight from the file to the socket where possible
+            ObjectReader::File(file) => response.file_range(file, start, end - start).unwrap_or_else(|e| {
+                println!("cannot read object {:?}: {}", object_path, e);
+                HttpResponse::new(500)
+            }),
+            mut obj => {
+                // the other readers cannot seek, what comes before the range is skipped
+                if let Err(e) = io::copy(&mut (&mut obj).take(start), &mut io::sink()) {
+                    println!("cannot read object {:?}: {}", object_path, e);
+                    return HttpResponse::new(500);
+                }
+                response.reader(obj.take(end - start), end - start)
+            }
+        }
     }
 
     fn path(&self) -> &str {
@@ -232,64 +276,127 @@ impl CreateObjectHandler {
 }
 
 impl HttpHandler for CreateObjectHandler {
-    fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>) {
-        let mut output = output.borrow_mut();
-        let query_params = &req.query_params;
-        let bucket_name = query_params.get("bucket_name");
-        let object_name = query_params.get("object_name");
-        if bucket_name.is_none() || object_name.is_none() {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        let bucket_name = req.param("bucket_name");
+        let object_name = req.param("object_name");
+        let (Some(bucket_name), Some(object_name)) = (bucket_name, object_name) else {
             println!("object_name and bucket_name are required");
-            output.write_all(http::BAD_REQUEST.as_bytes()).expect("object name does not exist panic");
-            return;
-        }
-        let bucket_name = bucket_name.unwrap();
-        let object_name = object_name.unwrap();
+            return HttpResponse::new(400);
+        };
 
-        let size = req.headers.get("content-length");
-        if size.is_none() {
-            println!("content-length header missing");
-            output.write_all(http::BAD_REQUEST.as_bytes()).expect("content length header missing panic");
-            return;
-        }
+        // chunked uploads, and HTTP/2 ones without a Content-Length, have no length
+        // upfront, the body reader stops where the body ends
+        let content_size = if req.body.remaining().is_none() {
+            usize::MAX
+        } else {
+            let Some(size) = req.headers.get("content-length") else {
+                println!("content-length header missing");
+                return HttpResponse::new(411);
+            };
+            let Ok(content_size) = size.trim().parse::<usize>() else {
+                println!("content length value is not correct");
+                return HttpResponse::new(400);
+            };
+            content_size
+        };
 
-        let size = size.unwrap().trim().parse::<usize>();
-        if size.is_err() {
-            println!("content length value is not correct");
-            output.write_all(http::BAD_REQUEST.as_bytes()).expect("content length value invalid");
-            return;
+        let mut expected = Vec::new();
+        for alg in ChecksumAlgorithm::ALL {
+            if let Some(value) = req.headers.get(alg.header()) {
+                let value = value.trim();
+                if alg.parse_digest(value).is_none() {
+                    println!("{} is not a valid digest: {}", alg.header(), value);
+                    return HttpResponse::new(400).body(b"InvalidDigest".to_vec());
+                }
+                expected.push((alg, value.to_string()));
+            }
         }
-        let content_size = size.unwrap();
-        let create_object_path = Path::new(bucket_name).join(object_name);
+        // checksums of streamed uploads can follow the body, announced in the Trailer header
+        let announced = req.headers.get("trailer").map_or(Vec::new(), |names| {
+            names.split(',').map(|name| name.trim().to_lowercase()).collect()
+        });
+        // md5 is always kept so reads can echo it even if the client did not send one
+        let mut hashers = vec![(ChecksumAlgorithm::Md5, ChecksumAlgorithm::Md5.hasher())];
+        for alg in ChecksumAlgorithm::ALL {
+            let wanted = expected.iter().any(|(a, _)| *a == alg)
+                || announced.iter().any(|name| name == alg.header());
+            if alg != ChecksumAlgorithm::Md5 && wanted {
+                hashers.push((alg, alg.hasher()));
+            }
+        }
+
+        let Some(create_object_path) = object_path(bucket_name, object_name) else {
+            println!("invalid object name: {}, {}", bucket_name, object_name);
+            return HttpResponse::new(400);
+        };
         let new_file = self.file_storage.create_file(create_object_path.as_path());
         match new_file {
             Ok(mut file) => {
                 let mut buff = [0; 1024*1024];
                 let mut cur_size: usize = 0;
                 let body = &mut req.body;
-                loop {
-                    match body.read(&mut buff) {
+                while cur_size < content_size {
+                    let want = buff.len().min(content_size - cur_size);
+                    match body.read(&mut buff[0..want]) {
                         Ok(0) => break,
                         Ok(written_size) => {
                             println!("{}", String::from_utf8_lossy(&buff[0..written_size]));
-                            file.write_all(&buff[0..written_size]).unwrap_or_else(|e| {
+                            if let Err(e) = file.write_all(&buff[0..written_size]) {
                                 println!("cannot write file: {}", e);
-                            });
-                            cur_size += written_size;
-                            if cur_size >= content_size {
-                                break;
+                                return HttpResponse::new(500);
+                            }
+                            for (_, hasher) in hashers.iter_mut() {
+                                hasher.update(&buff[0..written_size]);
                             }
+                            cur_size += written_size;
                         }
                         Err(e) => {
                             println!("cannot read response: {}", e);
+                            break;
                         }
                     }
                 }
 
-                output.write_all(http::OK_RESPONSE.as_bytes()).expect("write response panic");
+                // returning before commit_file leaves the previous object in place
+                if req.body.remaining() != Some(0) {
+                    println!("incomplete body for {:?} after {} bytes", create_object_path, cur_size);
+                    return HttpResponse::new(400);
+                }
+
+                for alg in ChecksumAlgorithm::ALL {
+                    let Some(value) = req.body.trailers().get(alg.header()) else {
+                        continue;
+                    };
+                    let value = value.trim();
+                    if alg.parse_digest(value).is_none() || !hashers.iter().any(|(a, _)| *a == alg) {
+                        println!("{} trailer is not valid or not announced: {}", alg.header(), value);
+                        return HttpResponse::new(400).body(b"InvalidDigest".to_vec());
+                    }
+                    expected.push((alg, value.to_string()));
+                }
+
+                let checksums = hashers
+                    .into_iter()
+                    .map(|(alg, hasher)| (alg, base64_encode(&hasher.finish())))
+                    .collect::<Vec<_>>();
+                let mismatch = expected.iter().find(|(alg, value)| {
+                    checksums.iter().any(|(a, actual)| a == alg && base64_decode(actual) != base64_decode(value))
+                });
+                if let Some((alg, value)) = mismatch {
+                    println!("{} mismatch for {:?}: expected {}", alg.header(), create_object_path, value);
+                    return HttpResponse::new(400).body(b"BadDigest".to_vec());
+                }
+
+                let meta = ObjectMeta::new(cur_size as u64, checksums);
+                if let Err(e) = self.file_storage.commit_file(create_object_path.as_path(), file, &meta) {
+                    println!("cannot finish object file: {}", e);
+                    return HttpResponse::new(500);
+                }
+                HttpResponse::new(200)
             }
             Err(e) => {
                 println!("cannot create object file: {}", e);
-                output.write_all(http::SERVER_ERROR.as_bytes()).expect("write response panic");
+                HttpResponse::new(500)
             }
         }
     }
@@ -301,3 +408,55 @@ impl HttpHandler for CreateObjectHandler {
         HttpMethod::POST
     }
 }
+
+// storage stats for monitoring, one "name value" pair per line
+pub struct StatsHandler {
+    file_storage: &'static FileStorage,
+}
+impl StatsHandler {
+    pub fn new(file_storage: &'static FileStorage) -> Self {
+        StatsHandler { file_storage }
+    }
+}
+
+impl HttpHandler for StatsHandler {
+    fn handle_request(&self, _req: &mut HttpReq) -> HttpResponse {
+        let stats = self.file_storage.cache_stats().unwrap_or_default();
+        let body = format!(
+            "cache_hits {}\ncache_misses {}\ncache_evictions {}\ncache_entries {}\ncache_bytes {}\n",
+            stats.hits, stats.misses, stats.evictions, stats.entries, stats.bytes
+        );
+        HttpResponse::new(200)
+            .header("Content-Type", "text/plain")
+            .body(body.into_bytes())
+    }
+
+    fn path(&self) -> &str {
+        STATS_PATH
+    }
+    fn method(&self) -> HttpMethod {
+        HttpMethod::GET
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_paths_test() {
+        assert_eq!(Some(Path::new("b")), bucket_path("b"));
+        assert_eq!(Some(PathBuf::from("b/x/y.txt")), object_path("b", "x/y.txt"));
+        assert_eq!(Some(PathBuf::from("b/..x")), object_path("b", "..x"));
+        assert_eq!(Some(PathBuf::from("b/o.lightio-tmp.txt")), object_path("b", "o.lightio-tmp.txt"));
+
+        for bucket in ["", ".", "..", "a/b", "/etc", SYSTEM_DIR, "b.lightio-tmp"] {
+            assert_eq!(None, bucket_path(bucket), "{}", bucket);
+            assert_eq!(None, object_path(bucket, "o"), "{}", bucket);
+        }
+        let objects = ["", ".", "..", "../o", "x/../../o", "/etc/passwd", "./o", "o.lightio-tmp", "x.lightio-tmp/o"];
+        for object in objects {
+            assert_eq!(None, object_path("b", object), "{}", object);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// syscall numbers, the same on every architecture since the table was unified
+const SYS_IO_URING_SETUP: i64 = 425;
+const SYS_IO_URING_ENTER: i64 = 426;
+const SYS_IO_URING_REGISTER: i64 = 427;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_BUFFERS: u32 = 0;
+
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const MAP_SHARED: i32 = 1;
+const MAP_POPULATE: i32 = 0x8000;
+const MAP_FAILED: *mut u8 = !0 as *mut u8;
+const EINTR: i32 = 4;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+// struct io_uring_params
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+// struct io_uring_sqe, only the fields reads and writes use
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+// struct io_uring_cqe
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct IoVec {
+    base: *mut u8,
+    len: usize,
+}
+
+unsafe extern "C" {
+    fn syscall(number: i64, ...) -> i64;
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+fn check(result: i64) -> io::Result<i64> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Self> {
+        let ptr = unsafe {
+            mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_POPULATE, fd, offset)
+        };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    // the offsets come from the kernel and are within the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+// A read or write for the submission queue. The buffer must stay valid, and
+// not be moved, until its completion is reaped.
+pub enum Op {
+    Read { fd: RawFd, buf: *mut u8, len: u32, offset: u64 },
+    Write { fd: RawFd, buf: *const u8, len: u32, offset: u64 },
+    // buf lies in the registered buffer at index
+    ReadFixed { fd: RawFd, buf: *mut u8, len: u32, offset: u64, index: u16 },
+    WriteFixed { fd: RawFd, buf: *const u8, len: u32, offset: u64, index: u16 },
+}
+
+// A single io_uring instance. Not shared between threads, a caller owns it and
+// pushes operations, then submits them all with one system call.
+pub struct IoUring {
+    fd: OwnedFd,
+    // only kept mapped, the pointers below lead into them
+    _sq: Mapping,
+    _cq: Mapping,
+    sqes: Mapping,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    // pushed but not yet handed to the kernel
+    pending: u32,
+    // handed to the kernel and not yet reaped
+    in_flight: u32,
+}
+
+// the rings are only touched through &mut self
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = check(unsafe { syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut Params) })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let sq = Mapping::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Mapping::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?;
+        let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+        let sqes = Mapping::new(fd.as_raw_fd(), sqes_len, IORING_OFF_SQES)?;
+        Ok(Self {
+            sq_head: sq.at(params.sq_off.head),
+            sq_tail: sq.at(params.sq_off.tail),
+            sq_mask: unsafe { *sq.at::<u32>(params.sq_off.ring_mask) },
+            sq_entries: params.sq_entries,
+            sq_array: sq.at(params.sq_off.array),
+            cq_head: cq.at(params.cq_off.head),
+            cq_tail: cq.at(params.cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(params.cq_off.ring_mask) },
+            cqes: cq.at(params.cq_off.cqes),
+            fd,
+            _sq: sq,
+            _cq: cq,
+            sqes,
+            pending: 0,
+            in_flight: 0,
+        })
+    }
+
+    // Pins the buffers for ReadFixed and WriteFixed, which then skip mapping the
+    // pages on every operation. They must outlive the ring and never move.
+    pub fn register_buffers(&mut self, buffers: &mut [Box<[u8]>]) -> io::Result<()> {
+        let iovecs = buffers
+            .iter_mut()
+            .map(|b| IoVec { base: b.as_mut_ptr(), len: b.len() })
+            .collect::<Vec<_>>();
+        check(unsafe {
+            syscall(
+                SYS_IO_URING_REGISTER,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_BUFFERS,
+                iovecs.as_ptr(),
+                iovecs.len() as u32,
+            )
+        })?;
+        Ok(())
+    }
+
+    // how many more operations fit before the next submit
+    pub fn space(&self) -> u32 {
+        self.sq_entries - self.pending - self.in_flight
+    }
+
+    // Queues an operation, its completion carries user_data. Returns false when
+    // the queue is full.
+    pub fn push(&mut self, op: Op, user_data: u64) -> bool {
+        if self.space() == 0 {
+            return false;
+        }
+        let (opcode, fd, addr, len, off, buf_index) = match op {
+            Op::Read { fd, buf, len, offset } => (IORING_OP_READ, fd, buf as u64, len, offset, 0),
+            Op::Write { fd, buf, len, offset } => (IORING_OP_WRITE, fd, buf as u64, len, offset, 0),
+            Op::ReadFixed { fd, buf, len, offset, index } => {
+                (IORING_OP_READ_FIXED, fd, buf as u64, len, offset, index)
+            }
+            Op::WriteFixed { fd, buf, len, offset, index } => {
+                (IORING_OP_WRITE_FIXED, fd, buf as u64, len, offset, index)
+            }
+        };
+        // only this side moves the tail
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+        let slot = tail & self.sq_mask;
+        unsafe {
+            let sqe = (self.sqes.ptr as *mut Sqe).add(slot as usize);
+            sqe.write(Sqe {
+                opcode,
+                flags: 0,
+                ioprio: 0,
+                fd,
+                off,
+                addr,
+                len,
+                rw_flags: 0,
+                user_data,
+                buf_index,
+                personality: 0,
+                splice_fd_in: 0,
+                addr3: 0,
+                pad: 0,
+            });
+            *self.sq_array.add(slot as usize) = slot;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pending += 1;
+        true
+    }
+
+    // Hands the pushed operations to the kernel and waits until at least
+    // `wait_for` completions are ready.
+    pub fn submit(&mut self, wait_for: u32) -> io::Result<()> {
+        let mut to_submit = self.pending;
+        loop {
+            let flags = if wait_for > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+            let result = unsafe {
+                syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd.as_raw_fd(),
+                    to_submit,
+                    wait_for,
+                    flags,
+                    ptr::null::<u8>(),
+                    0usize,
+                )
+            };
+            match check(result) {
+                Ok(submitted) => {
+                    self.pending -= submitted as u32;
+                    self.in_flight += submitted as u32;
+                    return Ok(());
+                }
+                Err(e) if e.raw_os_error() == Some(EINTR) => {
+                    // a signal may arrive after the operations were taken
+                    let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+                    let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+                    let queued = tail.wrapping_sub(head);
+                    self.in_flight += self.pending - queued;
+                    self.pending = queued;
+                    to_submit = queued;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Takes one completion, if any is ready: its user_data and the result, the
+    // number of bytes moved or an error.
+    pub fn complete(&mut self) -> Option<(u64, io::Result<usize>)> {
+        // only this side moves the head
+        let head = unsafe { (*self.cq_head).load(Ordering::Relaxed) };
+        let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+        if head == tail {
+            return None;
+        }
+        let cqe = unsafe { &*self.cqes.add((head & self.cq_mask) as usize) };
+        let result = if cqe.res < 0 {
+            Err(io::Error::from_raw_os_error(-cqe.res))
+        } else {
+            Ok(cqe.res as usize)
+        };
+        let user_data = cqe.user_data;
+        unsafe { (*self.cq_head).store(head.wrapping_add(1), Ordering::Release) };
+        self.in_flight -= 1;
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn read_write_test() {
+        let mut ring = IoUring::new(8).unwrap();
+        let path = std::env::temp_dir().join("lightio_io_uring_test");
+        let file = File::create(&path).unwrap();
+        let data = b"written through the ring";
+        let op = Op::Write { fd: file.as_raw_fd(), buf: data.as_ptr(), len: data.len() as u32, offset: 4 };
+        assert!(ring.push(op, 1));
+        ring.submit(1).unwrap();
+        let (user_data, written) = ring.complete().unwrap();
+        assert_eq!((1, data.len()), (user_data, written.unwrap()));
+        assert!(ring.complete().is_none());
+
+        let file = File::open(&path).unwrap();
+        let mut first = [0u8; 11];
+        let mut second = [0u8; 64];
+        let op = Op::Read { fd: file.as_raw_fd(), buf: first.as_mut_ptr(), len: 11, offset: 4 };
+        assert!(ring.push(op, 2));
+        let op = Op::Read { fd: file.as_raw_fd(), buf: second.as_mut_ptr(), len: 64, offset: 16 };
+        assert!(ring.push(op, 3));
+        ring.submit(2).unwrap();
+        let mut results = [ring.complete().unwrap(), ring.complete().unwrap()];
+        results.sort_by_key(|(user_data, _)| *user_data);
+        assert_eq!(11, results[0].1.as_ref().unwrap().clone());
+        assert_eq!(b"written thr", &first);
+        // the file ends early
+        assert_eq!(12, results[1].1.as_ref().unwrap().clone());
+        assert_eq!(b"the ring", &second[4..12]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fixed_buffers_test() {
+        let mut ring = IoUring::new(4).unwrap();
+        let mut buffers: Vec<Box<[u8]>> = vec![vec![0u8; 4096].into(), vec![0u8; 4096].into()];
+        ring.register_buffers(&mut buffers).unwrap();
+        let path = std::env::temp_dir().join("lightio_io_uring_fixed_test");
+        File::create(&path).unwrap().write_all(&[7u8; 5000]).unwrap();
+        let file = File::open(&path).unwrap();
+        let buf = buffers[1].as_mut_ptr();
+        let op = Op::ReadFixed { fd: file.as_raw_fd(), buf, len: 4096, offset: 1000, index: 1 };
+        assert!(ring.push(op, 9));
+        ring.submit(1).unwrap();
+        let (user_data, read) = ring.complete().unwrap();
+        assert_eq!((9, 4000), (user_data, read.unwrap()));
+        assert!(buffers[1][..4000].iter().all(|b| *b == 7));
+
+        // a bad descriptor fails only its own operation
+        let op = Op::Read { fd: -1, buf: buffers[0].as_mut_ptr(), len: 1, offset: 0 };
+        assert!(ring.push(op, 10));
+        ring.submit(1).unwrap();
+        let (user_data, read) = ring.complete().unwrap();
+        assert_eq!(10, user_data);
+        assert!(read.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
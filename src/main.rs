@@ -1,25 +1,261 @@
+mod checksum;
+#[cfg(target_os = "linux")]
+mod epoll;
+mod erasure;
 mod file_storage;
+mod hash_ring;
+mod hpack;
 mod http;
 mod http_handler;
+#[cfg(target_os = "linux")]
+mod io_uring;
+mod middleware;
+mod reed_solomon;
+mod router;
+mod sendfile;
 mod server;
+// on other systems only the types are left, UringIo::new fails
+#[cfg_attr(not(target_os = "linux"), allow(dead_code, unused_imports))]
+mod storage_io;
 mod thread_pool;
+mod tls;
 mod http_client;
+mod object_cache;
 
-use crate::file_storage::FileStorageConfig;
+use crate::file_storage::{FileStorageConfig, IoEngine};
+use crate::http::HttpMethod;
 use crate::http_handler::*;
-use crate::server::HttpServerConfig;
+use crate::middleware::{AccessLog, RequestId};
+use crate::server::{Engine, HttpServerConfig, ListenAddr};
+use crate::tls::TlsConfig;
 use file_storage::FileStorage;
 use server::HttpServer;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "usage: lightio [rebalance] [--data-path <dir>]... [--drain <dir>]... \
+                     [--data-shards <k> --parity-shards <m>] \
+                     [--cold-path <dir> --cold-after-days <n> [--promote-on-read]] \
+                     [--cache-size-mb <n>] [--epoll] [--io-uring] \
+                     [--listen <addr:port>|unix:<path>]... \
+                     [--tls-listen <addr:port>|unix:<path>]... [--tls-cert <pem> --tls-key <pem> \
+                     [--tls-sni <name>,<cert pem>,<key pem>]... [--tls-client-ca <pem>]]";
+
+struct Args {
+    rebalance: bool,
+    data_paths: Vec<String>,
+    drain: Vec<PathBuf>,
+    data_shards: Option<usize>,
+    parity_shards: Option<usize>,
+    cold_path: Option<String>,
+    cold_after_days: Option<usize>,
+    promote_on_read: bool,
+    cache_size_mb: Option<usize>,
+    epoll: bool,
+    io_uring: bool,
+    listen: Vec<ListenAddr>,
+    tls_listen: Vec<ListenAddr>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_sni: Vec<(String, String, String)>,
+    tls_client_ca: Option<String>,
+}
+
+fn parse_count(value: Option<String>, name: &str) -> Result<usize, String> {
+    value
+        .ok_or(format!("{} requires a value", name))?
+        .parse()
+        .map_err(|_| format!("{} must be a number", name))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        rebalance: false,
+        data_paths: Vec::new(),
+        drain: Vec::new(),
+        data_shards: None,
+        parity_shards: None,
+        cold_path: None,
+        cold_after_days: None,
+        promote_on_read: false,
+        cache_size_mb: None,
+        epoll: false,
+        io_uring: false,
+        listen: Vec::new(),
+        tls_listen: Vec::new(),
+        tls_cert: None,
+        tls_key: None,
+        tls_sni: Vec::new(),
+        tls_client_ca: None,
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "rebalance" => args.rebalance = true,
+            "--data-path" => args
+                .data_paths
+                .push(iter.next().ok_or("--data-path requires a value")?),
+            "--drain" => args
+                .drain
+                .push(PathBuf::from(iter.next().ok_or("--drain requires a value")?)),
+            "--data-shards" => args.data_shards = Some(parse_count(iter.next(), &arg)?),
+            "--parity-shards" => args.parity_shards = Some(parse_count(iter.next(), &arg)?),
+            "--cold-path" => {
+                args.cold_path = Some(iter.next().ok_or("--cold-path requires a value")?)
+            }
+            "--cold-after-days" => args.cold_after_days = Some(parse_count(iter.next(), &arg)?),
+            "--promote-on-read" => args.promote_on_read = true,
+            "--cache-size-mb" => args.cache_size_mb = Some(parse_count(iter.next(), &arg)?),
+            "--epoll" => args.epoll = true,
+            "--io-uring" => args.io_uring = true,
+            "--listen" => args
+                .listen
+                .push(iter.next().ok_or("--listen requires a value")?.parse()?),
+            "--tls-listen" => args
+                .tls_listen
+                .push(iter.next().ok_or("--tls-listen requires a value")?.parse()?),
+            "--tls-cert" => args.tls_cert = Some(iter.next().ok_or("--tls-cert requires a value")?),
+            "--tls-key" => args.tls_key = Some(iter.next().ok_or("--tls-key requires a value")?),
+            "--tls-sni" => {
+                let value = iter.next().ok_or("--tls-sni requires a value")?;
+                let mut parts = value.splitn(3, ',').map(str::to_string);
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(name), Some(cert), Some(key)) => args.tls_sni.push((name, cert, key)),
+                    _ => return Err("--tls-sni takes <name>,<cert pem>,<key pem>".to_string()),
+                }
+            }
+            "--tls-client-ca" => {
+                args.tls_client_ca = Some(iter.next().ok_or("--tls-client-ca requires a value")?)
+            }
+            unknown => return Err(format!("unknown argument: {}", unknown)),
+        }
+    }
+    Ok(args)
+}
 
 fn main() {
-    let file_storage_config = FileStorageConfig::new();
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+    let mut file_storage_config = FileStorageConfig::new();
+    if !args.data_paths.is_empty() {
+        file_storage_config = file_storage_config.data_paths(args.data_paths);
+    }
+    match (args.data_shards, args.parity_shards) {
+        (Some(data_shards), Some(parity_shards)) => {
+            file_storage_config = file_storage_config.erasure_coding(data_shards, parity_shards);
+        }
+        (None, None) => {}
+        _ => {
+            eprintln!("--data-shards and --parity-shards go together\n{}", USAGE);
+            process::exit(2);
+        }
+    }
+    match (args.cold_path, args.cold_after_days) {
+        (Some(cold_path), Some(days)) => {
+            let max_idle = Duration::from_secs(days as u64 * 24 * 3600);
+            file_storage_config = file_storage_config
+                .cold_tier(cold_path, max_idle)
+                .promote_on_read(args.promote_on_read);
+        }
+        (None, None) => {}
+        _ => {
+            eprintln!("--cold-path and --cold-after-days go together\n{}", USAGE);
+            process::exit(2);
+        }
+    }
+    if let Some(cache_size_mb) = args.cache_size_mb {
+        file_storage_config =
+            file_storage_config.cache(cache_size_mb as u64 * 1024 * 1024, 256 * 1024);
+    }
+    if args.io_uring {
+        file_storage_config = file_storage_config.io_engine(IoEngine::IoUring);
+    }
     let file_storage = Box::new(FileStorage::new(file_storage_config).unwrap());
     let file_storage: &'static FileStorage = Box::leak(file_storage);
-    HttpServer::start(HttpServerConfig::new().handlers(vec![
+    if args.rebalance {
+        match file_storage.rebalance(&args.drain) {
+            Ok(moved) => println!("rebalance finished, {} objects moved", moved),
+            Err(e) => {
+                eprintln!("rebalance failed: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    file_storage.start_healer();
+    file_storage.start_tiering();
+    let engine = if args.epoll { Engine::Epoll } else { Engine::Threads };
+    let mut config = HttpServerConfig::new()
+        .engine(engine)
+        .middleware(RequestId::new())
+        .middleware(AccessLog);
+    for addr in args.listen {
+        config = config.listen(addr);
+    }
+    if !args.tls_listen.is_empty() {
+        let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) else {
+            eprintln!("--tls-listen requires --tls-cert and --tls-key\n{}", USAGE);
+            process::exit(2);
+        };
+        if args.epoll {
+            eprintln!("--tls-listen cannot be used with --epoll\n{}", USAGE);
+            process::exit(2);
+        }
+        let mut tls = TlsConfig::new(cert, key);
+        for (name, cert, key) in args.tls_sni {
+            tls = tls.server_name(name, cert, key);
+        }
+        if let Some(client_ca) = args.tls_client_ca {
+            tls = tls.client_ca(client_ca);
+        }
+        for addr in args.tls_listen {
+            config = config.listen_tls(addr, tls.clone());
+        }
+    }
+    handle_stop_signals();
+    let server = HttpServer::start(config.handlers(vec![
         Box::new(BucketCreateHandler::new(file_storage)),
         Box::new(BucketDeleteHandler::new(file_storage)),
         Box::new(BucketExistsHandler::new(file_storage)),
         Box::new(ReadObjectHandler::new(file_storage)),
         Box::new(CreateObjectHandler::new(file_storage)),
-    ]))
+        Box::new(StatsHandler::new(file_storage)),
+        Box::new(Routed::new(REST_BUCKET_PATH, HttpMethod::PUT, BucketCreateHandler::new(file_storage))),
+        Box::new(Routed::new(REST_BUCKET_PATH, HttpMethod::DELETE, BucketDeleteHandler::new(file_storage))),
+        Box::new(Routed::new(REST_BUCKET_PATH, HttpMethod::GET, BucketExistsHandler::new(file_storage))),
+        Box::new(Routed::new(REST_OBJECT_PATH, HttpMethod::GET, ReadObjectHandler::new(file_storage))),
+        Box::new(Routed::new(REST_OBJECT_PATH, HttpMethod::PUT, CreateObjectHandler::new(file_storage))),
+    ]));
+    while !STOP.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    server.shutdown();
+}
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+// set once SIGINT or SIGTERM arrives
+static STOP: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+extern "C" fn on_stop_signal(_signum: i32) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn handle_stop_signals() {
+    // the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, on_stop_signal);
+        signal(SIGTERM, on_stop_signal);
+    }
 }
@@ -0,0 +1,92 @@
+use crate::http::{HttpReq, HttpResponse};
+use crate::http_handler::HttpHandler;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Wraps handler invocation. A middleware either calls `next.run(req)` to continue
+// down the chain and may change the response on the way back, or returns its own
+// response without calling it to short-circuit the request.
+pub trait Middleware {
+    fn handle(&self, req: &mut HttpReq, next: Next) -> HttpResponse;
+}
+
+pub type ArcMiddleware = Arc<dyn Middleware + Send + Sync>;
+
+// the rest of the chain: remaining middlewares, then the handler
+pub struct Next<'a> {
+    middlewares: &'a [ArcMiddleware],
+    handler: &'a dyn HttpHandler,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middlewares: &'a [ArcMiddleware], handler: &'a dyn HttpHandler) -> Self {
+        Self { middlewares, handler }
+    }
+
+    pub fn run(self, req: &mut HttpReq) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(req, Next::new(rest, self.handler)),
+            None => self.handler.handle_request(req),
+        }
+    }
+}
+
+// Echoes the client's x-request-id or assigns a new one, unique per process run.
+pub struct RequestId {
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub const HEADER: &'static str = "x-request-id";
+
+    pub fn new() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            prefix: format!("{:x}", started),
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, req: &mut HttpReq, next: Next) -> HttpResponse {
+        let id = match req.headers.get(Self::HEADER) {
+            Some(id) => id.trim().to_string(),
+            None => {
+                let n = self.counter.fetch_add(1, Ordering::Relaxed);
+                let id = format!("{}-{}", self.prefix, n);
+                req.headers.insert(Self::HEADER, &id);
+                id
+            }
+        };
+        next.run(req).header(Self::HEADER, &id)
+    }
+}
+
+// one line per request with its status and handling time
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn handle(&self, req: &mut HttpReq, next: Next) -> HttpResponse {
+        let started = Instant::now();
+        let method = req.method.clone();
+        let path = req.path.clone();
+        let version = req.version;
+        let request_id = req.headers.get(RequestId::HEADER).cloned();
+        let response = next.run(req);
+        println!(
+            "{} {} {} {} {:?} {}",
+            method.as_str(),
+            path,
+            version.as_str(),
+            response.status,
+            started.elapsed(),
+            request_id.as_deref().unwrap_or("-")
+        );
+        response
+    }
+}
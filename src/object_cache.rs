@@ -0,0 +1,245 @@
+use crate::file_storage::ObjectMeta;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone)]
+pub struct CachedObject {
+    pub meta: ObjectMeta,
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    object: CachedObject,
+    // position in Lru::order
+    tick: u64,
+}
+
+// invalidation counters, paths share them by hash
+const GENERATIONS: usize = 1024;
+
+#[derive(Debug)]
+struct Lru {
+    entries: HashMap<PathBuf, Entry>,
+    // least recently used first
+    order: BTreeMap<u64, PathBuf>,
+    next_tick: u64,
+    bytes: u64,
+    generations: Vec<u64>,
+}
+
+fn slot(path: &Path) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish() as usize % GENERATIONS
+}
+
+// Least recently used cache of small objects keyed by "bucket/object", bounded by
+// the total size of the cached data. Entries remember the ETag they were read with.
+// Invalidating a path bumps its generation, so an object read before that, from a
+// file that has been replaced since, is not inserted.
+#[derive(Debug)]
+pub struct ObjectCache {
+    max_bytes: u64,
+    max_object_size: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ObjectCache {
+    pub fn new(max_bytes: u64, max_object_size: u64) -> Self {
+        Self {
+            max_bytes,
+            max_object_size: max_object_size.min(max_bytes),
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_tick: 0,
+                bytes: 0,
+                generations: vec![0; GENERATIONS],
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn cacheable(&self, size: u64) -> bool {
+        size <= self.max_object_size
+    }
+
+    pub fn get(&self, path: &Path) -> Option<CachedObject> {
+        let mut lru = self.lru.lock().unwrap();
+        let tick = lru.next_tick;
+        let Some(entry) = lru.entries.get_mut(path) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let old_tick = entry.tick;
+        entry.tick = tick;
+        let object = entry.object.clone();
+        lru.next_tick += 1;
+        lru.order.remove(&old_tick);
+        lru.order.insert(tick, path.to_path_buf());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(object)
+    }
+
+    // like get, but neither counted in the stats nor refreshing the entry
+    pub fn peek(&self, path: &Path) -> Option<CachedObject> {
+        let lru = self.lru.lock().unwrap();
+        lru.entries.get(path).map(|entry| entry.object.clone())
+    }
+
+    // to be taken before reading the object that is then inserted
+    pub fn generation(&self, path: &Path) -> u64 {
+        self.lru.lock().unwrap().generations[slot(path)]
+    }
+
+    pub fn insert(&self, path: &Path, object: CachedObject, generation: u64) {
+        let size = object.data.len() as u64;
+        if !self.cacheable(size) {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        if lru.generations[slot(path)] != generation {
+            return;
+        }
+        Self::remove_entry(&mut lru, path);
+        while lru.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                lru.bytes -= entry.object.data.len() as u64;
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let tick = lru.next_tick;
+        lru.next_tick += 1;
+        lru.order.insert(tick, path.to_path_buf());
+        lru.bytes += size;
+        lru.entries.insert(path.to_path_buf(), Entry { object, tick });
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.generations[slot(path)] += 1;
+        Self::remove_entry(&mut lru, path);
+    }
+
+    // drops every object under a path, used when a bucket is deleted
+    pub fn invalidate_prefix(&self, prefix: &Path) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.generations.iter_mut().for_each(|generation| *generation += 1);
+        let paths = lru
+            .entries
+            .keys()
+            .filter(|p| p.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in paths {
+            Self::remove_entry(&mut lru, &path);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: lru.entries.len() as u64,
+            bytes: lru.bytes,
+        }
+    }
+
+    fn remove_entry(lru: &mut Lru, path: &Path) {
+        if let Some(entry) = lru.entries.remove(path) {
+            lru.order.remove(&entry.tick);
+            lru.bytes -= entry.object.data.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(len: usize) -> CachedObject {
+        CachedObject {
+            meta: ObjectMeta::new(len as u64, Vec::new()),
+            data: vec![0u8; len].into(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_test() {
+        let cache = ObjectCache::new(30, 10);
+        cache.insert(Path::new("b/1"), object(10), 0);
+        cache.insert(Path::new("b/2"), object(10), 0);
+        cache.insert(Path::new("b/3"), object(10), 0);
+        assert!(cache.get(Path::new("b/1")).is_some());
+
+        cache.insert(Path::new("b/4"), object(10), 0);
+
+        assert!(cache.get(Path::new("b/2")).is_none());
+        assert!(cache.get(Path::new("b/1")).is_some());
+        let stats = cache.stats();
+        assert_eq!((2, 1, 1), (stats.hits, stats.misses, stats.evictions));
+        assert_eq!((3, 30), (stats.entries, stats.bytes));
+    }
+
+    #[test]
+    fn skips_large_objects_test() {
+        let cache = ObjectCache::new(100, 10);
+        cache.insert(Path::new("b/big"), object(11), 0);
+        assert!(cache.get(Path::new("b/big")).is_none());
+    }
+
+    #[test]
+    fn invalidate_test() {
+        let cache = ObjectCache::new(100, 10);
+        cache.insert(Path::new("b/1"), object(5), 0);
+        cache.insert(Path::new("c/1"), object(5), 0);
+        cache.invalidate_prefix(Path::new("b"));
+        assert!(cache.get(Path::new("b/1")).is_none());
+        cache.invalidate(Path::new("c/1"));
+        assert!(cache.get(Path::new("c/1")).is_none());
+        assert_eq!(0, cache.stats().bytes);
+    }
+
+    #[test]
+    fn stale_insert_test() {
+        let cache = ObjectCache::new(100, 10);
+        let path = Path::new("b/1");
+        // the object changed while it was read
+        let generation = cache.generation(path);
+        cache.invalidate(path);
+        cache.insert(path, object(5), generation);
+        assert!(cache.get(path).is_none());
+
+        cache.insert(path, object(5), cache.generation(path));
+        assert!(cache.get(path).is_some());
+        // deleting a bucket counts for every path
+        let generation = cache.generation(Path::new("b/2"));
+        cache.invalidate_prefix(Path::new("c"));
+        cache.insert(Path::new("b/2"), object(5), generation);
+        assert!(cache.get(Path::new("b/2")).is_none());
+    }
+}
@@ -0,0 +1,239 @@
+// Systematic Reed-Solomon code over GF(2^8): the first k shards hold the data as is,
+// the m parity shards are rows of a Cauchy matrix applied to the data shards. Any k
+// of the k + m shards are enough to rebuild the rest.
+
+const GF_POLY: u16 = 0x11d;
+
+const GF_EXP: [u8; 512] = {
+    let mut exp = [0u8; 512];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= GF_POLY;
+        }
+        i += 1;
+    }
+    exp
+};
+
+const GF_LOG: [u8; 256] = {
+    let mut log = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        log[GF_EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    log
+};
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    GF_EXP[255 - GF_LOG[a as usize] as usize]
+}
+
+// out ^= c * input
+fn gf_mul_add(c: u8, input: &[u8], out: &mut [u8]) {
+    if c == 0 {
+        return;
+    }
+    let log_c = GF_LOG[c as usize] as usize;
+    for (o, i) in out.iter_mut().zip(input) {
+        if *i != 0 {
+            *o ^= GF_EXP[log_c + GF_LOG[*i as usize] as usize];
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReedSolomon {
+    data_shards: usize,
+    parity_shards: usize,
+    // (k + m) x k encoding matrix, identity on top
+    matrix: Vec<Vec<u8>>,
+}
+
+impl ReedSolomon {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, String> {
+        if data_shards == 0 || parity_shards == 0 {
+            return Err("data and parity shard counts must be positive".to_string());
+        }
+        if data_shards + parity_shards > 256 {
+            return Err("at most 256 shards are supported".to_string());
+        }
+        let mut matrix = Vec::with_capacity(data_shards + parity_shards);
+        for row in 0..data_shards {
+            let mut r = vec![0u8; data_shards];
+            r[row] = 1;
+            matrix.push(r);
+        }
+        for row in 0..parity_shards {
+            let x = (data_shards + row) as u8;
+            matrix.push((0..data_shards).map(|col| gf_inv(x ^ col as u8)).collect());
+        }
+        Ok(Self {
+            data_shards,
+            parity_shards,
+            matrix,
+        })
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    // fills the parity shards from the data shards, all shards must have the same length
+    pub fn encode(&self, shards: &mut [Vec<u8>]) {
+        let (data, parity) = shards.split_at_mut(self.data_shards);
+        for (i, out) in parity.iter_mut().enumerate() {
+            out.iter_mut().for_each(|b| *b = 0);
+            for (j, input) in data.iter().enumerate() {
+                gf_mul_add(self.matrix[self.data_shards + i][j], input, out);
+            }
+        }
+    }
+
+    // rebuilds every missing shard, fails when fewer than k shards are present
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<(), String> {
+        let present = shards
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.as_ref().map(|_| i))
+            .take(self.data_shards)
+            .collect::<Vec<_>>();
+        if present.len() < self.data_shards {
+            return Err(format!(
+                "{} shards available, {} required",
+                present.len(),
+                self.data_shards
+            ));
+        }
+        if shards.iter().all(|s| s.is_some()) {
+            return Ok(());
+        }
+        let len = shards[present[0]].as_ref().map_or(0, |s| s.len());
+
+        if shards[..self.data_shards].iter().any(|s| s.is_none()) {
+            let sub = present
+                .iter()
+                .map(|&i| self.matrix[i].clone())
+                .collect::<Vec<_>>();
+            let decode = invert(sub).ok_or("encoding matrix is singular")?;
+            for row in 0..self.data_shards {
+                if shards[row].is_some() {
+                    continue;
+                }
+                let mut out = vec![0u8; len];
+                for (col, &i) in present.iter().enumerate() {
+                    let input = shards[i].as_ref().expect("present shard");
+                    gf_mul_add(decode[row][col], input, &mut out);
+                }
+                shards[row] = Some(out);
+            }
+        }
+
+        for row in self.data_shards..self.total_shards() {
+            if shards[row].is_some() {
+                continue;
+            }
+            let mut out = vec![0u8; len];
+            for (col, input) in shards[..self.data_shards].iter().enumerate() {
+                let input = input.as_ref().expect("data shard rebuilt");
+                gf_mul_add(self.matrix[row][col], input, &mut out);
+            }
+            shards[row] = Some(out);
+        }
+        Ok(())
+    }
+}
+
+// Gauss-Jordan elimination in GF(2^8)
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut inverse = (0..n)
+        .map(|i| {
+            let mut r = vec![0u8; n];
+            r[i] = 1;
+            r
+        })
+        .collect::<Vec<_>>();
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| matrix[r][col] != 0)?;
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = gf_inv(matrix[col][col]);
+        for c in 0..n {
+            matrix[col][c] = gf_mul(matrix[col][c], scale);
+            inverse[col][c] = gf_mul(inverse[col][c], scale);
+        }
+        for r in 0..n {
+            let factor = matrix[r][col];
+            if r == col || factor == 0 {
+                continue;
+            }
+            for c in 0..n {
+                matrix[r][c] ^= gf_mul(factor, matrix[col][c]);
+                inverse[r][c] ^= gf_mul(factor, inverse[col][c]);
+            }
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(rs: &ReedSolomon, len: usize) -> Vec<Vec<u8>> {
+        let mut shards = (0..rs.total_shards())
+            .map(|i| (0..len).map(|j| (i * 31 + j * 7) as u8).collect())
+            .collect::<Vec<Vec<u8>>>();
+        rs.encode(&mut shards);
+        shards
+    }
+
+    #[test]
+    fn reconstruct_any_missing_test() {
+        let rs = ReedSolomon::new(4, 2).unwrap();
+        let original = encoded(&rs, 100);
+        for a in 0..6 {
+            for b in a + 1..6 {
+                let mut shards = original.iter().cloned().map(Some).collect::<Vec<_>>();
+                shards[a] = None;
+                shards[b] = None;
+                rs.reconstruct(&mut shards).unwrap();
+                let shards = shards.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+                assert_eq!(original, shards, "lost shards {} and {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_missing_test() {
+        let rs = ReedSolomon::new(3, 1).unwrap();
+        let mut shards = encoded(&rs, 10).into_iter().map(Some).collect::<Vec<_>>();
+        shards[0] = None;
+        shards[3] = None;
+        assert!(rs.reconstruct(&mut shards).is_err());
+    }
+
+    #[test]
+    fn gf_inverse_test() {
+        for a in 1..=255u8 {
+            assert_eq!(1, gf_mul(a, gf_inv(a)));
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::http::HttpMethod;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// Path patterns are made of "/"-separated segments:
+//   literal    matches the same text
+//   {name}     matches one non-empty segment
+//   {name...}  matches the rest of the path, one or more segments, last segment only
+// When several patterns match, the one with a literal at the first differing
+// segment wins over a parameter, and a parameter wins over a catch-all.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    CatchAll(String),
+}
+
+impl Segment {
+    // precedence order used to sort routes, smaller goes first
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::CatchAll(_) => 2,
+        }
+    }
+
+    // patterns with the same shape match exactly the same paths
+    fn same_shape(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Literal(a), Segment::Literal(b)) => a == b,
+            (Segment::Param(_), Segment::Param(_)) => true,
+            (Segment::CatchAll(_), Segment::CatchAll(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    InvalidPattern(String, &'static str),
+    Conflict(String, String),
+    Duplicate(String, HttpMethod),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::InvalidPattern(pattern, reason) => {
+                write!(f, "invalid route {}: {}", pattern, reason)
+            }
+            RouteError::Conflict(a, b) => write!(f, "route {} conflicts with {}", a, b),
+            RouteError::Duplicate(pattern, method) => {
+                write!(f, "route {} {} registered twice", method.as_str(), pattern)
+            }
+        }
+    }
+}
+
+pub struct Route<T> {
+    pattern: String,
+    segments: Vec<Segment>,
+    pub methods: BTreeMap<HttpMethod, T>,
+}
+
+impl<T> Route<T> {
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut parts = path.strip_prefix('/')?.split('/');
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|p| !p.is_empty())?;
+                    params.insert(name.clone(), part.to_string());
+                }
+                Segment::CatchAll(name) => {
+                    let rest = parts.collect::<Vec<_>>().join("/");
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    params.insert(name.clone(), rest);
+                    return Some(params);
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+pub struct Router<T> {
+    // sorted by precedence
+    routes: Vec<Route<T>>,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub fn add(&mut self, pattern: &str, method: HttpMethod, value: T) -> Result<(), RouteError> {
+        let segments = Self::parse(pattern)?;
+        if let Some(route) = self.routes.iter_mut().find(|r| r.segments == segments) {
+            if route.methods.contains_key(&method) {
+                return Err(RouteError::Duplicate(pattern.to_string(), method));
+            }
+            route.methods.insert(method, value);
+            return Ok(());
+        }
+        if let Some(route) = self.routes.iter().find(|r| Self::same_shape(&r.segments, &segments)) {
+            return Err(RouteError::Conflict(pattern.to_string(), route.pattern.clone()));
+        }
+        let mut methods = BTreeMap::new();
+        methods.insert(method, value);
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            segments,
+            methods,
+        });
+        self.routes.sort_by(|a, b| {
+            let rank = |r: &Route<T>| r.segments.iter().map(Segment::rank).collect::<Vec<_>>();
+            rank(a).cmp(&rank(b)).then_with(|| a.segments.cmp(&b.segments))
+        });
+        Ok(())
+    }
+
+    // the most specific route matching the path and its extracted parameters
+    pub fn find(&self, path: &str) -> Option<(&Route<T>, HashMap<String, String>)> {
+        self.routes
+            .iter()
+            .find_map(|route| route.matches(path).map(|params| (route, params)))
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route<T>> {
+        self.routes.iter()
+    }
+
+    fn same_shape(a: &[Segment], b: &[Segment]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.same_shape(b))
+    }
+
+    fn parse(pattern: &str) -> Result<Vec<Segment>, RouteError> {
+        let invalid = |reason| RouteError::InvalidPattern(pattern.to_string(), reason);
+        let rest = pattern.strip_prefix('/').ok_or(invalid("must start with /"))?;
+        let parts = rest.split('/').collect::<Vec<_>>();
+        let mut segments = Vec::with_capacity(parts.len());
+        let mut names = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) => {
+                    let (name, catch_all) = match name.strip_suffix("...") {
+                        Some(name) => (name, true),
+                        None => (name, false),
+                    };
+                    if name.is_empty() {
+                        return Err(invalid("empty parameter name"));
+                    }
+                    if names.contains(&name) {
+                        return Err(invalid("repeated parameter name"));
+                    }
+                    names.push(name);
+                    if catch_all && i != parts.len() - 1 {
+                        return Err(invalid("catch-all parameter must be last"));
+                    }
+                    if catch_all {
+                        Segment::CatchAll(name.to_string())
+                    } else {
+                        Segment::Param(name.to_string())
+                    }
+                }
+                None if part.contains('{') || part.contains('}') => {
+                    return Err(invalid("parameters must span a whole segment"));
+                }
+                None => Segment::Literal(part.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(patterns: &[&'static str]) -> Router<&'static str> {
+        let mut router = Router::new();
+        for pattern in patterns {
+            router.add(pattern, HttpMethod::GET, *pattern).unwrap();
+        }
+        router
+    }
+
+    fn find(router: &Router<&'static str>, path: &str) -> Option<(&'static str, Vec<(String, String)>)> {
+        let (route, params) = router.find(path)?;
+        let mut params = params.into_iter().collect::<Vec<_>>();
+        params.sort();
+        Some((route.methods[&HttpMethod::GET], params))
+    }
+
+    fn p(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn params_and_catch_all_test() {
+        let router = router(&["/buckets/{bucket}", "/buckets/{bucket}/objects/{key...}"]);
+
+        assert_eq!(Some(("/buckets/{bucket}", vec![p("bucket", "b")])), find(&router, "/buckets/b"));
+        assert_eq!(
+            Some(("/buckets/{bucket}/objects/{key...}", vec![p("bucket", "b"), p("key", "x/y.txt")])),
+            find(&router, "/buckets/b/objects/x/y.txt")
+        );
+        assert_eq!(None, find(&router, "/buckets/b/objects"));
+        assert_eq!(None, find(&router, "/buckets/b/objects/"));
+        assert_eq!(None, find(&router, "/buckets/"));
+        assert_eq!(None, find(&router, "/buckets/b/other"));
+    }
+
+    #[test]
+    fn precedence_test() {
+        let router = router(&["/{a...}", "/buckets/{bucket}", "/buckets/stats", "/{a}/{b}"]);
+
+        assert_eq!("/buckets/stats", find(&router, "/buckets/stats").unwrap().0);
+        assert_eq!("/buckets/{bucket}", find(&router, "/buckets/b").unwrap().0);
+        assert_eq!("/{a}/{b}", find(&router, "/other/b").unwrap().0);
+        assert_eq!("/{a...}", find(&router, "/x/y/z").unwrap().0);
+    }
+
+    #[test]
+    fn conflicts_test() {
+        let mut router = router(&["/buckets/{bucket}"]);
+
+        assert_eq!(
+            Err(RouteError::Conflict("/buckets/{name}".to_string(), "/buckets/{bucket}".to_string())),
+            router.add("/buckets/{name}", HttpMethod::PUT, "")
+        );
+        assert_eq!(
+            Err(RouteError::Duplicate("/buckets/{bucket}".to_string(), HttpMethod::GET)),
+            router.add("/buckets/{bucket}", HttpMethod::GET, "")
+        );
+        assert!(router.add("/buckets/{bucket}", HttpMethod::PUT, "").is_ok());
+        assert!(matches!(
+            router.add("/{key...}/x", HttpMethod::GET, ""),
+            Err(RouteError::InvalidPattern(_, _))
+        ));
+        assert!(matches!(
+            router.add("/a{b}", HttpMethod::GET, ""),
+            Err(RouteError::InvalidPattern(_, _))
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::ptr;
+
+#[cfg(target_os = "linux")]
+const EINTR: i32 = 4;
+#[cfg(target_os = "linux")]
+const EAGAIN: i32 = 11;
+#[cfg(target_os = "linux")]
+const EINVAL: i32 = 22;
+#[cfg(target_os = "linux")]
+const ENOSYS: i32 = 38;
+#[cfg(target_os = "linux")]
+const EOPNOTSUPP: i32 = 95;
+// the most the kernel moves in one call
+#[cfg(target_os = "linux")]
+const MAX_CHUNK: u64 = 0x7fff_f000;
+
+// the BSDs and macOS have a sendfile of their own, with other arguments
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+}
+
+// Elsewhere the file goes through user space.
+#[cfg(not(target_os = "linux"))]
+pub fn send_file<S: AsRawFd>(file: &File, socket: &S, len: u64) -> io::Result<u64>
+where
+    for<'a> &'a S: Write,
+{
+    io::copy(&mut file.take(len), &mut &*socket)
+}
+
+// Sends `len` bytes of the file from its current position to the socket, without
+// copying them through user space. Where the kernel cannot do that for this file
+// they are copied instead. Returns how many bytes were sent, fewer if the file ended.
+#[cfg(target_os = "linux")]
+pub fn send_file<S: AsRawFd>(file: &File, socket: &S, len: u64) -> io::Result<u64>
+where
+    for<'a> &'a S: Write,
+{
+    let mut sent = 0;
+    while sent < len {
+        let count = (len - sent).min(MAX_CHUNK) as usize;
+        // a null offset reads from, and moves, the file position
+        let n = unsafe { sendfile(socket.as_raw_fd(), file.as_raw_fd(), ptr::null_mut(), count) };
+        if n > 0 {
+            sent += n as u64;
+            continue;
+        }
+        if n == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(EINTR) => continue,
+            Some(EINVAL | ENOSYS | EOPNOTSUPP) if sent == 0 => {
+                return io::copy(&mut file.take(len), &mut &*socket);
+            }
+            // the socket's write timeout passed
+            Some(EAGAIN) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection write timed out"));
+            }
+            _ => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn send(len: u64, file: File) -> (io::Result<u64>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let reader = thread::spawn(move || {
+            let mut data = Vec::new();
+            (&client).read_to_end(&mut data).unwrap();
+            data
+        });
+        let sent = send_file(&file, &server, len);
+        drop(server);
+        (sent, reader.join().unwrap())
+    }
+
+    #[test]
+    fn send_file_test() {
+        let path = std::env::temp_dir().join("lightio_send_file_test");
+        let data = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(100)).unwrap();
+        let (sent, received) = send(2 * 1024 * 1024, file);
+        assert_eq!(2 * 1024 * 1024, sent.unwrap());
+        assert!(received == data[100..100 + 2 * 1024 * 1024]);
+
+        // the file ends before the requested length
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::End(-10)).unwrap();
+        let (sent, received) = send(100, file);
+        assert_eq!(10, sent.unwrap());
+        assert!(received == data[data.len() - 10..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_unsupported_file_test() {
+        // sendfile needs a source it can map, a pipe is copied instead
+        let (mut reader, mut writer) = io::pipe().unwrap();
+        writer.write_all(b"through a pipe").unwrap();
+        drop(writer);
+        let file = File::from(std::os::fd::OwnedFd::from(reader.try_clone().unwrap()));
+        let (sent, received) = send(100, file);
+        assert_eq!(14, sent.unwrap());
+        assert_eq!(b"through a pipe".to_vec(), received);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -1,48 +1,504 @@
-use std::cell::RefCell;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod http2;
+mod listen;
+
+pub use listen::ListenAddr;
+
 use crate::http;
-use crate::thread_pool;
-use crate::http::{HttpMethod, HttpReq};
+use crate::thread_pool::{Dispatch, QueuePolicy, ThreadPool};
+use crate::http::{
+    Body, ChunkedWriter, HttpMethod, HttpReq, HttpResponse, HttpVersion, ParseLimits, RequestBody,
+    RequestHead,
+};
 use crate::http_handler::HttpHandler;
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader,Write};
-use std::net::Shutdown::Both;
-use std::net::{TcpListener, TcpStream};
-use std::rc::Rc;
-use std::sync::Arc;
+use crate::middleware::{ArcMiddleware, Middleware, Next, RequestId};
+use crate::router::Router;
+use crate::sendfile;
+use crate::tls::{TlsAcceptor, TlsConfig};
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(target_os = "linux")]
+use event_loop::EventLoop;
+use listen::{Listener, Stream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// unread request bodies up to this size are skipped to keep the connection open
+const MAX_DRAIN_SIZE: u64 = 1024 * 1024;
+// seconds a client should wait after a 503 before retrying
+const RETRY_AFTER: &str = "1";
+// the minimum body rate is only enforced after this long, to let TCP ramp up
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    // to receive the whole request head
+    header_read: Duration,
+    // between two reads of the request body
+    body_read: Duration,
+    // for each write of the response
+    write: Duration,
+    // waiting for the next request on a connection
+    idle: Duration,
+    // average body bytes per second, 0 disables the check
+    min_body_rate: u64,
+}
+
+impl Timeouts {
+    // how long the next body read may wait, None once the body came in too slowly
+    fn body_read_timeout(&self, started: Instant, read: u64) -> Option<Duration> {
+        let elapsed = started.elapsed();
+        let expected = self.min_body_rate as f64 * elapsed.as_secs_f64();
+        if elapsed > MIN_RATE_GRACE && (read as f64) < expected {
+            return None;
+        }
+        Some(self.body_read)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Duration::from_secs(30),
+            body_read: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+            idle: Duration::from_secs(15),
+            min_body_rate: 1024,
+        }
+    }
+}
+
+enum Phase {
+    Idle,
+    Head { deadline: Instant },
+    Body { started: Instant, read: u64 },
+}
+
+// Reads the connection within the timeouts of the current phase. A read that would
+// exceed them fails with TimedOut and sets the shared flag, so the server can answer
+// 408 even when a handler swallowed the error.
+struct ConnReader<'a> {
+    stream: &'a Stream,
+    timeouts: Timeouts,
+    phase: Phase,
+    timed_out: &'a Cell<bool>,
+}
+
+impl ConnReader<'_> {
+    fn timed_out(&self) -> io::Error {
+        self.timed_out.set(true);
+        io::Error::new(io::ErrorKind::TimedOut, "connection read timed out")
+    }
+}
+
+impl Read for ConnReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.phase {
+            Phase::Idle => self.timeouts.idle,
+            Phase::Head { deadline } => deadline.saturating_duration_since(Instant::now()),
+            Phase::Body { started, read } => match self.timeouts.body_read_timeout(started, read) {
+                Some(timeout) => timeout,
+                None => return Err(self.timed_out()),
+            },
+        };
+        if timeout.is_zero() {
+            return Err(self.timed_out());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut stream = self.stream;
+        match stream.read(buf) {
+            Ok(n) => {
+                if let Phase::Body { read, .. } = &mut self.phase {
+                    *read += n as u64;
+                }
+                Ok(n)
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                Err(self.timed_out())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Connections accepted and not closed yet, queued ones included. Shutdown uses it to
+// close the idle ones right away and the busy ones once the grace period is over.
+struct Connections {
+    open: Mutex<HashMap<u64, (Stream, bool)>>,
+    closed: Condvar,
+    next_id: AtomicU64,
+    stopping: AtomicBool,
+}
+
+impl Connections {
+    fn new() -> Self {
+        Self {
+            open: Mutex::new(HashMap::new()),
+            closed: Condvar::new(),
+            next_id: AtomicU64::new(0),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    fn register(self: &Arc<Self>, stream: &Stream, max: usize) -> Option<Registration> {
+        let mut open = self.open.lock().unwrap();
+        if open.len() >= max {
+            return None;
+        }
+        let stream = stream
+            .try_clone()
+            .map_err(|e| eprintln!("cannot track connection: {}", e))
+            .ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        open.insert(id, (stream, false));
+        Some(Registration {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let open = self.open.lock().unwrap();
+        for (stream, _) in open.values().filter(|(_, idle)| *idle) {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    // Waits for the open connections to finish until the deadline, then closes the rest.
+    fn drain(&self, deadline: Instant) {
+        let mut open = self.open.lock().unwrap();
+        while !open.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                println!("closing {} connections still open after shutdown timeout", open.len());
+                for (stream, _) in open.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            open = self.closed.wait_timeout(open, timeout).unwrap().0;
+        }
+    }
+}
+
+// an entry in Connections, removed when dropped
+struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Registration {
+    fn stopping(&self) -> bool {
+        self.connections.stopping.load(Ordering::SeqCst)
+    }
+
+    // Marks the connection as waiting for a request or not. Returns false if the
+    // server is stopping, in which case an idle connection has to be closed.
+    fn set_idle(&self, idle: bool) -> bool {
+        let mut open = self.connections.open.lock().unwrap();
+        if self.stopping() {
+            return false;
+        }
+        if let Some((_, state)) = open.get_mut(&self.id) {
+            *state = idle;
+        }
+        true
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+// A running server, returned by HttpServer::start. Dropping it leaves the server running.
+pub struct ServerHandle {
+    addrs: Vec<ListenAddr>,
+    connections: Arc<Connections>,
+    acceptors: Vec<thread::JoinHandle<()>>,
+    pool: Arc<ThreadPool>,
+    shutdown_timeout: Duration,
+    engine: Engine,
+}
+
+impl ServerHandle {
+    // the addresses listened on, with the ports picked for port 0
+    #[allow(dead_code)]
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.addrs
+    }
+
+    // Stops accepting and closes idle connections. Requests in flight get the shutdown
+    // timeout to finish, their connections are closed after. Returns once the workers exited.
+    pub fn shutdown(self) {
+        println!("shutting down");
+        let deadline = Instant::now() + self.shutdown_timeout;
+        self.connections.stop();
+        // blocked acceptors notice the stop with the next connection, the event loop
+        // notices it on its own
+        for addr in self.addrs.iter().filter(|_| self.engine == Engine::Threads) {
+            if let Err(e) = addr.connect() {
+                eprintln!("cannot wake up the acceptor on {}: {}", addr, e);
+            }
+        }
+        self.connections.drain(deadline);
+        for acceptor in self.acceptors {
+            acceptor.join().expect("acceptor panicked");
+        }
+        // the acceptors held the other references, dropping the last one waits for the workers
+        drop(self.pool);
+        println!("shutdown finished");
+    }
+}
+
+// Where responses go: the socket itself, or the event loop's queue for it.
+trait Output: Write + Clone {
+    // called once the last response on the connection was written
+    fn close(&self) -> io::Result<()>;
+
+    // the socket, if files can be sent to it directly
+    fn socket(&self) -> Option<&Stream> {
+        None
+    }
+}
+
+impl Output for &Stream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    // TLS has to encrypt what it sends
+    fn socket(&self) -> Option<&Stream> {
+        match self {
+            Stream::Tls(_) => None,
+            _ => Some(self),
+        }
+    }
+}
 
 type BoxHttpHandler = Box<dyn HttpHandler + Send + Sync>;
-type BoxHttpHandlerMap = HashMap<HttpMethod, HashMap<String, BoxHttpHandler>>;
+
+// the routes, and the global middlewares that run around routing so that they also
+// see the answers the server gives itself, like 404 and automatic OPTIONS
+struct BoxHttpHandlerMap {
+    router: Router<Endpoint>,
+    middlewares: Vec<ArcMiddleware>,
+}
+
+// a handler with the route middlewares that run around it
+struct Endpoint {
+    handler: BoxHttpHandler,
+    middlewares: Vec<ArcMiddleware>,
+}
+
+// ends the global chain by routing the request, path and method are not used
+struct Routing<'a>(&'a BoxHttpHandlerMap);
+
+impl HttpHandler for Routing<'_> {
+    fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+        HttpServer::find_endpoint(req, self.0)
+    }
+
+    fn path(&self) -> &str {
+        "*"
+    }
+
+    fn method(&self) -> HttpMethod {
+        HttpMethod::OPTIONS
+    }
+}
 
 pub struct HttpServer;
 
+// how connections are served
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // each connection keeps a pool thread from accept to close
+    Threads,
+    // one thread waits on all connections with epoll and hands complete request
+    // heads to the pool, so idle keep-alive connections cost no thread. Linux
+    // only, elsewhere the threads engine is used instead.
+    Epoll,
+}
+
 pub struct HttpServerConfig {
     port: u16,
+    listen_addrs: Vec<(ListenAddr, Option<TlsConfig>)>,
     handlers: Vec<BoxHttpHandler>,
     pool_size: usize,
+    queue_size: usize,
+    queue_policy: QueuePolicy,
+    max_connections: usize,
+    middlewares: Vec<ArcMiddleware>,
+    route_middlewares: Vec<(String, ArcMiddleware)>,
+    limits: ParseLimits,
+    timeouts: Timeouts,
+    shutdown_timeout: Duration,
+    engine: Engine,
 }
 
 impl HttpServerConfig {
     pub fn new() -> Self {
         Self {
             port: 8080,
+            listen_addrs: Vec::new(),
             handlers: Vec::new(),
             pool_size: 4,
+            queue_size: 128,
+            queue_policy: QueuePolicy::Reject,
+            max_connections: 1024,
+            middlewares: Vec::new(),
+            route_middlewares: Vec::new(),
+            limits: ParseLimits::default(),
+            timeouts: Timeouts::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            engine: Engine::Threads,
         }
     }
 
+    // time allowed to receive the request line and headers, then 408
+    #[allow(dead_code)]
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.header_read = timeout;
+        self
+    }
+
+    // time allowed between two reads of the request body, then 408
+    #[allow(dead_code)]
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.body_read = timeout;
+        self
+    }
+
+    // time allowed for each write of the response before the connection is dropped
+    #[allow(dead_code)]
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    // how long a connection waits for its next request before it is closed
+    #[allow(dead_code)]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    // slower request bodies are answered 408, 0 disables the check
+    #[allow(dead_code)]
+    pub fn min_body_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.timeouts.min_body_rate = bytes_per_sec;
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    // how long requests in flight may take to finish on shutdown
+    #[allow(dead_code)]
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    // longer request lines are answered 414
+    #[allow(dead_code)]
+    pub fn max_request_line(mut self, len: usize) -> Self {
+        self.limits.max_request_line = len;
+        self
+    }
+
+    // larger header sections are answered 431
+    #[allow(dead_code)]
+    pub fn max_header_size(mut self, len: usize) -> Self {
+        self.limits.max_header_size = len;
+        self
+    }
+
+    // more header fields are answered 431
+    #[allow(dead_code)]
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.limits.max_headers = count;
+        self
+    }
+
+    // runs around every request, in the order added, also those the server answers itself
+    pub fn middleware(mut self, middleware: impl Middleware + Send + Sync + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    // runs around the handlers registered with exactly this path pattern, after the global ones
+    #[allow(dead_code)]
+    pub fn route_middleware(
+        mut self,
+        path: &str,
+        middleware: impl Middleware + Send + Sync + 'static,
+    ) -> Self {
+        self.route_middlewares.push((path.to_string(), Arc::new(middleware)));
+        self
+    }
+
     #[allow(dead_code)]
     pub fn pool_size(mut self, size: usize) -> Self {
         self.pool_size = size;
         self
     }
 
+    // accepted connections waiting for a worker, more are handled by the queue policy
+    #[allow(dead_code)]
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.queue_size = size;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.queue_policy = policy;
+        self
+    }
+
+    // queued and served connections together, more are answered 503
+    #[allow(dead_code)]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    // the port on 127.0.0.1 when no listen address is given
     #[allow(dead_code)]
     pub fn port(mut self, port: u16) -> Self {
         self.port = port;
         self
     }
 
+    // Adds an address to listen on, all of them serve the same handlers. Port 0
+    // picks a free port, see ServerHandle::local_addrs.
+    pub fn listen(mut self, addr: ListenAddr) -> Self {
+        self.listen_addrs.push((addr, None));
+        self
+    }
+
+    // Adds an address to listen on with TLS, the threads engine only. Its connections
+    // are served like the plain ones.
+    pub fn listen_tls(mut self, addr: ListenAddr, tls: TlsConfig) -> Self {
+        self.listen_addrs.push((addr, Some(tls)));
+        self
+    }
+
     pub fn handlers(mut self, handlers: Vec<BoxHttpHandler>) -> Self {
         self.handlers = handlers;
         self
@@ -50,174 +506,574 @@ impl HttpServerConfig {
 }
 
 impl HttpServer {
-    pub fn start(config: HttpServerConfig) {
+    // Binds the listen addresses and accepts connections on new threads.
+    pub fn start(config: HttpServerConfig) -> ServerHandle {
         let HttpServerConfig {
             handlers,
             port,
+            mut listen_addrs,
             pool_size,
+            queue_size,
+            queue_policy,
+            max_connections,
+            middlewares,
+            route_middlewares,
+            limits,
+            timeouts,
+            shutdown_timeout,
+            engine,
         } = config;
 
-        let pool = thread_pool::ThreadPool::new(pool_size).expect("thread pool create error"); 
-        let handlers = Arc::new(Self::create_handler_map(handlers));
-        let listener =
-            TcpListener::bind(format!("127.0.0.1:{}", port)).expect("Failed to bind port");
-        println!("Listening on {}", port);
-        for stream in listener.incoming() {
+        let pool = ThreadPool::new(pool_size, queue_size, queue_policy).expect("thread pool create error");
+        let pool = Arc::new(pool);
+        let connections = Arc::new(Connections::new());
+        let handlers = Arc::new(Self::create_handler_map(handlers, middlewares, route_middlewares));
+        if listen_addrs.is_empty() {
+            listen_addrs.push((ListenAddr::Tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, port))), None));
+        }
+        let engine = match engine {
+            Engine::Epoll if !cfg!(target_os = "linux") => {
+                println!("epoll is only available on Linux, using the threads engine");
+                Engine::Threads
+            }
+            engine => engine,
+        };
+        if engine != Engine::Threads && listen_addrs.iter().any(|(_, tls)| tls.is_some()) {
+            panic!("TLS listeners need the threads engine");
+        }
+        let listeners = listen_addrs
+            .iter()
+            .map(|(addr, tls)| {
+                let acceptor = tls.as_ref().map(|tls| {
+                    TlsAcceptor::new(tls).unwrap_or_else(|e| panic!("cannot load TLS certificates for {}: {}", addr, e))
+                });
+                let listener = Listener::bind(addr).unwrap_or_else(|e| panic!("cannot listen on {}: {}", addr, e));
+                match acceptor {
+                    Some(acceptor) => Listener::Tls(Box::new(listener), acceptor),
+                    None => listener,
+                }
+            })
+            .collect::<Vec<_>>();
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr().expect("Failed to get local address"))
+            .collect::<Vec<_>>();
+        for (addr, listener) in addrs.iter().zip(&listeners) {
+            match listener {
+                Listener::Tls(..) => println!("Listening on {} with TLS", addr),
+                _ => println!("Listening on {}", addr),
+            }
+        }
+        let acceptors = match engine {
+            Engine::Threads => listeners
+                .into_iter()
+                .map(|listener| {
+                    let (pool, connections, handlers) =
+                        (Arc::clone(&pool), Arc::clone(&connections), Arc::clone(&handlers));
+                    thread::spawn(move || {
+                        Self::accept(listener, pool, connections, max_connections, move |stream, connection| {
+                            Self::dispatch(stream, connection, Arc::clone(&handlers), limits, timeouts)
+                        })
+                    })
+                })
+                .collect(),
+            #[cfg(not(target_os = "linux"))]
+            Engine::Epoll => unreachable!("replaced by the threads engine"),
+            #[cfg(target_os = "linux")]
+            Engine::Epoll => {
+                let connections = Arc::clone(&connections);
+                let event_loop = EventLoop::new(
+                    listeners,
+                    Arc::clone(&pool),
+                    connections,
+                    max_connections,
+                    handlers,
+                    limits,
+                    timeouts,
+                )
+                .expect("event loop create error");
+                vec![thread::spawn(move || event_loop.run())]
+            }
+        };
+        ServerHandle {
+            addrs,
+            connections,
+            acceptors,
+            pool,
+            shutdown_timeout,
+            engine,
+        }
+    }
+
+    // Hands connections to the pool until the server is stopping.
+    fn accept(
+        listener: Listener,
+        pool: Arc<ThreadPool>,
+        connections: Arc<Connections>,
+        max_connections: usize,
+        serve: impl Fn(Stream, Registration) + Clone + Send + 'static,
+    ) {
+        loop {
+            let stream = listener.accept();
+            if connections.stopping.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    let handler_map = Arc::clone(&handlers);
-                    pool.execute(move || Self::dispatch(stream, handler_map));
+                    let Some(connection) = connections.register(&stream, max_connections) else {
+                        println!("too many connections, shedding");
+                        Self::shed(stream);
+                        continue;
+                    };
+                    let serve = serve.clone();
+                    pool.submit(move |dispatch| match dispatch {
+                        Dispatch::Run => serve(stream, connection),
+                        Dispatch::Shed => {
+                            println!("connection queue full, shedding");
+                            Self::shed(stream);
+                        }
+                    });
                 }
                 Err(e) => eprintln!("Http request e: {}", e),
             }
         }
     }
 
-    #[allow(dead_code)]
-    pub fn start_on_thread(config: HttpServerConfig) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            Self::start(config);
-        })
-    }
-
-    fn create_handler_map(handlers: Vec<BoxHttpHandler>) -> BoxHttpHandlerMap {
-        let mut map: BoxHttpHandlerMap = HashMap::new();
-        for handler in handlers {
-            if !map.contains_key(&handler.method()) {
-                map.insert(handler.method(), HashMap::new());
+    fn create_handler_map(
+        handlers: Vec<BoxHttpHandler>,
+        middlewares: Vec<ArcMiddleware>,
+        route_middlewares: Vec<(String, ArcMiddleware)>,
+    ) -> BoxHttpHandlerMap {
+        for (path, _) in &route_middlewares {
+            if !handlers.iter().any(|handler| handler.path() == path) {
+                panic!("middleware registration error: no handler for {}", path);
             }
-
-            let path_hm = map.get_mut(&handler.method()).expect("No handler");
-            path_hm.insert(handler.path().to_string(), handler);
+        }
+        let mut router = Router::new();
+        for handler in handlers {
+            let path = handler.path().to_string();
+            let chain = route_middlewares
+                .iter()
+                .filter(|(route, _)| *route == path)
+                .map(|(_, middleware)| Arc::clone(middleware))
+                .collect();
+            let endpoint = Endpoint {
+                handler,
+                middlewares: chain,
+            };
+            router
+                .add(&path, endpoint.handler.method(), endpoint)
+                .unwrap_or_else(|e| panic!("handler registration error: {}", e));
         }
 
-        map
+        BoxHttpHandlerMap { router, middlewares }
     }
 
-    fn dispatch(mut stream: TcpStream, handlers: Arc<BoxHttpHandlerMap>) {
-        loop {
-            let rc_stream = Rc::new(RefCell::new(&stream));
-            let rc_stream = Rc::clone(&rc_stream);
-            let mut reader = BufReader::new(*rc_stream.borrow());
-            let mut start_line = String::new();
-            if let Err(e) = reader.read_line(&mut start_line) {
-                eprintln!("start line read error: {}", e);
-                Self::write_and_close(&mut stream, http::SERVER_ERROR.as_bytes());
-                break;
+    fn dispatch(
+        stream: Stream,
+        connection: Registration,
+        handlers: Arc<BoxHttpHandlerMap>,
+        limits: ParseLimits,
+        timeouts: Timeouts,
+    ) {
+        // queued before the server started stopping
+        if connection.stopping() {
+            Self::shed(stream);
+            return;
+        }
+        if let Err(e) = stream.set_write_timeout(Some(timeouts.write)) {
+            eprintln!("cannot set write timeout: {}", e);
+            return;
+        }
+        // the handshake gets as long as a request head would
+        if let Stream::Tls(tls) = &stream {
+            let handshake = stream.set_read_timeout(Some(timeouts.header_read)).and_then(|_| tls.handshake());
+            if let Err(e) = handshake {
+                println!("TLS handshake failed: {}", e);
+                return;
             }
-            if start_line.is_empty() {
+        }
+        let timed_out = Cell::new(false);
+        // one reader for the whole connection so pipelined requests stay buffered
+        let mut reader = BufReader::new(ConnReader {
+            stream: &stream,
+            timeouts,
+            phase: Phase::Idle,
+            timed_out: &timed_out,
+        });
+        if let Stream::Tls(tls) = &stream
+            && tls.alpn_protocol() == Some(b"h2")
+        {
+            http2::serve(&stream, &mut reader, &connection, &handlers, &limits, timeouts);
+            return;
+        }
+        while Self::serve_request(&stream, &mut reader, &connection, &handlers, &limits, &timed_out) {}
+    }
+
+    // Reads and answers one request, returns whether the connection stays open.
+    fn serve_request(
+        stream: &Stream,
+        reader: &mut BufReader<ConnReader>,
+        connection: &Registration,
+        handlers: &BoxHttpHandlerMap,
+        limits: &ParseLimits,
+        timed_out: &Cell<bool>,
+    ) -> bool {
+        reader.get_mut().phase = Phase::Idle;
+        // a pipelined request is already buffered, otherwise shutdown may close it meanwhile
+        if reader.buffer().is_empty() && !connection.set_idle(true) {
+            return false;
+        }
+        match reader.fill_buf() {
+            Ok([]) => {
                 println!("connection closed");
-                break;
+                return false;
             }
-            let start_line = http::parse_start_line(&start_line);
-            if start_line.is_none() {
-                println!("convert start line error");
-                Self::write_and_close(&mut stream, http::BAD_REQUEST.as_bytes());
-                break;
+            Ok(_) => {}
+            Err(e) => {
+                println!("closing idle connection: {}", e);
+                return false;
             }
+        }
+        if !connection.set_idle(false) {
+            println!("closing connection on shutdown");
+            return false;
+        }
+        // h2c with prior knowledge, the preface is not a valid HTTP/1 request
+        if reader.buffer().starts_with(b"PRI ") {
+            let timeouts = reader.get_ref().timeouts;
+            http2::serve(stream, reader, connection, handlers, limits, timeouts);
+            return false;
+        }
 
-            let Some((method, path)) = start_line else {
-                panic!(
-                    "method and path extracting panic. start line {:?}",
-                    start_line
-                )
-            };
+        let deadline = Instant::now() + reader.get_ref().timeouts.header_read;
+        reader.get_mut().phase = Phase::Head { deadline };
+        let head = match http::parse_request_head(reader, limits) {
+            Ok(Some(head)) => head,
+            Ok(None) => {
+                println!("connection closed");
+                return false;
+            }
+            Err(e) => {
+                println!("request parse error: {:?}", e);
+                let status = if timed_out.get() { Some(408) } else { e.status() };
+                if let Some(status) = status {
+                    Self::respond(stream, HttpResponse::new(status), false, HttpVersion::Http11);
+                }
+                return false;
+            }
+        };
+        reader.get_mut().phase = Phase::Body {
+            started: Instant::now(),
+            read: 0,
+        };
+        Self::serve_head(head, reader, stream, &connection.connections.stopping, handlers, limits, timed_out)
+    }
 
-            let headers = http::parse_headers(&mut reader);
-            if headers.is_none() {
-                println!("headers parsing error");
-                Self::write_and_close(&mut stream, http::BAD_REQUEST.as_bytes());
-                break;
+    // Reads the body of a request whose head was parsed and answers it, returns
+    // whether the connection stays open.
+    fn serve_head<O: Output>(
+        head: RequestHead,
+        reader: &mut dyn BufRead,
+        stream: O,
+        stopping: &AtomicBool,
+        handlers: &BoxHttpHandlerMap,
+        limits: &ParseLimits,
+        timed_out: &Cell<bool>,
+    ) -> bool {
+        let RequestHead {
+            method,
+            target: path,
+            version,
+            headers,
+        } = head;
+        let chunked = match headers.get("transfer-encoding") {
+            None => false,
+            Some(coding) if coding.trim().eq_ignore_ascii_case("chunked") => true,
+            Some(coding) => {
+                println!("unsupported transfer encoding: {}", coding.trim());
+                Self::respond(stream, HttpResponse::new(501), false, HttpVersion::Http11);
+                return false;
             }
-            let (path, query_params) = http::parse_query_params(path);
-            let mut request = HttpReq {
-                path,
-                method,
-                headers: headers.unwrap(),
-                body: reader,
-                query_params
-            };
+        };
+        let body = if chunked {
+            RequestBody::chunked(reader, limits)
+        } else {
+            match headers.get("content-length").map(|v| v.parse::<u64>()) {
+                None => RequestBody::new(reader, 0),
+                Some(Ok(len)) => RequestBody::new(reader, len),
+                Some(Err(_)) => {
+                    println!("content length value is not correct");
+                    Self::respond(stream, HttpResponse::new(400), false, HttpVersion::Http11);
+                    return false;
+                }
+            }
+        };
+        // a message with both framings may be read differently by a proxy in front
+        let ambiguous = chunked && headers.contains_key("content-length");
+        let mut output = stream.clone();
+        let body = match headers.get("expect").map(|value| value.trim()) {
+            // HTTP/1.0 clients do not wait for an interim response
+            Some(_) if version == HttpVersion::Http10 => body,
+            Some(value) if value.eq_ignore_ascii_case("100-continue") => {
+                body.expect_continue(&mut output)
+            }
+            Some(value) => {
+                println!("unsupported expectation: {}", value);
+                Self::respond(stream, HttpResponse::new(417), false, version);
+                return false;
+            }
+            None => body,
+        };
+        let (path, query_params) = http::parse_query_params(path);
+        let mut request = HttpReq {
+            path,
+            method,
+            version,
+            headers,
+            body,
+            query_params,
+            path_params: HashMap::new(),
+        };
 
-            let method_hm = handlers.get(&request.method);
-            if method_hm.is_none() {
-                println!("method not found warning. method: {:?}", request.method);
-                Self::write(&mut stream, http::NOT_FOUND.as_bytes());
-                break;
+        // a panicking handler fails its request only, the body is left in an unknown state
+        let routed = panic::catch_unwind(AssertUnwindSafe(|| Self::route(&mut request, handlers)));
+        let panicked = routed.is_err();
+        let mut response = routed.unwrap_or_else(|payload| {
+            eprintln!(
+                "handler panicked: {} {} {}: {}",
+                request.method.as_str(),
+                request.path,
+                request.headers.get(RequestId::HEADER).map_or("-", |id| id.as_str()),
+                panic_message(&*payload)
+            );
+            HttpResponse::new(500)
+        });
+        if timed_out.get() {
+            println!("request body timed out: {} {}", request.method.as_str(), request.path);
+            response = HttpResponse::new(408);
+        }
+        // a large unread body is cheaper to drop along with the connection, and one
+        // rejected before 100 Continue may or may not be sent by the client
+        let keep_alive = request.keep_alive()
+            && !panicked
+            && !stopping.load(Ordering::SeqCst)
+            && !ambiguous
+            && !timed_out.get()
+            && !request.body.awaiting_continue()
+            && request.body.remaining().is_some_and(|len| len <= MAX_DRAIN_SIZE);
+        if !Self::respond(stream, response, keep_alive, request.version) {
+            return false;
+        }
+        match request.body.drain() {
+            Ok(_) => true,
+            Err(e) => {
+                println!("cannot discard request body: {}", e);
+                false
             }
-            let method_hm = method_hm.expect("method extracting panic");
-            let path = &request.path;
-            let http_handler = method_hm.get(path);
-            if http_handler.is_none() {
+        }
+    }
+
+    fn route(request: &mut HttpReq, handlers: &BoxHttpHandlerMap) -> HttpResponse {
+        Next::new(&handlers.middlewares, &Routing(handlers)).run(request)
+    }
+
+    fn find_endpoint(request: &mut HttpReq, handlers: &BoxHttpHandlerMap) -> HttpResponse {
+        let routes = &handlers.router;
+        if request.method == HttpMethod::OPTIONS && request.path == "*" {
+            let methods = routes.routes().flat_map(|route| route.methods.keys());
+            return HttpResponse::new(204).header("Allow", &Self::allow_header(methods));
+        }
+
+        let Some((route, path_params)) = routes.find(&request.path) else {
+            let implemented = request.method == HttpMethod::OPTIONS
+                || routes.routes().any(|route| route.methods.contains_key(&request.method));
+            if implemented {
                 println!(
                     "handler get warning. path: {}, method: {:?}",
                     request.path, request.method
                 );
-                Self::write(&mut stream, http::NOT_FOUND.as_bytes());
-                break;
+                return HttpResponse::new(404);
             }
-            
-            let handler = http_handler.expect("handler is not found");
-            handler.handle_request(&mut request, rc_stream)
-        }
+            println!("method not implemented warning. method: {:?}", request.method);
+            return HttpResponse::new(501);
+        };
+        // parameters are matched encoded, so an escaped "/" stays inside its segment
+        request.path_params = path_params
+            .into_iter()
+            .map(|(name, value)| (name, http::percent_decode(&value, false)))
+            .collect();
+        let method_hm = &route.methods;
+        let Some(endpoint) = method_hm.get(&request.method) else {
+            let allow = Self::allow_header(method_hm.keys());
+            if request.method == HttpMethod::OPTIONS {
+                return HttpResponse::new(204).header("Allow", &allow);
+            }
+            println!(
+                "method not allowed warning. path: {}, method: {:?}",
+                request.path, request.method
+            );
+            return HttpResponse::new(405).header("Allow", &allow);
+        };
+
+        Next::new(&endpoint.middlewares, endpoint.handler.as_ref()).run(request)
     }
 
-    fn write(tcp_stream: &mut TcpStream, msg: &[u8]) {
-        if let Err(e) = tcp_stream.write(msg) {
-            eprintln!("Error writing to stream: {}, msg {:?}", e, msg);
+    // Answers 503 without reading the request. What already arrived is discarded first
+    // so that closing does not reset the connection before the client reads the answer.
+    fn shed(stream: Stream) {
+        // answering would take a handshake first
+        if let Stream::Tls(_) = stream {
+            let _ = stream.shutdown(Shutdown::Both);
             return;
         }
-        if let Err(e) = tcp_stream.flush() {
-            eprintln!("Error flushing stream: {}, msg {:?}", e, msg);
+        let response = HttpResponse::new(503).header("Retry-After", RETRY_AFTER);
+        if stream.set_nonblocking(true).is_ok() {
+            let mut buf = [0; 4096];
+            while matches!((&stream).read(&mut buf), Ok(n) if n > 0) {}
+        }
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+        Self::respond(&stream, response, false, HttpVersion::Http11);
+    }
+
+    // registered methods plus OPTIONS, which the server answers itself
+    fn allow_header<'a>(methods: impl Iterator<Item = &'a HttpMethod>) -> String {
+        let mut methods = methods.collect::<BTreeSet<_>>();
+        methods.insert(&HttpMethod::OPTIONS);
+        methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    // Serializes the response, returns whether the connection can serve another request.
+    fn respond<O: Output>(
+        mut stream: O,
+        response: HttpResponse,
+        mut keep_alive: bool,
+        version: HttpVersion,
+    ) -> bool {
+        let HttpResponse { status, headers, body } = response;
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, http::reason_phrase(status));
+        for (name, value) in &headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Date: {}\r\n", http::http_date(SystemTime::now())));
+        head.push_str(&format!("Server: {}\r\n", http::SERVER_NAME));
+        let len = body.len();
+        if status >= 200 && status != 204 && status != 304 {
+            match len {
+                Some(len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+                None if version == HttpVersion::Http11 => {
+                    head.push_str("Transfer-Encoding: chunked\r\n")
+                }
+                // HTTP/1.0 clients read an unframed body until the connection closes
+                None => keep_alive = false,
+            }
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {}\r\n\r\n", connection));
+
+        let mut writer = BufWriter::new(&mut stream);
+        let written = writer.write_all(head.as_bytes()).and_then(|_| {
+            let len = len.unwrap_or_default();
+            let copied = match body {
+                Body::Empty => 0,
+                Body::Bytes(bytes) => {
+                    writer.write_all(&bytes)?;
+                    len
+                }
+                Body::File(file, len) => {
+                    writer.flush()?;
+                    match writer.get_ref().socket() {
+                        Some(socket) => sendfile::send_file(&file, socket, len)?,
+                        None => io::copy(&mut file.take(len), &mut writer)?,
+                    }
+                }
+                Body::Reader(reader, len) => io::copy(&mut reader.take(len), &mut writer)?,
+                Body::Stream(mut reader) if version == HttpVersion::Http11 => {
+                    let mut chunked = ChunkedWriter::new(&mut writer);
+                    io::copy(&mut reader, &mut chunked)?;
+                    chunked.finish()?;
+                    0
+                }
+                Body::Stream(mut reader) => {
+                    io::copy(&mut reader, &mut writer)?;
+                    0
+                }
+            };
+            if copied < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("body ended after {} of {} bytes", copied, len),
+                ));
+            }
+            writer.flush()
+        });
+        if let Err(e) = written {
+            eprintln!("Error writing response {}: {}", status, e);
+            return false;
+        }
+        drop(writer);
+        if !keep_alive {
+            stream
+                .close()
+                .unwrap_or_else(|e| eprintln!("Error shutting down stream: {}", e));
         }
+        keep_alive
     }
+}
 
-    fn write_and_close(tcp_stream: &mut TcpStream, msg: &[u8]) {
-        Self::write(tcp_stream, msg);
-        tcp_stream
-            .shutdown(Both)
-            .unwrap_or_else(|e| eprintln!("Error shutting down stream: {}, msg {:?}", e, msg))
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::TcpStream;
     use std::time::Duration;
     use crate::http_client::*;
+    use std::io::BufRead;
     
     struct TestHandler;
     unsafe impl Sync for TestHandler {}
     unsafe impl Send for TestHandler {}
 
     impl HttpHandler for TestHandler {
-        fn handle_request(&self, req: &mut HttpReq, output: Rc<RefCell<&TcpStream>>) {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
             if req.headers.contains_key("content-length") {
                 let mut start_line = String::new();
                 req.body.read_line(&mut start_line).expect("read body");
                 if start_line == "helloworld\r\n" {
-                    output.borrow_mut().write_all(http::TEMPLATE_OK.replace("{}", "201").as_bytes()).unwrap();
+                    HttpResponse::new(201)
                 } else {
-                    output.borrow_mut().write_all(http::BAD_REQUEST.as_bytes()).unwrap();
+                    HttpResponse::new(400)
                 }
             } else if req.headers.contains_key("x-query") {
                 let query_params = &req.query_params;
                 let hello = query_params.get("hello");
                 let test = query_params.get("test");
-                if hello.is_none() || test.is_none() {
-                    output.borrow_mut().write_all(http::BAD_REQUEST.as_bytes()).unwrap();
-                } else {
-                    let world = hello.unwrap();
-                    let one = test.unwrap();
+                if let (Some(world), Some(one)) = (hello, test) {
                     if world == "world" && one == "1" {
-                        output.borrow_mut().write_all(http::TEMPLATE_OK.replace("{}", "205").as_bytes()).unwrap();
+                        HttpResponse::new(205)
                     } else {
-                        output.borrow_mut().write_all(http::BAD_REQUEST.as_bytes()).unwrap();
+                        HttpResponse::new(400)
                     }
+                } else {
+                    HttpResponse::new(400)
                 }
             } else {
-                output.borrow_mut().write_all(http::TEMPLATE_OK.replace("{}", "200").as_bytes()).unwrap();
+                HttpResponse::new(200)
             }
-            output.borrow_mut().shutdown(Both).unwrap(); 
         }
 
         fn path(&self) -> &str {
@@ -229,8 +1085,120 @@ mod tests {
         }
     }
 
+    struct ParamHandler;
+
+    impl HttpHandler for ParamHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            match (req.param("bucket"), req.param("key")) {
+                (Some(bucket), Some(key)) if bucket == "b" && key == "x/y.txt" => {
+                    HttpResponse::new(207).body(b"found".to_vec())
+                }
+                _ => HttpResponse::new(400),
+            }
+        }
+
+        fn path(&self) -> &str {
+            "/buckets/{bucket}/objects/{key...}"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::GET
+        }
+    }
+
+    // sends the request body back, chunked
+    struct EchoHandler;
+
+    impl HttpHandler for EchoHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            let mut body = Vec::new();
+            if req.body.read_to_end(&mut body).is_err() {
+                return HttpResponse::new(400);
+            }
+            let trailer = req.body.trailers().get("x-trailer").cloned().unwrap_or_default();
+            HttpResponse::new(200)
+                .header("x-trailer", trailer.trim())
+                .stream(io::Cursor::new(body))
+        }
+
+        fn path(&self) -> &str {
+            "/echo"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::POST
+        }
+    }
+
+    // serves the bytes of a file the Range header asks for
+    struct FileHandler(std::path::PathBuf);
+
+    impl HttpHandler for FileHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            let file = std::fs::File::open(&self.0).unwrap();
+            let len = file.metadata().unwrap().len();
+            match req.headers.get("range").map(|range| http::parse_range(range, len)) {
+                Some(http::ByteRange::Partial(start, end)) => {
+                    HttpResponse::new(206).file_range(file, start, end - start).unwrap()
+                }
+                _ => HttpResponse::new(200).file_range(file, 0, len).unwrap(),
+            }
+        }
+
+        fn path(&self) -> &str {
+            "/file"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::GET
+        }
+    }
+
+    // fails on every request, like an unwrap on a missing object would
+    struct PanicHandler;
+
+    impl HttpHandler for PanicHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            panic!("cannot handle {}", req.path)
+        }
+
+        fn path(&self) -> &str {
+            "/panic"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::GET
+        }
+    }
+
+    // rejects requests without the token before the handler runs
+    struct Auth;
+
+    impl Middleware for Auth {
+        fn handle(&self, req: &mut HttpReq, next: Next) -> HttpResponse {
+            match req.headers.get("authorization") {
+                Some(token) if token.trim() == "Bearer secret" => next.run(req),
+                _ => HttpResponse::new(401),
+            }
+        }
+    }
+
+    // records the order middlewares run in
+    struct Trace(&'static str);
+
+    impl Middleware for Trace {
+        fn handle(&self, req: &mut HttpReq, next: Next) -> HttpResponse {
+            let response = next.run(req);
+            let trace = match response.headers.iter().find(|(name, _)| name == "x-trace") {
+                Some((_, inner)) => format!("{},{}", self.0, inner),
+                None => self.0.to_string(),
+            };
+            response.header("x-trace", &trace)
+        }
+    }
+
     fn start_server(port: u16, handler: impl HttpHandler + Sync + Send + 'static) {
-        HttpServer::start_on_thread(
+        HttpServer::start(
             HttpServerConfig::new()
                 .port(port)
                 .handlers(vec![Box::new(handler)]),
@@ -258,7 +1226,590 @@ mod tests {
     #[test]
     fn send_unknown_method() {
         start_server(8081, TestHandler);
-        assert_eq!(404, send_req(8081, HttpMethod::POST, "hello").status());
+        assert_eq!(405, send_req(8081, HttpMethod::POST, "hello").status());
+    }
+
+    #[test]
+    fn send_unsupported_method() {
+        start_server(8086, TestHandler);
+        let response = send_raw(8086, "POST /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        assert!(response.contains("Allow: GET, OPTIONS\r\n"), "{}", response);
+
+        let response = send_raw(8086, "PUT /hoho HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+    }
+
+    #[test]
+    fn send_path_params() {
+        start_server(8088, ParamHandler);
+        assert_eq!(207, send_req(8088, HttpMethod::GET, "buckets/b/objects/x/y.txt").status());
+        assert_eq!(207, send_req(8088, HttpMethod::GET, "buckets/%62/objects/x%2Fy.txt").status());
+        assert_eq!(404, send_req(8088, HttpMethod::GET, "buckets/b/objects").status());
+    }
+
+    #[test]
+    fn send_response_headers() {
+        start_server(8089, ParamHandler);
+        let response = send_raw(
+            8089,
+            "GET /buckets/b/objects/x/y.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 207 UNKNOWN\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 5"), "{}", head);
+        assert!(head.contains("\r\nDate: "), "{}", head);
+        assert!(head.contains(&format!("\r\nServer: {}", http::SERVER_NAME)), "{}", head);
+        assert!(head.contains("\r\nConnection: close"), "{}", head);
+        assert_eq!("found", body);
+    }
+
+    #[test]
+    fn send_through_middlewares() {
+        HttpServer::start(
+            HttpServerConfig::new()
+                .port(8090)
+                .middleware(Trace("global"))
+                .route_middleware("/buckets/{bucket}/objects/{key...}", Auth)
+                .route_middleware("/buckets/{bucket}/objects/{key...}", Trace("route"))
+                .handlers(vec![Box::new(TestHandler), Box::new(ParamHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+
+        let response = send_raw(8090, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\r\nx-trace: global\r\n"), "{}", response);
+
+        let path = "/buckets/b/objects/x/y.txt";
+        let response = send_raw(8090, &format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path));
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert!(response.contains("\r\nx-trace: global\r\n"), "{}", response);
+
+        let response = send_raw(
+            8090,
+            &format!("GET {} HTTP/1.1\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n", path),
+        );
+        assert!(response.starts_with("HTTP/1.1 207"), "{}", response);
+        assert!(response.contains("\r\nx-trace: global,route\r\n"), "{}", response);
+
+        // answered by the server itself, without a handler
+        for (request, status) in [
+            ("GET /hoho", "404"),
+            ("POST /hello", "405"),
+            ("PATCH /hoho", "501"),
+            ("OPTIONS /hello", "204"),
+            ("OPTIONS *", "204"),
+        ] {
+            let response = send_raw(8090, &format!("{} HTTP/1.1\r\nConnection: close\r\n\r\n", request));
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}", response);
+            assert!(response.contains("\r\nx-trace: global\r\n"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn send_options() {
+        start_server(8087, TestHandler);
+        let response = send_raw(
+            8087,
+            "OPTIONS /hello HTTP/1.1\r\n\r\nOPTIONS * HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let responses = response.split("HTTP/1.1 ").skip(1).collect::<Vec<_>>();
+        assert_eq!(2, responses.len(), "{}", response);
+        for response in responses {
+            assert!(response.starts_with("204 NO CONTENT\r\n"), "{}", response);
+            assert!(response.contains("\r\nAllow: GET, OPTIONS\r\n"), "{}", response);
+            assert!(!response.contains("Content-Length"), "{}", response);
+        }
+    }
+
+    fn statuses(response: &str) -> Vec<&str> {
+        response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| &r[..3])
+            .collect()
+    }
+
+    #[test]
+    fn send_pipelined() {
+        start_server(8091, TestHandler);
+        // the first body is read in part, the second not at all, both must be skipped
+        let response = send_raw(
+            8091,
+            "GET /hello HTTP/1.1\r\nContent-Length: 17\r\n\r\nhelloworld\r\nEXTRA\
+             POST /hello HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+             GET /hoho HTTP/1.1\r\n\r\n\
+             GET /hello?hello=world&test=1 HTTP/1.1\r\nX-Query: 1\r\nConnection: close\r\n\r\n\
+             GET /hello HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(vec!["201", "405", "404", "205"], statuses(&response), "{}", response);
+        assert!(response.ends_with("Connection: close\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn send_chunked() {
+        start_server(8093, EchoHandler);
+        let response = send_raw(
+            8093,
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: done\r\n\r\n\
+             POST /echo HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi",
+        );
+        let (first, second) = response.split_at(response.rfind("HTTP/1.1 ").unwrap());
+        assert!(first.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", first);
+        assert!(first.contains("\r\nx-trailer: done\r\n"), "{}", first);
+        assert!(first.ends_with("\r\n\r\nb\r\nhello world\r\n0\r\n\r\n"), "{}", first);
+        // HTTP/1.0 cannot be sent chunks, the end of the body is the end of the connection
+        assert!(!second.contains("Transfer-Encoding"), "{}", second);
+        assert!(second.contains("\r\nConnection: close\r\n"), "{}", second);
+        assert!(second.ends_with("\r\n\r\nhi"), "{}", second);
+
+        let response = send_raw(8093, "POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+    }
+
+    #[test]
+    fn send_expect_continue() {
+        start_server(8094, EchoHandler);
+        let mut conn = TcpStream::connect("localhost:8094").unwrap();
+        conn.write_all(b"POST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+        let mut interim = [0; 25];
+        conn.read_exact(&mut interim).unwrap();
+        assert_eq!(b"HTTP/1.1 100 CONTINUE\r\n\r\n", &interim);
+        conn.write_all(b"hello").unwrap();
+        conn.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("5\r\nhello\r\n0\r\n\r\n"), "{}", response);
+
+        // rejected without asking for the body, then the connection is closed
+        let response = send_raw(
+            8094,
+            "PUT /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+
+        let response = send_raw(8094, "POST /echo HTTP/1.1\r\nExpect: coffee\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 417"), "{}", response);
+    }
+
+    #[test]
+    fn send_over_limits() {
+        HttpServer::start(
+            HttpServerConfig::new()
+                .port(8095)
+                .max_request_line(32)
+                .max_header_size(64)
+                .max_headers(2)
+                .handlers(vec![Box::new(TestHandler), Box::new(EchoHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+
+        let status = |request: &str| send_raw(8095, request)[..12].to_string();
+        assert_eq!("HTTP/1.1 200", status("GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert_eq!("HTTP/1.1 414", status(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32))));
+        assert_eq!("HTTP/1.1 431", status("GET /hello HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"));
+        assert_eq!("HTTP/1.1 431", status(&format!("GET /hello HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(64))));
+        assert_eq!("HTTP/1.1 505", status("GET /hello HTTP/2.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 400", status("GET /hello HTTP/1.1\r\nA : 1\r\n\r\n"));
+
+        // trailers are held to the same limits as the head
+        let chunked = |trailers: &str| {
+            format!(
+                "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n2\r\nhi\r\n0\r\n{}\r\n",
+                trailers
+            )
+        };
+        assert_eq!("HTTP/1.1 200", status(&chunked("X-Trailer: ok\r\n")));
+        assert_eq!("HTTP/1.1 400", status(&chunked("A: 1\r\nB: 2\r\nC: 3\r\n")));
+        assert_eq!("HTTP/1.1 400", status(&chunked(&format!("A: {}\r\n", "a".repeat(64)))));
+    }
+
+    #[test]
+    fn send_slowly() {
+        HttpServer::start(
+            HttpServerConfig::new()
+                .port(8096)
+                .header_read_timeout(Duration::from_millis(300))
+                .body_read_timeout(Duration::from_millis(300))
+                .idle_timeout(Duration::from_millis(300))
+                .handlers(vec![Box::new(EchoHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+        let slow = |parts: &[&str]| {
+            let mut conn = TcpStream::connect("localhost:8096").unwrap();
+            for part in parts {
+                conn.write_all(part.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            }
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        // a connection without requests is closed quietly
+        assert_eq!("", slow(&[]));
+        // the head trickles in over more than the header timeout
+        let response = slow(&["POST /echo HTTP/1.1\r\n", "Content-Length: 3\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+        // the body stalls
+        let response = slow(&["POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nab"]);
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        // each part in time, then idle after the response
+        let response = slow(&["POST /echo HTTP/1.1\r\n", "Content-Length: 3\r\n\r\n", "abc"]);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\r\nConnection: keep-alive\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn send_overloaded() {
+        let overloaded = |port: u16, config: HttpServerConfig| {
+            HttpServer::start(
+                config
+                    .port(port)
+                    .pool_size(1)
+                    .idle_timeout(Duration::from_millis(500))
+                    .handlers(vec![Box::new(TestHandler)]),
+            );
+            thread::sleep(Duration::from_millis(200));
+            // the first connection keeps the worker waiting, the second one queues
+            let idle: Vec<_> = (0..2)
+                .map(|_| {
+                    let conn = TcpStream::connect(format!("localhost:{}", port)).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                    conn
+                })
+                .collect();
+            let response = send_raw(port, "GET /hello HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+            drop(idle);
+            // once the idle connections time out there is room again
+            thread::sleep(Duration::from_millis(1200));
+            let response = send_raw(port, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        };
+
+        overloaded(8097, HttpServerConfig::new().queue_size(1).queue_policy(QueuePolicy::Reject));
+        overloaded(8098, HttpServerConfig::new().max_connections(2));
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let server = |port: u16| {
+            let handle = HttpServer::start(
+                HttpServerConfig::new()
+                    .port(port)
+                    .shutdown_timeout(Duration::from_millis(500))
+                    .handlers(vec![Box::new(EchoHandler)]),
+            );
+            thread::sleep(Duration::from_millis(200));
+            let connect = || TcpStream::connect(format!("localhost:{}", port)).unwrap();
+            // one keep-alive connection between requests, one in the middle of a body
+            let mut idle = connect();
+            idle.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 1\r\n\r\na").unwrap();
+            let mut busy = connect();
+            busy.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nab").unwrap();
+            thread::sleep(Duration::from_millis(100));
+            let shutdown = thread::spawn(move || handle.shutdown());
+            thread::sleep(Duration::from_millis(100));
+            let mut response = String::new();
+            idle.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(TcpStream::connect(format!("localhost:{}", port)).is_err());
+            (busy, shutdown)
+        };
+
+        // the request in flight finishes and its connection is closed after
+        let (mut busy, shutdown) = server(8099);
+        busy.write_all(b"c").unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        shutdown.join().unwrap();
+
+        // one that takes longer than the shutdown timeout is cut off
+        let (mut busy, shutdown) = server(8100);
+        let started = Instant::now();
+        shutdown.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let mut response = String::new();
+        let _ = busy.read_to_string(&mut response);
+        assert!(!response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[test]
+    fn send_to_panicking_handler() {
+        HttpServer::start(
+            HttpServerConfig::new()
+                .port(8101)
+                .pool_size(1)
+                .handlers(vec![Box::new(PanicHandler), Box::new(TestHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+        for _ in 0..2 {
+            let response = send_raw(8101, "GET /panic HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\n\r\n");
+            assert_eq!(vec!["500"], statuses(&response), "{}", response);
+            assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        }
+        // the only worker is still there
+        assert_eq!(200, send_req(8101, HttpMethod::GET, "hello").status());
+    }
+
+    #[test]
+    fn send_file_range() {
+        let path = std::env::temp_dir().join("lightio_send_file_range");
+        let data = (0..2 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        start_server(8107, FileHandler(path.clone()));
+
+        let get = |range: &str| {
+            let mut conn = TcpStream::connect("localhost:8107").unwrap();
+            let request = format!("GET /file HTTP/1.1\r\n{}Connection: close\r\n\r\n", range);
+            conn.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            conn.read_to_end(&mut response).unwrap();
+            let body = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (String::from_utf8_lossy(&response[..body]).to_string(), response[body..].to_vec())
+        };
+        let (head, body) = get("");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(body == data);
+        let (head, body) = get("Range: bytes=1000-1048575\r\n");
+        assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 1047576\r\n"), "{}", head);
+        assert!(body == data[1000..1048576]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_http_1_0() {
+        start_server(8092, TestHandler);
+        let response = send_raw(8092, "GET /hello HTTP/1.0\r\n\r\nGET /hello HTTP/1.0\r\n\r\n");
+        assert_eq!(vec!["200"], statuses(&response), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+
+        let response = send_raw(
+            8092,
+            "GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /hello HTTP/1.0\r\n\r\n",
+        );
+        assert_eq!(vec!["200", "200"], statuses(&response), "{}", response);
+        assert!(response.contains("\r\nConnection: keep-alive\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn listen_addrs_test() {
+        let socket = std::env::temp_dir().join("lightio_listen_addrs_test.sock");
+        for engine in [Engine::Threads, Engine::Epoll] {
+            let handle = HttpServer::start(
+                HttpServerConfig::new()
+                    .engine(engine)
+                    .listen("127.0.0.1:0".parse().unwrap())
+                    .listen("[::1]:0".parse().unwrap())
+                    .listen(ListenAddr::Unix(socket.clone()))
+                    .handlers(vec![Box::new(TestHandler)]),
+            );
+            let addrs = handle.local_addrs().to_vec();
+            assert_eq!(3, addrs.len());
+            for addr in &addrs {
+                if let ListenAddr::Tcp(addr) = addr {
+                    assert_ne!(0, addr.port());
+                }
+                let conn = addr.connect().unwrap();
+                (&conn).write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+                let mut response = String::new();
+                (&conn).read_to_string(&mut response).unwrap();
+                assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", addr, response);
+            }
+            assert_eq!(ListenAddr::Unix(socket.clone()), addrs[2]);
+            // a keep-alive connection the client closes does not hold up shutdown
+            let conn = addrs[2].connect().unwrap();
+            (&conn).write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                (&conn).read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            drop(conn);
+            thread::sleep(Duration::from_millis(100));
+            let started = Instant::now();
+            handle.shutdown();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            assert!(!socket.exists());
+            assert!(addrs.iter().all(|addr| addr.connect().is_err()));
+        }
+    }
+
+    #[test]
+    fn tls_listen_test() {
+        use crate::tls::test_client::{Client, ClientOptions};
+        use crate::tls::testdata_path;
+
+        let path = std::env::temp_dir().join("lightio_tls_listen");
+        let data = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let tls = TlsConfig::new(testdata_path("server.pem"), testdata_path("server.key"));
+        let handle = HttpServer::start(
+            HttpServerConfig::new()
+                .listen_tls("127.0.0.1:0".parse().unwrap(), tls)
+                .handlers(vec![Box::new(FileHandler(path.clone())), Box::new(EchoHandler)]),
+        );
+        let ListenAddr::Tcp(addr) = handle.local_addrs()[0] else { unreachable!() };
+        let mut client = Client::connect(TcpStream::connect(addr).unwrap(), &ClientOptions::default()).unwrap();
+        client
+            .send(b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /file HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        loop {
+            let record = client.receive().unwrap();
+            if record.is_empty() {
+                break;
+            }
+            response.extend_from_slice(&record);
+        }
+        assert!(response.ends_with(&data));
+        let response = String::from_utf8_lossy(&response);
+        assert_eq!(vec!["200", "200"], statuses(&response));
+        assert!(response.contains("\r\n\r\n5\r\nhello\r\n0\r\n"), "{}", &response[..200]);
+
+        // a client that does not speak TLS gets an alert and the connection closed
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(b"GET /file HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = conn.read_to_end(&mut response);
+        assert_eq!(&[21, 3, 3, 0, 2, 2][..], &response[..6]);
+        handle.shutdown();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_object_checksums() {
+        use crate::checksum::{ChecksumAlgorithm, base64_encode};
+        use crate::file_storage::{FileStorage, FileStorageConfig};
+        use crate::http_handler::{CreateObjectHandler, REST_OBJECT_PATH, ReadObjectHandler, Routed};
+
+        let root = std::env::temp_dir().join("lightio_object_checksums");
+        let _ = std::fs::remove_dir_all(&root);
+        let config = FileStorageConfig::new()
+            .data_path(root.to_string_lossy().to_string())
+            .cache(0, 0);
+        let storage: &'static FileStorage = Box::leak(Box::new(FileStorage::new(config).unwrap()));
+        storage.create_bucket(std::path::Path::new("b")).unwrap();
+        let handle = HttpServer::start(
+            HttpServerConfig::new()
+                .listen("127.0.0.1:0".parse().unwrap())
+                .handlers(vec![
+                    Box::new(Routed::new(REST_OBJECT_PATH, HttpMethod::PUT, CreateObjectHandler::new(storage))),
+                    Box::new(Routed::new(REST_OBJECT_PATH, HttpMethod::GET, ReadObjectHandler::new(storage))),
+                ]),
+        );
+        let ListenAddr::Tcp(addr) = handle.local_addrs()[0] else { unreachable!() };
+        let port = addr.port();
+        let digest = |alg: ChecksumAlgorithm, data: &str| {
+            let mut hasher = alg.hasher();
+            hasher.update(data.as_bytes());
+            base64_encode(&hasher.finish())
+        };
+        let put = |headers: &str, body: &str| {
+            send_raw(
+                port,
+                &format!(
+                    "PUT /buckets/b/objects/o HTTP/1.1\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+                    body.len(),
+                    headers,
+                    body
+                ),
+            )
+        };
+        // trailers follow a chunked body, announced or not
+        let put_chunked = |headers: &str, body: &str, trailers: &str| {
+            send_raw(
+                port,
+                &format!(
+                    "PUT /buckets/b/objects/o HTTP/1.1\r\nTransfer-Encoding: chunked\r\n{}Connection: close\r\n\r\n\
+                     {:x}\r\n{}\r\n0\r\n{}\r\n",
+                    headers,
+                    body.len(),
+                    body,
+                    trailers
+                ),
+            )
+        };
+        let get = || send_raw(port, "GET /buckets/b/objects/o HTTP/1.1\r\nConnection: close\r\n\r\n");
+
+        let md5 = digest(ChecksumAlgorithm::Md5, "hello");
+        let sha256 = digest(ChecksumAlgorithm::Sha256, "hello");
+        let response = put(
+            &format!("Content-MD5: {}\r\nx-lightio-checksum-sha256: {}\r\n", md5, sha256),
+            "hello",
+        );
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = get();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains(&format!("\r\ncontent-md5: {}\r\n", md5)), "{}", response);
+        assert!(response.contains(&format!("\r\nx-lightio-checksum-sha256: {}\r\n", sha256)), "{}", response);
+        assert!(response.contains("\r\nETag: \"5d41402abc4b2a76b9719d911017c592\"\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        // rejected uploads leave the previous object as it was
+        let crc32c = format!("x-lightio-checksum-crc32c: {}\r\n", digest(ChecksumAlgorithm::Crc32c, "bye"));
+        let rejected = [
+            (put(&format!("Content-MD5: {}\r\n", digest(ChecksumAlgorithm::Md5, "other")), "bye"), "BadDigest"),
+            (put("Content-MD5: Zm9v\r\n", "bye"), "InvalidDigest"),
+            (put("Content-MD5: not base64!\r\n", "bye"), "InvalidDigest"),
+            (
+                put_chunked("Trailer: x-lightio-checksum-crc32c\r\n", "bye", "x-lightio-checksum-crc32c: AAAAAA==\r\n"),
+                "BadDigest",
+            ),
+            (put_chunked("", "bye", &crc32c), "InvalidDigest"),
+        ];
+        for (response, error) in rejected {
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+            assert!(response.ends_with(error), "{}", response);
+            assert!(get().ends_with("\r\n\r\nhello"));
+        }
+
+        let response = put_chunked("Trailer: x-lightio-checksum-crc32c\r\n", "bye", &crc32c);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = get();
+        assert!(response.contains(&format!("\r\n{}", crc32c)), "{}", response);
+        let md5 = digest(ChecksumAlgorithm::Md5, "bye");
+        assert!(response.contains(&format!("\r\ncontent-md5: {}\r\n", md5)), "{}", response);
+        assert!(!response.contains("sha256"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nbye"), "{}", response);
+        handle.shutdown();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn send_raw(port: u16, request: &str) -> String {
+        let mut conn = TcpStream::connect(format!("localhost:{}", port)).unwrap();
+        conn.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn send_unrecognized_method() {
+        HttpServer::start(
+            HttpServerConfig::new()
+                .port(8085)
+                .pool_size(1)
+                .handlers(vec![Box::new(TestHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+
+        for _ in 0..3 {
+            let response = send_raw(8085, "BREW /hello HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+        }
+        // the only worker is still alive
+        assert_eq!(200, send_req(8085, HttpMethod::GET, "hello").status());
     }
 
     #[test]
@@ -274,7 +1825,6 @@ mod tests {
         let client = HttpClient::new();
         let req = client
             .get("http://localhost:8083/hello")
-            .header("content-length", "?")
             .body("helloworld\r\n");
         let response = req.send().unwrap();
 
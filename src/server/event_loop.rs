@@ -0,0 +1,1046 @@
+use super::listen::{Listener, Stream};
+use super::{BoxHttpHandlerMap, ConnReader, Connections, HttpServer, Output, Phase, RETRY_AFTER, Registration, Timeouts, http2};
+use crate::epoll::{self, Epoll, Event, EventFd};
+use crate::http::{self, HttpResponse, ParseLimits, RequestHead};
+use crate::thread_pool::{Dispatch, QueuePolicy, ThreadPool};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// bytes buffered in each direction before the producing side has to wait
+const PIPE_CAPACITY: usize = 256 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+// how often deadlines are checked
+const TICK: Duration = Duration::from_millis(100);
+// tokens of the waker and the listeners, listener i has LISTENERS - i, connections
+// use their registration id
+const WAKER: u64 = u64::MAX;
+const LISTENERS: u64 = u64::MAX - 1;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Lets workers tell the loop which connections have news.
+struct Waker {
+    event_fd: EventFd,
+    tokens: Mutex<Vec<u64>>,
+}
+
+impl Waker {
+    fn wake(&self, token: u64) {
+        let mut tokens = lock(&self.tokens);
+        if tokens.is_empty()
+            && let Err(e) = self.event_fd.notify()
+        {
+            eprintln!("cannot wake event loop: {}", e);
+        }
+        tokens.push(token);
+    }
+
+    fn take(&self) -> Vec<u64> {
+        if let Err(e) = self.event_fd.clear() {
+            eprintln!("cannot clear event loop waker: {}", e);
+        }
+        mem::take(&mut *lock(&self.tokens))
+    }
+}
+
+// Bytes between the loop and the worker serving a request, one pipe per direction.
+// The loop side never blocks, the worker side waits for data or room.
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+    waker: Arc<Waker>,
+    token: u64,
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    // the producing side is done
+    closed: bool,
+    // the consuming side is gone, or the socket failed
+    broken: bool,
+    // for responses, whether the connection stays open after them
+    keep_alive: bool,
+}
+
+// what a flush of the response pipe achieved
+struct Flushed {
+    written: usize,
+    // nothing is left to write for now
+    drained: bool,
+    // the worker is done and everything it produced was written
+    finished: bool,
+    keep_alive: bool,
+}
+
+impl Pipe {
+    fn new(waker: &Arc<Waker>, token: u64) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(PipeState::default()),
+            changed: Condvar::new(),
+            waker: Arc::clone(waker),
+            token,
+        })
+    }
+
+    // Adds request bytes, false once the worker stopped reading.
+    fn push(&self, bytes: &[u8]) -> bool {
+        let mut state = lock(&self.state);
+        if state.broken {
+            return false;
+        }
+        state.data.extend(bytes);
+        self.changed.notify_all();
+        true
+    }
+
+    fn is_full(&self) -> bool {
+        lock(&self.state).data.len() >= PIPE_CAPACITY
+    }
+
+    fn is_broken(&self) -> bool {
+        lock(&self.state).broken
+    }
+
+    fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+
+    fn close(&self) {
+        lock(&self.state).closed = true;
+        self.changed.notify_all();
+    }
+
+    fn fail(&self) {
+        lock(&self.state).broken = true;
+        self.changed.notify_all();
+    }
+
+    // request bytes the worker did not consume
+    fn take_leftover(&self) -> Vec<u8> {
+        mem::take(&mut lock(&self.state).data).into()
+    }
+
+    // Writes out what the worker produced so far, without blocking.
+    fn flush(&self, mut stream: &Stream) -> io::Result<Flushed> {
+        let mut state = lock(&self.state);
+        let was_full = state.data.len() >= PIPE_CAPACITY;
+        let mut written = 0;
+        while !state.data.is_empty() {
+            let (front, _) = state.data.as_slices();
+            match stream.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    state.data.drain(..n);
+                    written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if was_full && state.data.len() < PIPE_CAPACITY {
+            self.changed.notify_all();
+        }
+        let drained = state.data.is_empty();
+        Ok(Flushed {
+            written,
+            drained,
+            finished: drained && state.closed,
+            keep_alive: state.keep_alive,
+        })
+    }
+}
+
+// The request body as the worker sees it, within the body timeouts.
+struct PipeReader<'a> {
+    pipe: &'a Pipe,
+    timeouts: Timeouts,
+    started: Instant,
+    read: u64,
+    timed_out: &'a Cell<bool>,
+}
+
+impl PipeReader<'_> {
+    fn timed_out(&self) -> io::Error {
+        self.timed_out.set(true);
+        io::Error::new(io::ErrorKind::TimedOut, "connection read timed out")
+    }
+}
+
+impl Read for PipeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = lock(&self.pipe.state);
+        loop {
+            if !state.data.is_empty() {
+                let was_full = state.data.len() >= PIPE_CAPACITY;
+                let n = state.data.read(buf)?;
+                self.read += n as u64;
+                // the loop stopped reading the socket when the pipe filled up
+                if was_full {
+                    self.pipe.waker.wake(self.pipe.token);
+                }
+                return Ok(n);
+            }
+            if state.closed || state.broken {
+                return Ok(0);
+            }
+            let Some(timeout) = self.timeouts.body_read_timeout(self.started, self.read) else {
+                return Err(self.timed_out());
+            };
+            let (next, result) = self
+                .pipe
+                .changed
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner);
+            state = next;
+            if result.timed_out() && state.data.is_empty() && !state.closed && !state.broken {
+                return Err(self.timed_out());
+            }
+        }
+    }
+}
+
+// Where the worker writes the response, waiting at most the write timeout for room.
+#[derive(Clone)]
+struct PipeWriter {
+    pipe: Arc<Pipe>,
+    timeout: Duration,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = lock(&self.pipe.state);
+        while state.data.len() >= PIPE_CAPACITY && !state.broken {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection write timed out"));
+            }
+            state = self
+                .pipe
+                .changed
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        if state.broken {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let was_empty = state.data.is_empty();
+        let n = buf.len().min(PIPE_CAPACITY - state.data.len());
+        state.data.extend(&buf[..n]);
+        if was_empty {
+            self.pipe.waker.wake(self.pipe.token);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the loop closes the connection once the response went out
+impl Output for PipeWriter {
+    fn close(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the pipes of the request a worker is serving
+struct Exchange {
+    input: Arc<Pipe>,
+    output: Arc<Pipe>,
+}
+
+// Ends the exchange when the job returns, or panics: the unread request bytes go
+// back to the loop, and the response is marked complete.
+struct Finish {
+    input: Arc<Pipe>,
+    output: Arc<Pipe>,
+    leftover: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        {
+            let mut input = lock(&self.input.state);
+            let rest = mem::take(&mut input.data);
+            input.data = mem::take(&mut self.leftover).into();
+            input.data.extend(rest);
+            input.broken = true;
+        }
+        {
+            let mut output = lock(&self.output.state);
+            output.closed = true;
+            output.keep_alive = self.keep_alive;
+        }
+        self.output.waker.wake(self.output.token);
+    }
+}
+
+struct Conn {
+    stream: Stream,
+    registration: Registration,
+    // received and not handed to a worker yet
+    input: Vec<u8>,
+    exchange: Option<Exchange>,
+    // the client will not send more
+    eof: bool,
+    // the socket failed while a worker still uses the exchange
+    closing: bool,
+    idle: bool,
+    head_started: Option<Instant>,
+    // the response could not be written completely
+    writing: bool,
+    // closed, or answered 408, when reached
+    deadline: Option<Instant>,
+    interest: u32,
+}
+
+impl Conn {
+    // Reads what the socket has. While a worker serves a request the bytes go to
+    // its pipe, and reading pauses while the pipe is full.
+    fn receive(&mut self, head_limit: usize) -> io::Result<()> {
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            let pipe = self.exchange.as_ref().map(|exchange| &exchange.input);
+            let paused = match pipe {
+                Some(pipe) if !pipe.is_broken() => pipe.is_full(),
+                _ => self.input.len() > head_limit,
+            };
+            if self.eof || paused {
+                return Ok(());
+            }
+            match (&self.stream).read(&mut buf) {
+                Ok(0) => {
+                    self.eof = true;
+                    if let Some(pipe) = pipe {
+                        pipe.close();
+                    }
+                    return Ok(());
+                }
+                Ok(n) => {
+                    if !pipe.is_some_and(|pipe| pipe.push(&buf[..n])) {
+                        self.input.extend_from_slice(&buf[..n]);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reading(&self, head_limit: usize) -> bool {
+        if self.eof || self.closing {
+            return false;
+        }
+        match &self.exchange {
+            Some(exchange) if !exchange.input.is_broken() => !exchange.input.is_full(),
+            _ => self.input.len() <= head_limit,
+        }
+    }
+}
+
+// End of the request head in `input`: the empty line after the header fields. Empty
+// lines before the request line are skipped, as the parser does.
+fn head_end(input: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    let mut seen_line = false;
+    while let Some(pos) = input[start..].iter().position(|&b| b == b'\n') {
+        let line = &input[start..start + pos + 1];
+        start += pos + 1;
+        let empty = line == b"\n" || line == b"\r\n";
+        if empty && seen_line {
+            return Some(start);
+        }
+        seen_line |= !empty;
+    }
+    None
+}
+
+// Serves all connections from one thread. Sockets are non-blocking: request heads
+// are parsed here as they arrive, then a worker reads the body and produces the
+// response through pipes the loop feeds from and drains to the socket.
+pub struct EventLoop {
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    // emptied once the server is stopping
+    listeners: Vec<Listener>,
+    pool: Arc<ThreadPool>,
+    connections: Arc<Connections>,
+    max_connections: usize,
+    handlers: Arc<BoxHttpHandlerMap>,
+    limits: ParseLimits,
+    timeouts: Timeouts,
+    conns: HashMap<u64, Conn>,
+}
+
+impl EventLoop {
+    pub fn new(
+        listeners: Vec<Listener>,
+        pool: Arc<ThreadPool>,
+        connections: Arc<Connections>,
+        max_connections: usize,
+        handlers: Arc<BoxHttpHandlerMap>,
+        limits: ParseLimits,
+        timeouts: Timeouts,
+    ) -> io::Result<Self> {
+        // workers wait on the loop, so the loop must never wait on them
+        if pool.policy() == QueuePolicy::Block {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the epoll engine cannot block on a full worker queue",
+            ));
+        }
+        let epoll = Epoll::new()?;
+        let waker = Arc::new(Waker {
+            event_fd: EventFd::new()?,
+            tokens: Mutex::new(Vec::new()),
+        });
+        for (i, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            epoll.add(listener.as_raw_fd(), LISTENERS - i as u64, epoll::READABLE)?;
+        }
+        epoll.add(waker.event_fd.as_raw_fd(), WAKER, epoll::READABLE)?;
+        Ok(Self {
+            epoll,
+            waker,
+            listeners,
+            pool,
+            connections,
+            max_connections,
+            handlers,
+            limits,
+            timeouts,
+            conns: HashMap::new(),
+        })
+    }
+
+    // Runs until the server is stopping and its connections are closed.
+    pub fn run(mut self) {
+        let mut events = Vec::with_capacity(1024);
+        let mut swept = Instant::now();
+        loop {
+            if self.connections.stopping.load(Ordering::SeqCst) {
+                for listener in self.listeners.drain(..) {
+                    let _ = self.epoll.delete(listener.as_raw_fd());
+                }
+            }
+            if self.listeners.is_empty() && self.conns.is_empty() {
+                break;
+            }
+            if let Err(e) = self.epoll.wait(&mut events, TICK) {
+                eprintln!("event loop wait failed: {}", e);
+                break;
+            }
+            for event in &events {
+                match event.token() {
+                    WAKER => {
+                        for token in self.waker.take() {
+                            self.advance(token);
+                        }
+                    }
+                    token if token > LISTENERS - self.listeners.len() as u64 => {
+                        self.accept((LISTENERS - token) as usize)
+                    }
+                    token => self.ready(token, event),
+                }
+            }
+            if swept.elapsed() >= TICK {
+                self.sweep();
+                swept = Instant::now();
+            }
+        }
+    }
+
+    fn head_limit(&self) -> usize {
+        self.limits.max_request_line + self.limits.max_header_size
+    }
+
+    fn accept(&mut self, listener: usize) {
+        let mut accepted = Vec::new();
+        while let Some(listener) = self.listeners.get(listener) {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Http request e: {}", e);
+                    break;
+                }
+            };
+            if self.connections.stopping.load(Ordering::SeqCst) {
+                continue;
+            }
+            let Some(registration) = self.connections.register(&stream, self.max_connections) else {
+                println!("too many connections, shedding");
+                HttpServer::shed(stream);
+                continue;
+            };
+            let token = registration.id;
+            let added = stream
+                .set_nonblocking(true)
+                .and_then(|_| self.epoll.add(stream.as_raw_fd(), token, epoll::READABLE));
+            if let Err(e) = added {
+                eprintln!("cannot watch connection: {}", e);
+                continue;
+            }
+            self.conns.insert(
+                token,
+                Conn {
+                    stream,
+                    registration,
+                    input: Vec::new(),
+                    exchange: None,
+                    eof: false,
+                    closing: false,
+                    idle: false,
+                    head_started: None,
+                    writing: false,
+                    deadline: None,
+                    interest: epoll::READABLE,
+                },
+            );
+            accepted.push(token);
+        }
+        for token in accepted {
+            self.advance(token);
+        }
+    }
+
+    fn ready(&mut self, token: u64, event: &Event) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        if event.is(epoll::ERROR | epoll::HANGUP) {
+            Self::fail(&self.epoll, conn);
+        } else if event.is(epoll::READABLE)
+            && let Err(e) = conn.receive(self.limits.max_request_line + self.limits.max_header_size)
+        {
+            println!("closing connection: {}", e);
+            Self::fail(&self.epoll, conn);
+        }
+        self.advance(token);
+    }
+
+    // Stops watching a broken connection. A worker still using it sees its pipes
+    // fail, the connection is dropped when it is done.
+    fn fail(epoll: &Epoll, conn: &mut Conn) {
+        if !conn.closing {
+            let _ = epoll.delete(conn.stream.as_raw_fd());
+            conn.closing = true;
+        }
+        if let Some(exchange) = &conn.exchange {
+            exchange.input.fail();
+            exchange.output.fail();
+        }
+    }
+
+    fn advance(&mut self, token: u64) {
+        let Some(mut conn) = self.conns.remove(&token) else {
+            return;
+        };
+        if !self.step(&mut conn) {
+            self.close(conn);
+            return;
+        }
+        let mut interest = 0;
+        if conn.reading(self.head_limit()) {
+            interest |= epoll::READABLE;
+        }
+        if conn.writing {
+            interest |= epoll::WRITABLE;
+        }
+        if !conn.closing && interest != conn.interest {
+            if let Err(e) = self.epoll.modify(conn.stream.as_raw_fd(), token, interest) {
+                eprintln!("cannot watch connection: {}", e);
+                self.close(conn);
+                return;
+            }
+            conn.interest = interest;
+        }
+        self.conns.insert(token, conn);
+    }
+
+    // Moves the connection on as far as it goes, returns whether it stays open.
+    fn step(&self, conn: &mut Conn) -> bool {
+        loop {
+            if let Some(exchange) = &conn.exchange {
+                if conn.closing {
+                    // wait for the worker to let go of the pipes
+                    return !exchange.output.is_closed();
+                }
+                let flushed = match exchange.output.flush(&conn.stream) {
+                    Ok(flushed) => flushed,
+                    Err(e) => {
+                        println!("Error writing response: {}", e);
+                        Self::fail(&self.epoll, conn);
+                        continue;
+                    }
+                };
+                conn.writing = !flushed.drained;
+                if flushed.drained {
+                    conn.deadline = None;
+                } else if flushed.written > 0 || conn.deadline.is_none() {
+                    conn.deadline = Some(Instant::now() + self.timeouts.write);
+                }
+                if !flushed.finished {
+                    return true;
+                }
+                if !flushed.keep_alive {
+                    return false;
+                }
+                let mut input = exchange.input.take_leftover();
+                input.append(&mut conn.input);
+                conn.input = input;
+                conn.exchange = None;
+            }
+
+            // the socket failed between requests, a unix socket whose client is gone hangs up
+            if conn.closing {
+                return false;
+            }
+            if conn.input.is_empty() {
+                if conn.eof {
+                    println!("connection closed");
+                    return false;
+                }
+                if !conn.registration.set_idle(true) {
+                    return false;
+                }
+                if !conn.idle {
+                    conn.idle = true;
+                    conn.head_started = None;
+                    conn.deadline = Some(Instant::now() + self.timeouts.idle);
+                }
+                return true;
+            }
+            if !conn.registration.set_idle(false) {
+                println!("closing connection on shutdown");
+                return false;
+            }
+            conn.idle = false;
+            if conn.input.starts_with(b"PRI ") {
+                self.hand_off(conn);
+                return false;
+            }
+            let started = *conn.head_started.get_or_insert_with(Instant::now);
+            let Some(end) = head_end(&conn.input) else {
+                if conn.input.len() > self.head_limit() {
+                    // too long to be complete, the parser tells which limit it broke
+                    let status = match http::parse_request_head(&mut conn.input.as_slice(), &self.limits) {
+                        Err(e) => e.status().unwrap_or(431),
+                        Ok(_) => 431,
+                    };
+                    println!("request head too large");
+                    self.reply(conn, status);
+                    continue;
+                }
+                if conn.eof {
+                    println!("connection closed");
+                    return false;
+                }
+                conn.deadline = Some(started + self.timeouts.header_read);
+                return true;
+            };
+            let rest = conn.input.split_off(end);
+            let head = mem::replace(&mut conn.input, rest);
+            conn.head_started = None;
+            conn.deadline = None;
+            match http::parse_request_head(&mut head.as_slice(), &self.limits) {
+                Ok(Some(head)) => self.submit(conn, head),
+                Ok(None) => return false,
+                Err(e) => {
+                    println!("request parse error: {:?}", e);
+                    match e.status() {
+                        Some(status) => self.reply(conn, status),
+                        None => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    // Answers from the loop itself and closes the connection afterwards.
+    fn reply(&self, conn: &mut Conn, status: u16) {
+        let input = Pipe::new(&self.waker, conn.registration.id);
+        let output = Pipe::new(&self.waker, conn.registration.id);
+        let writer = PipeWriter {
+            pipe: Arc::clone(&output),
+            timeout: self.timeouts.write,
+        };
+        HttpServer::respond(writer, HttpResponse::new(status), false, http::HttpVersion::Http11);
+        input.fail();
+        output.close();
+        conn.input.clear();
+        conn.exchange = Some(Exchange { input, output });
+    }
+
+    // Hands the request to a worker, along with the body bytes that came with the head.
+    fn submit(&self, conn: &mut Conn, head: RequestHead) {
+        let token = conn.registration.id;
+        let input = Pipe::new(&self.waker, token);
+        let output = Pipe::new(&self.waker, token);
+        input.push(&mem::take(&mut conn.input));
+        if conn.eof {
+            input.close();
+        }
+        conn.exchange = Some(Exchange {
+            input: Arc::clone(&input),
+            output: Arc::clone(&output),
+        });
+
+        let handlers = Arc::clone(&self.handlers);
+        let connections = Arc::clone(&self.connections);
+        let timeouts = self.timeouts;
+        let limits = self.limits;
+        self.pool.submit(move |dispatch| {
+            let mut finish = Finish {
+                input: Arc::clone(&input),
+                output: Arc::clone(&output),
+                leftover: Vec::new(),
+                keep_alive: false,
+            };
+            let writer = PipeWriter {
+                pipe: output,
+                timeout: timeouts.write,
+            };
+            match dispatch {
+                Dispatch::Run => {
+                    let timed_out = Cell::new(false);
+                    let mut reader = BufReader::new(PipeReader {
+                        pipe: &input,
+                        timeouts,
+                        started: Instant::now(),
+                        read: 0,
+                        timed_out: &timed_out,
+                    });
+                    finish.keep_alive = HttpServer::serve_head(
+                        head,
+                        &mut reader,
+                        writer,
+                        &connections.stopping,
+                        &handlers,
+                        &limits,
+                        &timed_out,
+                    );
+                    finish.leftover = reader.buffer().to_vec();
+                }
+                Dispatch::Shed => {
+                    println!("connection queue full, shedding");
+                    let response = HttpResponse::new(503).header("Retry-After", RETRY_AFTER);
+                    HttpServer::respond(writer, response, false, head.version);
+                }
+            }
+        });
+    }
+
+    // An h2c connection multiplexes its requests, so it leaves the loop for a worker
+    // that serves it blocking until it closes.
+    fn hand_off(&self, conn: &mut Conn) {
+        let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        conn.closing = true;
+        let stream = match conn.stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("cannot hand off HTTP/2 connection: {}", e);
+                return;
+            }
+        };
+        let Some(registration) = self.connections.register(&stream, usize::MAX) else {
+            return;
+        };
+        let input = mem::take(&mut conn.input);
+        let handlers = Arc::clone(&self.handlers);
+        let limits = self.limits;
+        let timeouts = self.timeouts;
+        self.pool.submit(move |dispatch| match dispatch {
+            Dispatch::Run => {
+                let blocking = stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.set_write_timeout(Some(timeouts.write)));
+                if let Err(e) = blocking {
+                    eprintln!("cannot hand off HTTP/2 connection: {}", e);
+                    return;
+                }
+                let timed_out = Cell::new(false);
+                let conn_reader = ConnReader {
+                    stream: &stream,
+                    timeouts,
+                    phase: Phase::Idle,
+                    timed_out: &timed_out,
+                };
+                let mut reader = BufReader::new(io::Cursor::new(input).chain(conn_reader));
+                http2::serve(&stream, &mut reader, &registration, &handlers, &limits, timeouts);
+            }
+            Dispatch::Shed => println!("connection queue full, shedding"),
+        });
+    }
+
+    fn close(&mut self, conn: Conn) {
+        if !conn.closing {
+            let _ = self.epoll.delete(conn.stream.as_raw_fd());
+            let _ = conn.stream.shutdown(Shutdown::Write);
+        }
+    }
+
+    // Closes idle connections and answers 408 to heads that take too long. A
+    // response the client does not read fails the connection.
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| conn.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in expired {
+            let Some(conn) = self.conns.get_mut(&token) else {
+                continue;
+            };
+            conn.deadline = None;
+            if conn.exchange.is_some() {
+                println!("closing connection: response write timed out");
+                Self::fail(&self.epoll, conn);
+            } else if conn.idle {
+                println!("closing idle connection");
+                conn.eof = true;
+                conn.input.clear();
+            } else {
+                println!("request parse error: header read timed out");
+                let mut conn = self.conns.remove(&token).unwrap();
+                self.reply(&mut conn, 408);
+                self.conns.insert(token, conn);
+            }
+            self.advance(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Engine, HttpServerConfig};
+    use super::*;
+    use crate::http::{HttpMethod, HttpReq};
+    use crate::http_handler::HttpHandler;
+    use std::net::TcpStream;
+    use std::thread;
+
+    // sends the request body back along with its length
+    struct EchoHandler;
+
+    impl HttpHandler for EchoHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            let mut body = Vec::new();
+            if req.body.read_to_end(&mut body).is_err() {
+                return HttpResponse::new(400);
+            }
+            HttpResponse::new(200)
+                .header("x-length", &body.len().to_string())
+                .stream(io::Cursor::new(body))
+        }
+
+        fn path(&self) -> &str {
+            "/echo"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::POST
+        }
+    }
+
+    struct HelloHandler;
+
+    impl HttpHandler for HelloHandler {
+        fn handle_request(&self, _req: &mut HttpReq) -> HttpResponse {
+            HttpResponse::new(200).body(b"hello".to_vec())
+        }
+
+        fn path(&self) -> &str {
+            "/hello"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::GET
+        }
+    }
+
+    fn start_server(port: u16, config: HttpServerConfig) {
+        HttpServer::start(
+            config
+                .port(port)
+                .engine(Engine::Epoll)
+                .handlers(vec![Box::new(EchoHandler), Box::new(HelloHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    fn connect(port: u16) -> TcpStream {
+        TcpStream::connect(format!("localhost:{}", port)).unwrap()
+    }
+
+    fn send_raw(port: u16, request: &[u8]) -> Vec<u8> {
+        let mut conn = connect(port);
+        conn.write_all(request).unwrap();
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn statuses(response: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(response)
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r[..3].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn head_end_test() {
+        assert_eq!(None, head_end(b"GET / HTTP/1.1\r\nHost: a\r\n"));
+        assert_eq!(Some(18), head_end(b"GET / HTTP/1.1\r\n\r\nbody"));
+        assert_eq!(Some(20), head_end(b"\r\nGET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(Some(16), head_end(b"GET / HTTP/1.1\n\n"));
+        assert_eq!(None, head_end(b"\r\n\r\n"));
+    }
+
+    #[test]
+    fn epoll_pipelined_test() {
+        start_server(8102, HttpServerConfig::new());
+        let response = send_raw(
+            8102,
+            b"GET /hello HTTP/1.1\r\n\r\nPOST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+              POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n0\r\n\r\n\
+              GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(vec!["200", "200", "200", "404"], statuses(&response));
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("\r\n\r\nhello"), "{}", response);
+        assert!(response.contains("\r\nx-length: 3\r\n"), "{}", response);
+        assert!(response.contains("\r\nx-length: 2\r\n"), "{}", response);
+        assert_eq!(vec!["400"], statuses(&send_raw(8102, b"GET /hello HTTP/1.1\r\nA : 1\r\n\r\n")));
+    }
+
+    #[test]
+    fn epoll_large_body_test() {
+        start_server(8103, HttpServerConfig::new().pool_size(1));
+        // several times the pipe capacity each way, so both sides have to wait
+        let body = (0..3 * PIPE_CAPACITY as u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut conn = connect(8103);
+        let writer = {
+            let mut conn = conn.try_clone().unwrap();
+            let mut request = format!(
+                "POST /echo HTTP/1.1\r\nContent-Length: {}\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            request.extend(&body);
+            thread::spawn(move || conn.write_all(&request).unwrap())
+        };
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).unwrap();
+        writer.join().unwrap();
+        let text = String::from_utf8_lossy(&response[..200]);
+        assert!(text.starts_with("HTTP/1.1 100 CONTINUE\r\n\r\nHTTP/1.1 200"), "{}", text);
+        assert!(text.contains(&format!("x-length: {}", body.len())), "{}", text);
+        // the echo comes back chunked
+        let head = response.windows(12).position(|w| w == b"HTTP/1.1 200").unwrap();
+        let head_end = head + response[head..].windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let mut chunked = &response[head_end + 2..];
+        let mut echoed = Vec::new();
+        while let Some(start) = chunked.windows(2).position(|w| w == b"\r\n") {
+            chunked = &chunked[start + 2..];
+            let Some(end) = chunked.windows(2).position(|w| w == b"\r\n") else {
+                break;
+            };
+            let len = usize::from_str_radix(std::str::from_utf8(&chunked[..end]).unwrap(), 16);
+            let Ok(len) = len else {
+                break;
+            };
+            echoed.extend(&chunked[end + 2..end + 2 + len]);
+            chunked = &chunked[end + 2 + len..];
+        }
+        assert_eq!(body.len(), echoed.len());
+        assert!(body == echoed);
+    }
+
+    #[test]
+    fn epoll_many_idle_test() {
+        start_server(8104, HttpServerConfig::new().pool_size(2));
+        // far more keep-alive connections than workers, each used in turn
+        let mut conns = (0..200).map(|_| connect(8104)).collect::<Vec<_>>();
+        for _ in 0..2 {
+            for conn in &mut conns {
+                conn.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+                let mut response = [0; 512];
+                let n = conn.read(&mut response).unwrap();
+                let response = String::from_utf8_lossy(&response[..n]);
+                assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+                assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+            }
+        }
+    }
+
+    #[test]
+    fn epoll_timeouts_test() {
+        start_server(
+            8105,
+            HttpServerConfig::new()
+                .header_read_timeout(Duration::from_millis(300))
+                .body_read_timeout(Duration::from_millis(300))
+                .idle_timeout(Duration::from_millis(300)),
+        );
+        // an idle connection is closed quietly, a slow head is answered 408
+        assert!(send_raw(8105, b"").is_empty());
+        let mut conn = connect(8105);
+        conn.write_all(b"GET /hello HTTP/1.1\r\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+        // the worker times out a stalled body
+        let mut conn = connect(8105);
+        conn.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nab").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    }
+
+    #[test]
+    fn epoll_shutdown_test() {
+        let handle = HttpServer::start(
+            HttpServerConfig::new()
+                .port(8106)
+                .engine(Engine::Epoll)
+                .handlers(vec![Box::new(EchoHandler)]),
+        );
+        thread::sleep(Duration::from_millis(200));
+        let mut idle = connect(8106);
+        idle.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 1\r\n\r\na").unwrap();
+        let mut busy = connect(8106);
+        busy.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nab").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let shutdown = thread::spawn(move || handle.shutdown());
+        let mut response = String::new();
+        idle.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        busy.write_all(b"c").unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        shutdown.join().unwrap();
+    }
+}
@@ -0,0 +1,1249 @@
+// HTTP/2 (RFC 9113) for the threads engine. The connection's thread reads frames
+// and request streams are served by worker threads of the connection, started as
+// needed up to the stream limit, so a slow response does not hold up the others.
+// Responses share the connection through a lock, frame by frame, within the flow
+// control windows the client grants.
+
+use super::listen::Stream;
+use super::{BoxHttpHandlerMap, HttpServer, Registration, Timeouts, panic_message};
+use crate::hpack::{self, Field};
+use crate::http::{self, Body, Headers, HttpMethod, HttpReq, HttpResponse, HttpVersion, ParseLimits, RequestBody};
+use crate::middleware::RequestId;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Read, Write};
+use std::net::Shutdown;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, mpsc};
+use std::thread::{self, Scope};
+use std::time::SystemTime;
+
+// what a client sends first, no HTTP/1 request starts like it
+pub(super) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// frame types
+const DATA: u8 = 0;
+const HEADERS: u8 = 1;
+const PRIORITY: u8 = 2;
+const RST_STREAM: u8 = 3;
+const SETTINGS: u8 = 4;
+const PUSH_PROMISE: u8 = 5;
+const PING: u8 = 6;
+const GOAWAY: u8 = 7;
+const WINDOW_UPDATE: u8 = 8;
+const CONTINUATION: u8 = 9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// error codes
+const NO_ERROR: u32 = 0;
+const PROTOCOL_ERROR: u32 = 1;
+const INTERNAL_ERROR: u32 = 2;
+const FLOW_CONTROL_ERROR: u32 = 3;
+const STREAM_CLOSED: u32 = 5;
+const FRAME_SIZE_ERROR: u32 = 6;
+const REFUSED_STREAM: u32 = 7;
+const CANCEL: u32 = 8;
+const COMPRESSION_ERROR: u32 = 9;
+const ENHANCE_YOUR_CALM: u32 = 11;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 5;
+
+// streams served at once on a connection, and so its most worker threads
+const MAX_CONCURRENT_STREAMS: usize = 16;
+// frame and window sizes both sides start with, this side keeps them
+const DEFAULT_MAX_FRAME: usize = 16384;
+const DEFAULT_WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+// :method, :scheme, :authority and :path
+const PSEUDO_HEADERS: usize = 4;
+
+// fields that only mean something to HTTP/1 connections
+const CONNECTION_FIELDS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+enum Error {
+    // the client broke the protocol, GOAWAY tells it with this code
+    Protocol(u32, &'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+fn put_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn u32_at(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("4 bytes"))
+}
+
+// the payload without the padding a PADDED frame carries
+fn unpadded(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&padding, rest) = frame.payload.split_first().ok_or(Error::Protocol(FRAME_SIZE_ERROR, "padded frame is empty"))?;
+    rest.len()
+        .checked_sub(padding as usize)
+        .map(|len| &rest[..len])
+        .ok_or(Error::Protocol(PROTOCOL_ERROR, "padding longer than the frame"))
+}
+
+// a stream as both sides see it
+struct StreamState {
+    // what this side may still send, and what the client may
+    send_window: i64,
+    receive_window: i64,
+    // DATA payloads the handler has not read yet
+    data: VecDeque<Vec<u8>>,
+    // the client finished sending
+    end: bool,
+    // either side reset it
+    reset: bool,
+}
+
+struct State {
+    streams: HashMap<u32, StreamState>,
+    send_window: i64,
+    // the client's settings
+    initial_window: i64,
+    max_frame: usize,
+    // no new streams are served, GOAWAY was sent or received
+    going_away: bool,
+    // the connection is done, nothing more goes out
+    closed: bool,
+}
+
+// What the connection's thread and the stream threads share.
+struct Shared<'a> {
+    stream: &'a Stream,
+    // a header block and its CONTINUATION frames go out together
+    writer: Mutex<()>,
+    state: Mutex<State>,
+    changed: Condvar,
+    timeouts: Timeouts,
+    stopping: &'a AtomicBool,
+}
+
+impl Shared<'_> {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn send(&self, frames: &[u8]) -> io::Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut stream = self.stream;
+        stream.write_all(frames).inspect_err(|_| {
+            self.lock().closed = true;
+            self.changed.notify_all();
+        })
+    }
+
+    fn send_frame(&self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(9 + payload.len());
+        put_frame(&mut out, kind, flags, stream_id, payload);
+        self.send(&out)
+    }
+
+    fn window_update(&self, stream_id: u32, increment: usize) -> io::Result<()> {
+        self.send_frame(WINDOW_UPDATE, 0, stream_id, &(increment as u32).to_be_bytes())
+    }
+
+    // ends a stream early, its thread notices on its next read or write
+    fn reset(&self, stream_id: u32, code: u32) -> io::Result<()> {
+        if let Some(stream) = self.lock().streams.get_mut(&stream_id) {
+            stream.reset = true;
+        }
+        self.changed.notify_all();
+        self.send_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes())
+    }
+
+    // The next DATA payload of the stream, None once the client finished sending.
+    // Taking it from the queue makes room for the client to send more.
+    fn next_data(&self, stream_id: u32) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.lock();
+        loop {
+            let closed = state.closed;
+            let stream = state.streams.get_mut(&stream_id).expect("stream served");
+            if stream.reset || closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset"));
+            }
+            if let Some(data) = stream.data.pop_front() {
+                let more = !stream.end && !data.is_empty();
+                if more {
+                    stream.receive_window += data.len() as i64;
+                }
+                drop(state);
+                if more {
+                    self.window_update(stream_id, data.len())?;
+                }
+                return Ok(Some(data));
+            }
+            if stream.end {
+                return Ok(None);
+            }
+            let (guard, wait) = self.changed.wait_timeout(state, self.timeouts.body_read).unwrap();
+            state = guard;
+            if wait.timed_out() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request body timed out"));
+            }
+        }
+    }
+
+    // Takes up to `want` bytes of the send windows, waiting for the client to grant some.
+    fn reserve(&self, stream_id: u32, want: usize) -> io::Result<usize> {
+        let mut state = self.lock();
+        loop {
+            let (closed, connection_window, max_frame) = (state.closed, state.send_window, state.max_frame);
+            let stream = state.streams.get_mut(&stream_id).expect("stream served");
+            if stream.reset || closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset"));
+            }
+            let n = connection_window.min(stream.send_window).min(want.min(max_frame) as i64);
+            if n > 0 {
+                stream.send_window -= n;
+                state.send_window -= n;
+                return Ok(n as usize);
+            }
+            let (guard, wait) = self.changed.wait_timeout(state, self.timeouts.write).unwrap();
+            state = guard;
+            if wait.timed_out() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "flow control window stayed closed"));
+            }
+        }
+    }
+
+    // Forgets a served stream. A client still sending the request body is told to stop.
+    fn finish(&self, stream_id: u32) {
+        let mut state = self.lock();
+        let stream = state.streams.remove(&stream_id).expect("stream served");
+        // the connection's thread waits for a frame, or for the last stream to go
+        let last = state.streams.is_empty() && (state.going_away || self.stopping.load(Ordering::SeqCst));
+        let closed = state.closed;
+        drop(state);
+        if !stream.end && !stream.reset && !closed {
+            let _ = self.send_frame(RST_STREAM, 0, stream_id, &NO_ERROR.to_be_bytes());
+        }
+        if last {
+            let _ = self.stream.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+// The request body of a stream, read as the client sends it.
+struct StreamBody<'a> {
+    shared: &'a Shared<'a>,
+    stream_id: u32,
+    data: Vec<u8>,
+    consumed: usize,
+    timed_out: &'a Cell<bool>,
+}
+
+impl Read for StreamBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StreamBody<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.consumed == self.data.len() {
+            match self.shared.next_data(self.stream_id) {
+                Ok(Some(data)) => (self.data, self.consumed) = (data, 0),
+                Ok(None) => break,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        self.timed_out.set(true);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(&self.data[self.consumed..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt;
+    }
+}
+
+// Serves an HTTP/2 connection until it closes. The reader holds what was read of
+// it so far, the preface included.
+pub(super) fn serve(
+    stream: &Stream,
+    reader: &mut dyn BufRead,
+    connection: &Registration,
+    handlers: &BoxHttpHandlerMap,
+    limits: &ParseLimits,
+    timeouts: Timeouts,
+) {
+    let shared = Shared {
+        stream,
+        writer: Mutex::new(()),
+        state: Mutex::new(State {
+            streams: HashMap::new(),
+            send_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame: DEFAULT_MAX_FRAME,
+            going_away: false,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+        timeouts,
+        stopping: &connection.connections.stopping,
+    };
+    let (jobs, queue) = mpsc::channel();
+    let queue = Mutex::new(queue);
+    let idle_workers = AtomicUsize::new(0);
+    // frames are written whole, and small ones such as WINDOW_UPDATE must not wait
+    // for the client's acknowledgements
+    if let Err(e) = stream.set_nodelay(true) {
+        eprintln!("cannot set TCP_NODELAY: {}", e);
+    }
+    thread::scope(|scope| {
+        let mut conn = Connection {
+            shared: &shared,
+            scope,
+            handlers,
+            limits,
+            decoder: hpack::Decoder::new(),
+            received: Vec::new(),
+            header_block: None,
+            last_stream_id: 0,
+            idle: false,
+            jobs,
+            queue: &queue,
+            idle_workers: &idle_workers,
+            workers: 0,
+        };
+        let (code, reason) = match conn.run(reader, connection) {
+            Ok(()) => (NO_ERROR, None),
+            Err(Error::Protocol(code, message)) => (code, Some(message.to_string())),
+            Err(Error::Io(e)) => (NO_ERROR, Some(e.to_string())),
+        };
+        if let Some(reason) = reason {
+            println!("closing HTTP/2 connection: {}", reason);
+        }
+        let mut goaway = conn.last_stream_id.to_be_bytes().to_vec();
+        goaway.extend_from_slice(&code.to_be_bytes());
+        let _ = shared.send_frame(GOAWAY, 0, 0, &goaway);
+        // streams still served fail their next read or write, and the workers
+        // stop once the connection's end of the queue is gone
+        shared.lock().closed = true;
+        shared.changed.notify_all();
+        drop(conn);
+    });
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+// The connection's thread: reads frames, hands out the data, starts streams.
+struct Connection<'scope, 'env> {
+    shared: &'env Shared<'env>,
+    scope: &'scope Scope<'scope, 'env>,
+    handlers: &'env BoxHttpHandlerMap,
+    limits: &'env ParseLimits,
+    decoder: hpack::Decoder,
+    // read bytes that do not make a whole frame yet
+    received: Vec<u8>,
+    // a header block waiting for CONTINUATION frames: stream, flags and fragments
+    header_block: Option<(u32, u8, Vec<u8>)>,
+    last_stream_id: u32,
+    idle: bool,
+    // streams for the workers, which wait on the queue between them
+    jobs: mpsc::Sender<Job>,
+    queue: &'env Mutex<mpsc::Receiver<Job>>,
+    idle_workers: &'env AtomicUsize,
+    workers: usize,
+}
+
+impl<'scope, 'env> Connection<'scope, 'env> {
+    // Reads more of the connection, false once the client closed it. Timeouts keep
+    // what was read.
+    fn receive(&mut self, reader: &mut dyn BufRead) -> io::Result<bool> {
+        let available = reader.fill_buf()?;
+        let n = available.len();
+        self.received.extend_from_slice(available);
+        reader.consume(n);
+        Ok(n > 0)
+    }
+
+    fn read_frame(&mut self, reader: &mut dyn BufRead) -> Result<Option<Frame>, Error> {
+        loop {
+            if self.received.len() >= 9 {
+                let len = u32_at(&[&[0][..], &self.received[..3]].concat()) as usize;
+                if len > DEFAULT_MAX_FRAME {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "frame larger than allowed"));
+                }
+                if self.received.len() >= 9 + len {
+                    let rest = self.received.split_off(9 + len);
+                    let frame = std::mem::replace(&mut self.received, rest);
+                    return Ok(Some(Frame {
+                        kind: frame[3],
+                        flags: frame[4],
+                        stream_id: u32_at(&frame[5..]) & 0x7fff_ffff,
+                        payload: frame[9..].to_vec(),
+                    }));
+                }
+            }
+            if !self.receive(reader)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn run(&mut self, reader: &mut dyn BufRead, connection: &Registration) -> Result<(), Error> {
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        self.shared.send_frame(SETTINGS, 0, 0, &settings)?;
+        while self.received.len() < PREFACE.len() {
+            if !self.receive(reader)? {
+                return Ok(());
+            }
+        }
+        if !self.received.starts_with(PREFACE) {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "invalid connection preface"));
+        }
+        self.received.drain(..PREFACE.len());
+
+        loop {
+            let (idle, going_away) = {
+                let state = self.shared.lock();
+                (state.streams.is_empty(), state.going_away)
+            };
+            if idle && going_away && self.header_block.is_none() {
+                return Ok(());
+            }
+            // an idle connection is closed on shutdown, a busy one gets GOAWAY
+            if idle != self.idle {
+                self.idle = idle;
+                if !connection.set_idle(idle) {
+                    self.shared.lock().going_away = true;
+                    continue;
+                }
+            }
+            if self.shared.stopping.load(Ordering::SeqCst) {
+                self.shared.lock().going_away = true;
+            }
+            let frame = match self.read_frame(reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                // responses may take a while with nothing coming in
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut && !idle => continue,
+                Err(e) => return Err(e),
+            };
+            self.handle(frame)?;
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), Error> {
+        if let Some((stream_id, _, _)) = &self.header_block
+            && (frame.kind != CONTINUATION || frame.stream_id != *stream_id)
+        {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "header block interrupted"));
+        }
+        let on_stream = frame.stream_id != 0;
+        match frame.kind {
+            DATA if on_stream => self.on_data(frame),
+            HEADERS if on_stream => self.on_headers(frame),
+            CONTINUATION if self.header_block.is_some() => {
+                let (stream_id, flags, mut block) = self.header_block.take().expect("header block");
+                block.extend_from_slice(&frame.payload);
+                self.on_header_fragment(stream_id, flags | frame.flags & END_HEADERS, block)
+            }
+            PRIORITY if on_stream => match frame.payload.len() {
+                5 => Ok(()),
+                _ => Ok(self.shared.reset(frame.stream_id, FRAME_SIZE_ERROR)?),
+            },
+            RST_STREAM if on_stream => {
+                if frame.payload.len() != 4 {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "RST_STREAM of the wrong size"));
+                }
+                if let Some(stream) = self.shared.lock().streams.get_mut(&frame.stream_id) {
+                    stream.reset = true;
+                }
+                self.shared.changed.notify_all();
+                Ok(())
+            }
+            SETTINGS if !on_stream => self.on_settings(frame),
+            PING if !on_stream => {
+                if frame.payload.len() != 8 {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "PING of the wrong size"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.shared.send_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            GOAWAY if !on_stream => {
+                self.shared.lock().going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(frame),
+            DATA | HEADERS | CONTINUATION | PRIORITY | RST_STREAM | SETTINGS | PUSH_PROMISE | PING | GOAWAY => {
+                Err(Error::Protocol(PROTOCOL_ERROR, "unexpected frame"))
+            }
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Error> {
+        let data = unpadded(&frame)?;
+        let len = frame.payload.len();
+        let mut state = self.shared.lock();
+        let Some(stream) = state.streams.get_mut(&frame.stream_id) else {
+            drop(state);
+            if frame.stream_id > self.last_stream_id {
+                return Err(Error::Protocol(PROTOCOL_ERROR, "DATA on an idle stream"));
+            }
+            // the stream was served or reset, its data still counts for the connection
+            if len > 0 {
+                self.shared.window_update(0, len)?;
+            }
+            return Ok(());
+        };
+        let error = if stream.end {
+            Some(STREAM_CLOSED)
+        } else if stream.receive_window < len as i64 {
+            Some(FLOW_CONTROL_ERROR)
+        } else {
+            None
+        };
+        if error.is_none() && !stream.reset {
+            stream.receive_window -= len as i64;
+            if !data.is_empty() {
+                stream.data.push_back(data.to_vec());
+            }
+            stream.end = frame.flags & END_STREAM != 0;
+        }
+        // padding is never read, it is given back right away
+        let padding = len - data.len();
+        drop(state);
+        self.shared.changed.notify_all();
+        // the connection window is given back right away, the streams' ones limit what is buffered
+        if len > 0 {
+            self.shared.window_update(0, len)?;
+        }
+        if let Some(code) = error {
+            self.shared.reset(frame.stream_id, code)?;
+        } else if padding > 0 && frame.flags & END_STREAM == 0 {
+            self.shared.window_update(frame.stream_id, padding)?;
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Error> {
+        let mut fragment = unpadded(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            fragment = fragment.get(5..).ok_or(Error::Protocol(FRAME_SIZE_ERROR, "HEADERS too short"))?;
+        }
+        self.on_header_fragment(frame.stream_id, frame.flags, fragment.to_vec())
+    }
+
+    // collects a header block until END_HEADERS, then starts or ends the stream
+    fn on_header_fragment(&mut self, stream_id: u32, flags: u8, block: Vec<u8>) -> Result<(), Error> {
+        if block.len() > self.limits.max_header_size {
+            return Err(Error::Protocol(ENHANCE_YOUR_CALM, "header block too large"));
+        }
+        if flags & END_HEADERS == 0 {
+            self.header_block = Some((stream_id, flags, block));
+            return Ok(());
+        }
+        // decoded even when unused, the table has to follow what the client encoded
+        // the pseudo-header fields carry what the request line would
+        let limits = hpack::Limits {
+            size: self.limits.max_request_line + self.limits.max_header_size,
+            fields: self.limits.max_headers + PSEUDO_HEADERS,
+        };
+        let fields = self.decoder.decode(&block, limits).map_err(|e| match e {
+            hpack::Error::Malformed(e) => Error::Protocol(COMPRESSION_ERROR, e),
+            hpack::Error::TooLarge(e) => Error::Protocol(ENHANCE_YOUR_CALM, e),
+        })?;
+        let end = flags & END_STREAM != 0;
+        let mut state = self.shared.lock();
+        if let Some(stream) = state.streams.get_mut(&stream_id) {
+            // trailers, which are not passed on
+            if !end || stream.end {
+                drop(state);
+                return Ok(self.shared.reset(stream_id, PROTOCOL_ERROR)?);
+            }
+            stream.end = true;
+            drop(state);
+            self.shared.changed.notify_all();
+            return Ok(());
+        }
+        if stream_id <= self.last_stream_id {
+            // a stream already served or reset
+            return Ok(());
+        }
+        if stream_id.is_multiple_of(2) {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "client stream ids are odd"));
+        }
+        self.last_stream_id = stream_id;
+        if state.going_away || state.streams.len() >= MAX_CONCURRENT_STREAMS {
+            drop(state);
+            return Ok(self.shared.reset(stream_id, REFUSED_STREAM)?);
+        }
+        let stream = StreamState {
+            send_window: state.initial_window,
+            receive_window: DEFAULT_WINDOW,
+            data: VecDeque::new(),
+            end,
+            reset: false,
+        };
+        state.streams.insert(stream_id, stream);
+        drop(state);
+        // an idle worker or a new one takes the stream. With all workers started,
+        // one has finished its stream and is on its way back to the queue.
+        let claimed = self.idle_workers.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| idle.checked_sub(1));
+        if claimed.is_err() && self.workers < MAX_CONCURRENT_STREAMS {
+            let (shared, handlers, limits) = (self.shared, self.handlers, self.limits);
+            let (queue, idle_workers) = (self.queue, self.idle_workers);
+            let spawned = thread::Builder::new()
+                .spawn_scoped(self.scope, move || work(shared, handlers, limits, queue, idle_workers));
+            match spawned {
+                Ok(_) => self.workers += 1,
+                Err(e) if self.workers == 0 => {
+                    eprintln!("cannot start HTTP/2 stream thread: {}", e);
+                    self.shared.reset(stream_id, REFUSED_STREAM)?;
+                    self.shared.finish(stream_id);
+                    return Ok(());
+                }
+                Err(e) => eprintln!("cannot start HTTP/2 stream thread: {}", e),
+            }
+        }
+        self.jobs.send((stream_id, fields)).expect("workers wait as long as the connection");
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.flags & ACK != 0 {
+            return match frame.payload.len() {
+                0 => Ok(()),
+                _ => Err(Error::Protocol(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with a payload")),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "SETTINGS of the wrong size"));
+        }
+        let mut state = self.shared.lock();
+        for setting in frame.payload.chunks(6) {
+            let (id, value) = (u16::from_be_bytes([setting[0], setting[1]]), u32_at(&setting[2..]) as i64);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Error::Protocol(PROTOCOL_ERROR, "invalid ENABLE_PUSH")),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value > MAX_WINDOW {
+                        return Err(Error::Protocol(FLOW_CONTROL_ERROR, "initial window too large"));
+                    }
+                    let delta = value - state.initial_window;
+                    state.initial_window = value;
+                    for stream in state.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(Error::Protocol(FLOW_CONTROL_ERROR, "stream window too large"));
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME as i64..1 << 24).contains(&value) {
+                        return Err(Error::Protocol(PROTOCOL_ERROR, "invalid MAX_FRAME_SIZE"));
+                    }
+                    state.max_frame = value as usize;
+                }
+                // the encoder uses no table, and this side sends no pushes
+                _ => {}
+            }
+        }
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(self.shared.send_frame(SETTINGS, ACK, 0, &[])?)
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "WINDOW_UPDATE of the wrong size"));
+        }
+        let increment = (u32_at(&frame.payload) & 0x7fff_ffff) as i64;
+        let mut state = self.shared.lock();
+        let window = match frame.stream_id {
+            0 => &mut state.send_window,
+            stream_id => match state.streams.get_mut(&stream_id) {
+                Some(stream) => &mut stream.send_window,
+                None => return Ok(()),
+            },
+        };
+        *window += increment;
+        let invalid = match (increment, *window > MAX_WINDOW) {
+            (0, _) => Some(PROTOCOL_ERROR),
+            (_, true) => Some(FLOW_CONTROL_ERROR),
+            _ => None,
+        };
+        drop(state);
+        self.shared.changed.notify_all();
+        match (invalid, frame.stream_id) {
+            (None, _) => Ok(()),
+            (Some(code), 0) => Err(Error::Protocol(code, "invalid WINDOW_UPDATE")),
+            (Some(code), stream_id) => Ok(self.shared.reset(stream_id, code)?),
+        }
+    }
+}
+
+// the request's fields as the HTTP/1 side would have them, None if malformed
+fn request_head(fields: Vec<Field>) -> Option<(String, String, Headers)> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers = Headers::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return None;
+        };
+        let Some(pseudo) = name.strip_prefix(':') else {
+            if name.bytes().any(|b| b.is_ascii_uppercase())
+                || CONNECTION_FIELDS.contains(&name.as_str())
+                || (name == "te" && value != "trailers")
+            {
+                return None;
+            }
+            headers.append(&name, &value);
+            continue;
+        };
+        let field = match pseudo {
+            "method" => &mut method,
+            "path" => &mut path,
+            "scheme" => &mut scheme,
+            "authority" => &mut authority,
+            _ => return None,
+        };
+        // pseudo-header fields come first, once each
+        if field.is_some() || headers.len() > 0 {
+            return None;
+        }
+        *field = Some(value);
+    }
+    if let Some(authority) = authority
+        && !headers.contains_key("host")
+    {
+        headers.insert("host", &authority);
+    }
+    match (method, path, scheme) {
+        (Some(method), Some(path), Some(_)) if !path.is_empty() => Some((method, path, headers)),
+        _ => None,
+    }
+}
+
+// A stream and its request's fields.
+type Job = (u32, Vec<Field>);
+
+// A worker thread of the connection: serves streams until the connection's
+// thread is done.
+fn work(
+    shared: &Shared,
+    handlers: &BoxHttpHandlerMap,
+    limits: &ParseLimits,
+    queue: &Mutex<mpsc::Receiver<Job>>,
+    idle_workers: &AtomicUsize,
+) {
+    loop {
+        let job = queue.lock().unwrap().recv();
+        let Ok((stream_id, fields)) = job else {
+            return;
+        };
+        serve_stream(shared, handlers, limits, stream_id, fields);
+        idle_workers.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// Runs the handler for one stream and sends its response.
+fn serve_stream(shared: &Shared, handlers: &BoxHttpHandlerMap, limits: &ParseLimits, stream_id: u32, fields: Vec<Field>) {
+    let Some((method, path, headers)) = request_head(fields) else {
+        println!("malformed HTTP/2 request on stream {}", stream_id);
+        let _ = shared.reset(stream_id, PROTOCOL_ERROR);
+        shared.finish(stream_id);
+        return;
+    };
+    let response = match method.parse::<HttpMethod>() {
+        _ if headers.len() > limits.max_headers => {
+            println!("too many headers: {}", headers.len());
+            HttpResponse::new(431)
+        }
+        Ok(method) => {
+            let timed_out = Cell::new(false);
+            let mut body = StreamBody {
+                shared,
+                stream_id,
+                data: Vec::new(),
+                consumed: 0,
+                timed_out: &timed_out,
+            };
+            let body = match headers.get("content-length").map(|len| len.trim().parse::<u64>()) {
+                None => RequestBody::to_end(&mut body),
+                Some(Ok(len)) => RequestBody::new(&mut body, len),
+                Some(Err(_)) => {
+                    println!("content length value is not correct");
+                    let _ = shared.reset(stream_id, PROTOCOL_ERROR);
+                    shared.finish(stream_id);
+                    return;
+                }
+            };
+            let (path, query_params) = http::parse_query_params(path);
+            let mut request = HttpReq {
+                path,
+                method,
+                version: HttpVersion::Http2,
+                headers,
+                body,
+                query_params,
+                path_params: HashMap::new(),
+            };
+            let routed = panic::catch_unwind(AssertUnwindSafe(|| HttpServer::route(&mut request, handlers)));
+            let mut response = routed.unwrap_or_else(|payload| {
+                eprintln!(
+                    "handler panicked: {} {} {}: {}",
+                    request.method.as_str(),
+                    request.path,
+                    request.headers.get(RequestId::HEADER).map_or("-", |id| id.as_str()),
+                    panic_message(&*payload)
+                );
+                HttpResponse::new(500)
+            });
+            if timed_out.get() {
+                println!("request body timed out: {} {}", request.method.as_str(), request.path);
+                response = HttpResponse::new(408);
+            }
+            response
+        }
+        Err(e) => {
+            println!("request parse error: {:?}", e);
+            HttpResponse::new(e.status().unwrap_or(400))
+        }
+    };
+    let status = response.status;
+    if let Err(e) = respond(shared, stream_id, response) {
+        eprintln!("Error writing response {}: {}", status, e);
+        if !matches!(e.kind(), io::ErrorKind::ConnectionReset) {
+            let _ = shared.reset(stream_id, match e.kind() {
+                io::ErrorKind::TimedOut => CANCEL,
+                _ => INTERNAL_ERROR,
+            });
+        }
+    }
+    shared.finish(stream_id);
+}
+
+// Sends the response's header block, then its body as DATA frames.
+fn respond(shared: &Shared, stream_id: u32, response: HttpResponse) -> io::Result<()> {
+    let HttpResponse { status, headers, body } = response;
+    let len = body.len();
+    let mut fields = vec![(":status".to_string(), status.to_string())];
+    for (name, value) in headers {
+        let name = name.to_lowercase();
+        if !CONNECTION_FIELDS.contains(&name.as_str()) {
+            fields.push((name, value));
+        }
+    }
+    fields.push(("date".to_string(), http::http_date(SystemTime::now())));
+    fields.push(("server".to_string(), http::SERVER_NAME.to_string()));
+    if let Some(len) = len.filter(|_| status >= 200 && status != 204 && status != 304) {
+        fields.push(("content-length".to_string(), len.to_string()));
+    }
+    let fields = fields.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect::<Vec<_>>();
+    let block = hpack::encode(&fields);
+    let max_frame = shared.lock().max_frame;
+    let mut frames = Vec::with_capacity(block.len() + 9);
+    let mut fragments = block.chunks(max_frame).peekable();
+    let mut kind = HEADERS;
+    let end_stream = if len == Some(0) { END_STREAM } else { 0 };
+    // an empty block still needs its HEADERS frame
+    loop {
+        let fragment = fragments.next().unwrap_or_default();
+        let end_headers = if fragments.peek().is_none() { END_HEADERS } else { 0 };
+        let flags = if kind == HEADERS { end_headers | end_stream } else { end_headers };
+        put_frame(&mut frames, kind, flags, stream_id, fragment);
+        kind = CONTINUATION;
+        if end_headers != 0 {
+            break;
+        }
+    }
+    shared.send(&frames)?;
+    if len == Some(0) {
+        return Ok(());
+    }
+    let mut reader: Box<dyn Read> = match body {
+        Body::Empty => return Ok(()),
+        Body::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+        Body::File(file, len) => Box::new(file.take(len)),
+        Body::Reader(reader, len) => Box::new(reader.take(len)),
+        Body::Stream(reader) => reader,
+    };
+    let mut buf = vec![0; DEFAULT_MAX_FRAME];
+    let mut sent = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            if let Some(len) = len.filter(|&len| sent < len) {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("body ended after {} of {} bytes", sent, len),
+                ));
+            }
+            // a body of unknown length ends with an empty frame
+            return shared.send_frame(DATA, END_STREAM, stream_id, &[]);
+        }
+        let mut chunk = &buf[..n];
+        while !chunk.is_empty() {
+            let allowed = shared.reserve(stream_id, chunk.len())?;
+            sent += allowed as u64;
+            let flags = if len == Some(sent) { END_STREAM } else { 0 };
+            shared.send_frame(DATA, flags, stream_id, &chunk[..allowed])?;
+            chunk = &chunk[allowed..];
+        }
+        if len == Some(sent) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Engine, HttpServerConfig, ListenAddr, ServerHandle};
+    use super::*;
+    use crate::http_handler::HttpHandler;
+    use crate::tls::TlsConfig;
+    use crate::tls::test_client::{self, ClientOptions};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    const BIG_LEN: usize = 200_000;
+    const UNLIMITED: hpack::Limits = hpack::Limits {
+        size: usize::MAX,
+        fields: usize::MAX,
+    };
+
+    fn big_body() -> Vec<u8> {
+        (0..BIG_LEN).map(|i| (i % 251) as u8).collect()
+    }
+
+    // sends the request body back along with its length
+    struct EchoHandler;
+
+    impl HttpHandler for EchoHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            let mut body = Vec::new();
+            if req.body.read_to_end(&mut body).is_err() {
+                return HttpResponse::new(400);
+            }
+            HttpResponse::new(200)
+                .header("X-Length", &body.len().to_string())
+                .header("Connection", "keep-alive")
+                .stream(io::Cursor::new(body))
+        }
+
+        fn path(&self) -> &str {
+            "/echo"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::POST
+        }
+    }
+
+    // larger than the windows both sides start with
+    struct BigHandler;
+
+    impl HttpHandler for BigHandler {
+        fn handle_request(&self, req: &mut HttpReq) -> HttpResponse {
+            assert_eq!(HttpVersion::Http2, req.version);
+            HttpResponse::new(200).header("x-host", req.headers.get("host").map_or("", |host| host)).body(big_body())
+        }
+
+        fn path(&self) -> &str {
+            "/big"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::GET
+        }
+    }
+
+    fn start(config: HttpServerConfig) -> (ServerHandle, TcpStream) {
+        let handle = HttpServer::start(config.handlers(vec![Box::new(EchoHandler), Box::new(BigHandler)]));
+        let ListenAddr::Tcp(addr) = handle.local_addrs()[0] else { unreachable!() };
+        let conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        (handle, conn)
+    }
+
+    // a TLS client as a byte stream
+    struct TlsConn(test_client::Client, Vec<u8>);
+
+    impl Read for TlsConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.is_empty() {
+                self.1 = self.0.receive()?;
+            }
+            let n = self.1.len().min(buf.len());
+            self.1.drain(..n).zip(buf.iter_mut()).for_each(|(b, out)| *out = b);
+            Ok(n)
+        }
+    }
+
+    impl Write for TlsConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf).map(|_| buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Response {
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        // the RST_STREAM error code, if reset
+        reset: Option<u32>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    // An HTTP/2 client that keeps to the server's flow control windows no further
+    // than the tests need: request bodies stay within the initial window.
+    struct Client<S: Read + Write> {
+        conn: S,
+        decoder: hpack::Decoder,
+    }
+
+    impl<S: Read + Write> Client<S> {
+        fn new(mut conn: S) -> Self {
+            let mut out = PREFACE.to_vec();
+            put_frame(&mut out, SETTINGS, 0, 0, &[]);
+            conn.write_all(&out).unwrap();
+            Client { conn, decoder: hpack::Decoder::new() }
+        }
+
+        fn send(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+            let mut out = Vec::new();
+            put_frame(&mut out, kind, flags, stream_id, payload);
+            self.conn.write_all(&out).unwrap();
+        }
+
+        fn request(&mut self, stream_id: u32, method: &str, path: &str, body: Option<&[u8]>) {
+            let fields = [(":method", method), (":scheme", "http"), (":path", path), (":authority", "example.test")];
+            let flags = if body.is_some() { END_HEADERS } else { END_HEADERS | END_STREAM };
+            self.send(HEADERS, flags, stream_id, &hpack::encode(&fields));
+            if let Some(body) = body {
+                let mut chunks = body.chunks(DEFAULT_MAX_FRAME).peekable();
+                while let Some(chunk) = chunks.next() {
+                    let flags = if chunks.peek().is_none() { END_STREAM } else { 0 };
+                    self.send(DATA, flags, stream_id, chunk);
+                }
+            }
+        }
+
+        // the next frame, None once the server closed
+        fn frame(&mut self) -> Option<Frame> {
+            let mut header = [0; 9];
+            if let Err(e) = self.conn.read_exact(&mut header) {
+                assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+                return None;
+            }
+            let len = u32_at(&[&[0][..], &header[..3]].concat()) as usize;
+            let mut payload = vec![0; len];
+            self.conn.read_exact(&mut payload).unwrap();
+            Some(Frame {
+                kind: header[3],
+                flags: header[4],
+                stream_id: u32_at(&header[5..]),
+                payload,
+            })
+        }
+
+        // reads until the streams finished, granting window as data comes in
+        fn responses(&mut self, streams: &[u32]) -> HashMap<u32, Response> {
+            let mut responses = HashMap::<u32, Response>::new();
+            let mut open = streams.len();
+            while open > 0 {
+                let frame = self.frame().expect("connection closed");
+                let response = responses.entry(frame.stream_id).or_default();
+                match frame.kind {
+                    SETTINGS if frame.flags & ACK == 0 => self.send(SETTINGS, ACK, 0, &[]),
+                    HEADERS => {
+                        assert_ne!(0, frame.flags & END_HEADERS);
+                        for (name, value) in self.decoder.decode(&frame.payload, UNLIMITED).unwrap() {
+                            let field = (String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap());
+                            response.headers.push(field);
+                        }
+                    }
+                    DATA if !frame.payload.is_empty() => {
+                        response.body.extend_from_slice(&frame.payload);
+                        let increment = (frame.payload.len() as u32).to_be_bytes();
+                        self.send(WINDOW_UPDATE, 0, 0, &increment);
+                        self.send(WINDOW_UPDATE, 0, frame.stream_id, &increment);
+                    }
+                    RST_STREAM => {
+                        response.reset = Some(u32_at(&frame.payload));
+                        open -= 1;
+                        continue;
+                    }
+                    GOAWAY => panic!("GOAWAY {}", u32_at(&frame.payload[4..])),
+                    _ => {}
+                }
+                if matches!(frame.kind, HEADERS | DATA) && frame.flags & END_STREAM != 0 {
+                    open -= 1;
+                }
+            }
+            responses
+        }
+
+        // the error code of the GOAWAY the server closes with
+        fn goaway(&mut self) -> u32 {
+            let mut code = None;
+            while let Some(frame) = self.frame() {
+                if frame.kind == GOAWAY {
+                    code = Some(u32_at(&frame.payload[4..]));
+                }
+            }
+            code.expect("GOAWAY")
+        }
+    }
+
+    // serves several streams at once over one connection
+    fn exchange<S: Read + Write>(client: &mut Client<S>) {
+        let upload = big_body()[..60_000].to_vec();
+        client.request(1, "GET", "/big", None);
+        client.request(3, "POST", "/echo", Some(&upload));
+        client.request(5, "GET", "/big?x=1", None);
+        client.request(7, "GET", "/missing", None);
+        let responses = client.responses(&[1, 3, 5, 7]);
+        for id in [1, 5] {
+            let response = &responses[&id];
+            assert_eq!(Some("200"), response.header(":status"));
+            assert_eq!(Some("example.test"), response.header("x-host"));
+            assert_eq!(Some(BIG_LEN.to_string().as_str()), response.header("content-length"));
+            assert!(response.body == big_body());
+        }
+        let echo = &responses[&3];
+        assert_eq!(Some("200"), echo.header(":status"));
+        assert_eq!(Some("60000"), echo.header("x-length"));
+        // connection-specific fields are left out
+        assert_eq!(None, echo.header("connection"));
+        assert!(echo.body == upload);
+        assert_eq!(Some("404"), responses[&7].header(":status"));
+    }
+
+    #[test]
+    fn h2c_test() {
+        for engine in [Engine::Threads, Engine::Epoll] {
+            let (handle, conn) = start(HttpServerConfig::new().listen("127.0.0.1:0".parse().unwrap()).engine(engine));
+            let mut client = Client::new(conn);
+            exchange(&mut client);
+            // a malformed request fails its stream only
+            client.send(HEADERS, END_HEADERS | END_STREAM, 9, &hpack::encode(&[(":method", "GET")]));
+            assert_eq!(Some(PROTOCOL_ERROR), client.responses(&[9])[&9].reset);
+            client.request(11, "GET", "/big", None);
+            assert_eq!(Some("200"), client.responses(&[11])[&11].header(":status"));
+            handle.shutdown();
+        }
+    }
+
+    #[test]
+    fn h2_tls_test() {
+        use crate::tls::testdata_path;
+
+        let tls = TlsConfig::new(testdata_path("server.pem"), testdata_path("server.key"));
+        let (handle, conn) = start(HttpServerConfig::new().listen_tls("127.0.0.1:0".parse().unwrap(), tls));
+        let options = ClientOptions { alpn: vec![b"h2", b"http/1.1"], ..Default::default() };
+        let tls = test_client::Client::connect(conn, &options).unwrap();
+        assert_eq!(Some(b"h2".to_vec()), tls.alpn);
+        let mut client = Client::new(TlsConn(tls, Vec::new()));
+        exchange(&mut client);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn refused_stream_test() {
+        let (handle, conn) = start(HttpServerConfig::new().listen("127.0.0.1:0".parse().unwrap()));
+        let mut client = Client::new(conn);
+        // uploads kept open hold every worker
+        let streams: Vec<u32> = (0..MAX_CONCURRENT_STREAMS as u32).map(|i| 2 * i + 1).collect();
+        for &stream_id in &streams {
+            client.request(stream_id, "POST", "/echo", Some(&[]));
+        }
+        let refused = 2 * MAX_CONCURRENT_STREAMS as u32 + 1;
+        client.request(refused, "GET", "/big", None);
+        assert_eq!(Some(REFUSED_STREAM), client.responses(&[refused])[&refused].reset);
+        for &stream_id in &streams {
+            client.send(DATA, END_STREAM, stream_id, b"x");
+        }
+        let responses = client.responses(&streams);
+        for stream_id in streams {
+            assert_eq!(Some("1"), responses[&stream_id].header("x-length"));
+        }
+        // the workers serve the streams that come next
+        client.request(refused + 2, "GET", "/big", None);
+        assert_eq!(Some("200"), client.responses(&[refused + 2])[&(refused + 2)].header(":status"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn protocol_error_test() {
+        let (handle, conn) = start(HttpServerConfig::new().listen("127.0.0.1:0".parse().unwrap()));
+        // client streams have odd ids
+        let mut client = Client::new(conn);
+        client.request(2, "GET", "/big", None);
+        assert_eq!(PROTOCOL_ERROR, client.goaway());
+
+        let ListenAddr::Tcp(addr) = handle.local_addrs()[0] else { unreachable!() };
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        client.send(WINDOW_UPDATE, 0, 0, &[0, 0, 0, 0]);
+        assert_eq!(PROTOCOL_ERROR, client.goaway());
+
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &[0xff, 0xff]);
+        assert_eq!(COMPRESSION_ERROR, client.goaway());
+
+        // a 4000 byte table entry referenced by one byte each decodes to megabytes
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        let mut block = vec![0x40, 0x01, b'x', 0x7f, 0xa1, 0x1e];
+        block.extend_from_slice(&[b'v'; 4000]);
+        block.extend_from_slice(&[0xbe; 2000]);
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        assert_eq!(ENHANCE_YOUR_CALM, client.goaway());
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,271 @@
+use crate::tls::{Socket, TlsAcceptor, TlsStream};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Where the server listens: "127.0.0.1:8080", "[::]:8080", or "unix:/run/lightio.sock".
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        addr.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("{} is not an address and port, or unix:<path>", addr))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl ListenAddr {
+    // opens a connection, which makes a blocked accept return
+    pub(super) fn connect(&self) -> io::Result<Stream> {
+        match self {
+            ListenAddr::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            ListenAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+}
+
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    // the socket file is removed when the listener is dropped
+    Unix(UnixListener, PathBuf),
+    // accepted connections are wrapped, the handshake is left to whoever serves them
+    Tls(Box<Listener>, TlsAcceptor),
+}
+
+impl Listener {
+    pub(super) fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix(path) => {
+                // a socket file left behind by a server that did not stop cleanly
+                let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+                if stale {
+                    if UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::new(ErrorKind::AddrInUse, "socket is in use"));
+                    }
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    // the bound address, with the port the system picked for port 0
+    pub(super) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+            Listener::Tls(listener, _) => listener.local_addr(),
+        }
+    }
+
+    pub(super) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+            Listener::Tls(listener, acceptor) => {
+                listener.accept().map(|stream| Stream::Tls(Arc::new(acceptor.accept(stream))))
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+            Listener::Tls(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+            Listener::Tls(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// An accepted connection, served the same whatever it came in on.
+pub(super) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    // over a Tcp or Unix stream, shared since the TLS state cannot be duplicated
+    Tls(Arc<TlsStream<Stream>>),
+}
+
+impl Stream {
+    pub(super) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+        }
+    }
+
+    pub(super) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => {
+                // a peer should see close_notify rather than a truncated stream
+                if how == Shutdown::Write {
+                    stream.close_notify()?;
+                }
+                stream.get_ref().shutdown(how)
+            }
+        }
+    }
+
+    pub(super) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+        }
+    }
+
+    pub(super) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => stream.get_ref().set_write_timeout(timeout),
+        }
+    }
+
+    // unix sockets do not coalesce small writes, so there is nothing to turn off
+    pub(super) fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            Stream::Unix(_) => Ok(()),
+            Stream::Tls(stream) => stream.get_ref().set_nodelay(nodelay),
+        }
+    }
+
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(stream) => stream.get_ref().set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tls(stream) => stream.get_ref().as_raw_fd(),
+        }
+    }
+}
+
+impl Socket for Stream {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addr_test() {
+        assert_eq!(Ok(ListenAddr::Tcp("0.0.0.0:80".parse().unwrap())), "0.0.0.0:80".parse());
+        assert_eq!(Ok(ListenAddr::Tcp("[::1]:0".parse().unwrap())), "[::1]:0".parse());
+        assert_eq!(Ok(ListenAddr::Unix(PathBuf::from("/tmp/s"))), "unix:/tmp/s".parse());
+        assert!("localhost:80".parse::<ListenAddr>().is_err());
+        assert!("127.0.0.1".parse::<ListenAddr>().is_err());
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert_eq!("unix:/tmp/s", ListenAddr::Unix(PathBuf::from("/tmp/s")).to_string());
+        assert_eq!("[::1]:8080", "[::1]:8080".parse::<ListenAddr>().unwrap().to_string());
+    }
+
+    #[test]
+    fn stale_unix_socket_test() {
+        let path = std::env::temp_dir().join("lightio_listen_stale.sock");
+        let _ = fs::remove_file(&path);
+        let addr = ListenAddr::Unix(path.clone());
+        // left behind without removing the file
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&addr).unwrap();
+        assert_eq!(ErrorKind::AddrInUse, Listener::bind(&addr).err().unwrap().kind());
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,366 @@
+#[cfg(target_os = "linux")]
+use crate::io_uring::{IoUring, Op};
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+// registered buffers, also the most operations in one batch
+const BUFFERS: usize = 16;
+// the most one operation moves, larger reads and writes are split
+pub const BUFFER_SIZE: usize = 256 * 1024;
+// UringWriter collects this much before writing it as one batch
+const WRITE_BATCH: usize = 4 * BUFFER_SIZE;
+
+enum Kind {
+    Read(usize),
+    Write(Vec<u8>),
+}
+
+struct Request {
+    // a duplicate of the caller's descriptor, which the caller may close while
+    // chunks of a failed read or write are still queued
+    file: Arc<File>,
+    offset: u64,
+    kind: Kind,
+    // the bytes read, or the prefix of the data that was written
+    reply: SyncSender<io::Result<Vec<u8>>>,
+}
+
+// Reads and writes files through io_uring. Requests from any thread go to a
+// single submitter thread, which hands everything queued at that moment to the
+// kernel with one system call, so concurrent small object reads are batched.
+// Data goes through buffers registered with the kernel.
+#[derive(Debug)]
+pub struct UringIo {
+    sender: Sender<Request>,
+}
+
+impl UringIo {
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(ErrorKind::Unsupported, "io_uring is only available on Linux"))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn new() -> io::Result<Self> {
+        let mut ring = IoUring::new(BUFFERS as u32)?;
+        let mut buffers: Vec<Box<[u8]>> = (0..BUFFERS).map(|_| vec![0u8; BUFFER_SIZE].into()).collect();
+        // pinning needs locked memory, which may be limited, plain reads still work
+        let fixed = match ring.register_buffers(&mut buffers) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("cannot register io_uring buffers, using unregistered ones: {}", e);
+                false
+            }
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || Self::submitter(ring, buffers, fixed, receiver))?;
+        Ok(Self { sender })
+    }
+
+    // Reads up to len bytes at offset, fewer only if the file ends first.
+    pub fn read_at(&self, file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let file = Arc::new(file.try_clone()?);
+        let mut replies = Vec::new();
+        let mut queued = 0;
+        while queued < len {
+            let chunk = (len - queued).min(BUFFER_SIZE);
+            replies.push(self.request(&file, offset + queued as u64, Kind::Read(chunk))?);
+            queued += chunk;
+        }
+        let mut data = Vec::with_capacity(len);
+        for reply in replies {
+            let chunk = Self::wait(reply)?;
+            let short = chunk.len() < BUFFER_SIZE.min(len - data.len());
+            data.extend_from_slice(&chunk);
+            if short {
+                // the file ended, later chunks are empty or were written since
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn write_all_at(&self, file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+        let shared = Arc::new(file.try_clone()?);
+        let replies = data
+            .chunks(BUFFER_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let offset = offset + (i * BUFFER_SIZE) as u64;
+                self.request(&shared, offset, Kind::Write(chunk.to_vec()))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        for (i, reply) in replies.into_iter().enumerate() {
+            let written = Self::wait(reply)?.len();
+            let chunk = &data[i * BUFFER_SIZE..data.len().min((i + 1) * BUFFER_SIZE)];
+            if written == 0 && !chunk.is_empty() {
+                return Err(io::Error::new(ErrorKind::WriteZero, "file write returned zero"));
+            }
+            if written < chunk.len() {
+                let offset = offset + (i * BUFFER_SIZE + written) as u64;
+                self.write_all_at(file, offset, &chunk[written..])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, file: &Arc<File>, offset: u64, kind: Kind) -> io::Result<Receiver<io::Result<Vec<u8>>>> {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        let file = Arc::clone(file);
+        self.sender
+            .send(Request { file, offset, kind, reply })
+            .map_err(|_| io::Error::other("io_uring submitter stopped"))?;
+        Ok(receiver)
+    }
+
+    fn wait(receiver: Receiver<io::Result<Vec<u8>>>) -> io::Result<Vec<u8>> {
+        receiver
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("io_uring submitter stopped")))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn submitter(mut ring: IoUring, mut buffers: Vec<Box<[u8]>>, fixed: bool, receiver: Receiver<Request>) {
+        let mut batch = Vec::with_capacity(BUFFERS);
+        while let Ok(request) = receiver.recv() {
+            batch.push(request);
+            while batch.len() < BUFFERS
+                && let Ok(request) = receiver.try_recv()
+            {
+                batch.push(request);
+            }
+            for (i, request) in batch.iter().enumerate() {
+                let buffer = &mut buffers[i];
+                let (fd, offset, index) = (request.file.as_raw_fd(), request.offset, i as u16);
+                let op = match (&request.kind, fixed) {
+                    (Kind::Read(len), true) => {
+                        Op::ReadFixed { fd, buf: buffer.as_mut_ptr(), len: *len as u32, offset, index }
+                    }
+                    (Kind::Read(len), false) => Op::Read { fd, buf: buffer.as_mut_ptr(), len: *len as u32, offset },
+                    (Kind::Write(data), fixed) => {
+                        buffer[..data.len()].copy_from_slice(data);
+                        let (buf, len) = (buffer.as_ptr(), data.len() as u32);
+                        if fixed {
+                            Op::WriteFixed { fd, buf, len, offset, index }
+                        } else {
+                            Op::Write { fd, buf, len, offset }
+                        }
+                    }
+                };
+                assert!(ring.push(op, i as u64), "a batch fits the ring");
+            }
+            let mut results = (0..batch.len()).map(|_| None).collect::<Vec<_>>();
+            if let Err(e) = Self::run(&mut ring, &mut results) {
+                // operations may still be in the kernel with our buffers, stop using them
+                eprintln!("io_uring submission failed, stopping the submitter: {}", e);
+                for request in batch.drain(..) {
+                    let _ = request.reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+                return;
+            }
+            for ((i, request), result) in batch.drain(..).enumerate().zip(results) {
+                let result = result.expect("every operation completed").map(|n| match request.kind {
+                    Kind::Read(_) => buffers[i][..n].to_vec(),
+                    Kind::Write(mut data) => {
+                        data.truncate(n);
+                        data
+                    }
+                });
+                // the caller may have given up on a failed earlier chunk
+                let _ = request.reply.send(result);
+            }
+        }
+    }
+
+    // submits the pushed operations and waits for all of them
+    #[cfg(target_os = "linux")]
+    fn run(ring: &mut IoUring, results: &mut [Option<io::Result<usize>>]) -> io::Result<()> {
+        ring.submit(results.len() as u32)?;
+        let mut done = 0;
+        while done < results.len() {
+            match ring.complete() {
+                Some((i, result)) => {
+                    results[i as usize] = Some(result);
+                    done += 1;
+                }
+                None => ring.submit(1)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes a file through io_uring in batches of several operations. finish must
+// be called after the last write, the file is incomplete otherwise.
+pub struct UringWriter {
+    file: File,
+    io: Arc<UringIo>,
+    offset: u64,
+    buffer: Vec<u8>,
+}
+
+impl UringWriter {
+    pub fn new(file: File, io: Arc<UringIo>) -> Self {
+        Self {
+            file,
+            io,
+            offset: 0,
+            buffer: Vec::with_capacity(WRITE_BATCH),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Write for UringWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(WRITE_BATCH - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == WRITE_BATCH {
+            self.flush()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.write_all_at(&self.file, self.offset, &self.buffer)?;
+        self.offset += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Instant;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_write_test() {
+        let io = Arc::new(UringIo::new().unwrap());
+        let path = std::env::temp_dir().join("lightio_storage_io_test");
+        let expected = data(3 * BUFFER_SIZE + 100);
+        let mut writer = UringWriter::new(File::create(&path).unwrap(), Arc::clone(&io));
+        for chunk in expected.chunks(70_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(std::fs::read(&path).unwrap() == expected);
+
+        let file = File::open(&path).unwrap();
+        assert!(io.read_at(&file, 0, expected.len()).unwrap() == expected);
+        assert!(io.read_at(&file, 10, 1000).unwrap() == expected[10..1010]);
+        // reading past the end stops at the end
+        assert!(io.read_at(&file, BUFFER_SIZE as u64, 10 * BUFFER_SIZE).unwrap() == expected[BUFFER_SIZE..]);
+        assert!(io.read_at(&file, expected.len() as u64, 10).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_write_test() {
+        let io = Arc::new(UringIo::new().unwrap());
+        let dir = std::env::temp_dir().join("lightio_storage_io_failed_write");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("read_only"), b"").unwrap();
+        // the first chunk fails, the later ones are still queued when this returns
+        let read_only = File::open(dir.join("read_only")).unwrap();
+        assert!(io.write_all_at(&read_only, 0, &data(8 * BUFFER_SIZE)).is_err());
+        drop(read_only);
+        // likely to get the closed descriptor's number
+        let mut other = File::create(dir.join("other")).unwrap();
+        other.write_all(b"other").unwrap();
+        assert!(io.read_at(&File::open(dir.join("read_only")).unwrap(), 0, 10).unwrap().is_empty());
+        assert_eq!(b"other".to_vec(), std::fs::read(dir.join("other")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_reads_test() {
+        let io = Arc::new(UringIo::new().unwrap());
+        let dir = std::env::temp_dir().join("lightio_storage_io_concurrent");
+        std::fs::create_dir_all(&dir).unwrap();
+        let threads = (0..8)
+            .map(|t| {
+                let io = Arc::clone(&io);
+                let path = dir.join(t.to_string());
+                thread::spawn(move || {
+                    let expected = data(1000 + t * 3000);
+                    std::fs::write(&path, &expected).unwrap();
+                    let file = File::open(&path).unwrap();
+                    for _ in 0..50 {
+                        assert!(io.read_at(&file, 0, expected.len()).unwrap() == expected);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // cargo test --release uring_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn uring_benchmark() {
+        let dir = std::env::temp_dir().join("lightio_storage_io_benchmark");
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = 256;
+        let size = 16 * 1024;
+        for i in 0..files {
+            std::fs::write(dir.join(i.to_string()), data(size)).unwrap();
+        }
+        let io = Arc::new(UringIo::new().unwrap());
+        let rounds = 20;
+        for engine in ["blocking", "io_uring"] {
+            let started = Instant::now();
+            let threads = (0..8)
+                .map(|t| {
+                    let (io, dir) = (Arc::clone(&io), dir.clone());
+                    thread::spawn(move || {
+                        for _ in 0..rounds {
+                            for i in (t..files).step_by(8) {
+                                let mut file = File::open(dir.join(i.to_string())).unwrap();
+                                let read = if engine == "blocking" {
+                                    let mut data = Vec::with_capacity(size);
+                                    file.read_to_end(&mut data).unwrap();
+                                    data
+                                } else {
+                                    io.read_at(&file, 0, size).unwrap()
+                                };
+                                assert_eq!(size, read.len());
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            let elapsed = started.elapsed();
+            println!(
+                "{}: {} reads of {} bytes in {:?}, {:.0} reads/s",
+                engine,
+                files * rounds,
+                size,
+                elapsed,
+                (files * rounds) as f64 / elapsed.as_secs_f64()
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
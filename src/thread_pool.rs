@@ -1,70 +1,284 @@
+use std::collections::VecDeque;
 use std::io;
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
 
-struct Worker {
-    thread: Option<thread::JoinHandle<()>>,
+// What a submitted job is told: run on a worker, or give up because the queue is full.
+// Shed jobs are called on the submitting thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispatch {
+    Run,
+    Shed,
 }
 
-impl Worker {
-    fn new(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                let message = receiver.lock().unwrap().recv();
-
-                match message {
-                    Ok(job) => {
-                        job();
-                    }
-                    Err(_) => {
-                        break;
-                    }
-                }
+// What to do with a job submitted while the queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    // wait for room in the queue
+    #[allow(dead_code)]
+    Block,
+    // shed the new job
+    Reject,
+    // shed the oldest queued job to make room
+    #[allow(dead_code)]
+    DropOldest,
+}
+
+type Job = Box<dyn FnOnce(Dispatch) + Send + 'static>;
+
+struct Jobs {
+    queued: VecDeque<Job>,
+    // set when the pool is dropped, workers exit once the queue is empty
+    closed: bool,
+}
+
+struct Queue {
+    jobs: Mutex<Jobs>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    // respawned workers are added here too
+    workers: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+// Jobs never run with a lock held, but a poisoned lock must not take the pool down.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Queue {
+    fn pop(&self) -> Option<Job> {
+        let mut jobs = lock(&self.jobs);
+        loop {
+            if let Some(job) = jobs.queued.pop_front() {
+                self.not_full.notify_one();
+                return Some(job);
             }
-        });
+            if jobs.closed {
+                return None;
+            }
+            jobs = self.not_empty.wait(jobs).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
 
-        Worker {
-            thread: Some(thread),
+// Lives on a worker thread and replaces it when a job panics, so the pool keeps its size.
+struct Sentinel {
+    queue: Arc<Queue>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!("worker thread panicked, respawning it");
+            spawn_worker(&self.queue);
         }
     }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+fn spawn_worker(queue: &Arc<Queue>) {
+    let sentinel = Sentinel {
+        queue: Arc::clone(queue),
+    };
+    let thread = thread::spawn(move || {
+        while let Some(job) = sentinel.queue.pop() {
+            job(Dispatch::Run);
+        }
+    });
+    lock(&queue.workers).push(thread);
+}
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    queue: Arc<Queue>,
+    policy: QueuePolicy,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> io::Result<Self> {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size);
+    // `queue_size` jobs at most wait for a worker, more are handled by the policy
+    pub fn new(size: usize, queue_size: usize, policy: QueuePolicy) -> io::Result<Self> {
+        if size == 0 || queue_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pool and queue sizes must be positive",
+            ));
+        }
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(Jobs {
+                queued: VecDeque::with_capacity(queue_size),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: queue_size,
+            workers: Mutex::new(Vec::with_capacity(size)),
+        });
 
         for _ in 0..size {
-            workers.push(Worker::new(Arc::clone(&receiver)));
+            spawn_worker(&queue);
         }
 
-        Ok(ThreadPool { workers, sender })
+        Ok(ThreadPool { queue, policy })
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn policy(&self) -> QueuePolicy {
+        self.policy
     }
 
+    // runs the job on a worker, a shed job is dropped
+    #[allow(dead_code)]
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        self.submit(move |dispatch| {
+            if dispatch == Dispatch::Run {
+                f()
+            }
+        });
+    }
+
+    pub fn submit<F>(&self, f: F)
+    where
+        F: FnOnce(Dispatch) + Send + 'static,
+    {
+        let mut jobs = lock(&self.queue.jobs);
+        let mut shed = None;
+        while jobs.queued.len() >= self.queue.capacity {
+            match self.policy {
+                QueuePolicy::Block => {
+                    jobs = self.queue.not_full.wait(jobs).unwrap_or_else(PoisonError::into_inner)
+                }
+                QueuePolicy::Reject => {
+                    drop(jobs);
+                    f(Dispatch::Shed);
+                    return;
+                }
+                QueuePolicy::DropOldest => shed = jobs.queued.pop_front(),
+            }
+        }
+        jobs.queued.push_back(Box::new(f));
+        drop(jobs);
+        self.queue.not_empty.notify_one();
+        if let Some(job) = shed {
+            job(Dispatch::Shed);
+        }
     }
 }
 
+// Runs the jobs still queued, then joins the workers.
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        lock(&self.queue.jobs).closed = true;
+        self.queue.not_empty.notify_all();
+        // a worker that panics meanwhile registers its replacement before it exits
+        loop {
+            let thread = lock(&self.queue.workers).pop();
+            match thread {
+                Some(thread) => {
+                    let _ = thread.join();
+                }
+                None => break,
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // a pool whose only worker is stuck until the returned sender is used
+    fn busy_pool(policy: QueuePolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::new(1, 1, policy).unwrap();
+        let (release, wait) = mpsc::channel();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        });
+        running.recv().unwrap();
+        (pool, release)
+    }
+
+    fn record(pool: &ThreadPool, name: &'static str, log: &mpsc::Sender<(&'static str, Dispatch)>) {
+        let log = log.clone();
+        pool.submit(move |dispatch| log.send((name, dispatch)).unwrap());
+    }
+
+    #[test]
+    fn reject_policy_test() {
+        let (pool, release) = busy_pool(QueuePolicy::Reject);
+        let (log, results) = mpsc::channel();
+        record(&pool, "queued", &log);
+        record(&pool, "rejected", &log);
+        assert_eq!(("rejected", Dispatch::Shed), results.recv().unwrap());
+        release.send(()).unwrap();
+        assert_eq!(("queued", Dispatch::Run), results.recv().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_policy_test() {
+        let (pool, release) = busy_pool(QueuePolicy::DropOldest);
+        let (log, results) = mpsc::channel();
+        record(&pool, "oldest", &log);
+        record(&pool, "newest", &log);
+        assert_eq!(("oldest", Dispatch::Shed), results.recv().unwrap());
+        release.send(()).unwrap();
+        assert_eq!(("newest", Dispatch::Run), results.recv().unwrap());
+    }
+
+    #[test]
+    fn block_policy_test() {
+        let (pool, release) = busy_pool(QueuePolicy::Block);
+        let (log, results) = mpsc::channel();
+        record(&pool, "first", &log);
+        let pool = Arc::new(pool);
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            let log = log.clone();
+            thread::spawn(move || record(&pool, "second", &log))
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!submitter.is_finished());
+        release.send(()).unwrap();
+        submitter.join().unwrap();
+        assert_eq!(("first", Dispatch::Run), results.recv().unwrap());
+        assert_eq!(("second", Dispatch::Run), results.recv().unwrap());
+    }
+
+    #[test]
+    fn panic_respawn_test() {
+        let pool = ThreadPool::new(2, 4, QueuePolicy::Block).unwrap();
+        for _ in 0..2 {
+            pool.execute(|| panic!("job panicked"));
+        }
+        // both workers were replaced, so two jobs can still wait on each other
+        let (log, results) = mpsc::channel();
+        let (signal, wait) = mpsc::channel();
+        let waiting = log.clone();
+        pool.execute(move || {
+            wait.recv_timeout(Duration::from_secs(1)).unwrap();
+            waiting.send("waiting").unwrap();
+        });
+        pool.execute(move || {
+            signal.send(()).unwrap();
+            log.send("signalling").unwrap();
+        });
+        let mut done = vec![results.recv().unwrap(), results.recv().unwrap()];
+        done.sort();
+        assert_eq!(vec!["signalling", "waiting"], done);
+    }
+
+    #[test]
+    fn drop_runs_queued_jobs_test() {
+        let (pool, release) = busy_pool(QueuePolicy::Block);
+        let (log, results) = mpsc::channel();
+        record(&pool, "queued", &log);
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(("queued", Dispatch::Run), results.try_recv().unwrap());
+    }
+}
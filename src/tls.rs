@@ -0,0 +1,621 @@
+// TLS for the server's listeners, on rustls with the ring provider and TLS 1.3
+// only. What is left to this file: certificates picked by SNI name, files reloaded
+// when they change, and a stream that one thread reads while others write.
+
+#[cfg(test)]
+pub mod test_client;
+
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+// ciphertext read from the socket at a time, a whole record with its overhead
+const RECEIVE_SIZE: usize = 16384 + 256;
+
+// What a TLS stream runs over, used through shared references like TcpStream.
+pub trait Socket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+}
+
+impl Socket for TcpStream {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+}
+
+// a socket as Read and Write for rustls
+struct Io<'a, S>(&'a S);
+
+impl<S: Socket> Read for Io<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl<S: Socket> Write for Io<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid(e: impl fmt::Display) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+// Certificates and keys for a TLS listener. The files are checked for changes
+// while the server runs and reloaded, a broken file keeps the previous one in use.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    server_names: Vec<(String, PathBuf, PathBuf)>,
+    client_ca: Option<PathBuf>,
+    alpn: Vec<Vec<u8>>,
+    reload_interval: Duration,
+}
+
+impl TlsConfig {
+    // the certificate chain, leaf first, and its key, both PEM files, used when no
+    // server name matches
+    pub fn new(cert: String, key: String) -> Self {
+        Self {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+            server_names: Vec::new(),
+            client_ca: None,
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            reload_interval: Duration::from_secs(1),
+        }
+    }
+
+    // a certificate for clients asking for this name with SNI, "*.example.com"
+    // matches one label
+    pub fn server_name(mut self, name: String, cert: String, key: String) -> Self {
+        self.server_names.push((name.to_ascii_lowercase(), PathBuf::from(cert), PathBuf::from(key)));
+        self
+    }
+
+    // requires clients to present a certificate issued by one in this PEM file
+    pub fn client_ca(mut self, path: String) -> Self {
+        self.client_ca = Some(PathBuf::from(path));
+        self
+    }
+
+    // how often the files are checked for changes
+    #[allow(dead_code)]
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+}
+
+// Something loaded from files, loaded again when their modification times change.
+struct Reloadable<T> {
+    paths: Vec<PathBuf>,
+    load: fn(&[PathBuf]) -> io::Result<T>,
+    interval: Duration,
+    state: Mutex<Loaded<T>>,
+}
+
+struct Loaded<T> {
+    value: Arc<T>,
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+}
+
+impl<T> Reloadable<T> {
+    fn new(paths: Vec<PathBuf>, load: fn(&[PathBuf]) -> io::Result<T>, interval: Duration) -> io::Result<Self> {
+        let modified = Self::modified(&paths);
+        let value = Arc::new(load(&paths)?);
+        Ok(Self {
+            paths,
+            load,
+            interval,
+            state: Mutex::new(Loaded { value, modified, checked: Instant::now() }),
+        })
+    }
+
+    fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+        paths.iter().map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok()).collect()
+    }
+
+    fn get(&self) -> Arc<T> {
+        let mut state = self.state.lock().unwrap();
+        if state.checked.elapsed() >= self.interval {
+            state.checked = Instant::now();
+            let modified = Self::modified(&self.paths);
+            if modified != state.modified {
+                // remembered either way, a file being rewritten changes again when done
+                state.modified = modified;
+                let names = self.paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ");
+                match (self.load)(&self.paths) {
+                    Ok(value) => {
+                        println!("reloaded {}", names);
+                        state.value = Arc::new(value);
+                    }
+                    Err(e) => eprintln!("cannot reload {}, keeping the previous one: {}", names, e),
+                }
+            }
+        }
+        Arc::clone(&state.value)
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn in_file<T>(path: &Path, result: io::Result<T>) -> io::Result<T> {
+    result.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+// paths are the certificate chain and the key
+fn load_identity(paths: &[PathBuf]) -> io::Result<CertifiedKey> {
+    let chain = load_certificates(&paths[..1])?;
+    let key = in_file(&paths[1], PrivateKeyDer::from_pem_slice(read_file(&paths[1])?.as_bytes()).map_err(invalid))?;
+    CertifiedKey::from_der(chain, key, &provider()).map_err(|e| {
+        invalid(format!("{} does not match {}: {}", paths[1].display(), paths[0].display(), e))
+    })
+}
+
+fn load_certificates(paths: &[PathBuf]) -> io::Result<Vec<CertificateDer<'static>>> {
+    let pem = read_file(&paths[0])?;
+    let certs = CertificateDer::pem_slice_iter(pem.as_bytes()).collect::<Result<Vec<_>, _>>().map_err(invalid);
+    match in_file(&paths[0], certs)? {
+        certs if certs.is_empty() => Err(invalid(format!("{}: no certificates", paths[0].display()))),
+        certs => Ok(certs),
+    }
+}
+
+// Client certificates are checked by webpki: the chain up to the CA, validity,
+// key usage for client authentication and unknown critical extensions.
+fn load_verifier(paths: &[PathBuf]) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certificates(paths)? {
+        in_file(&paths[0], roots.add(cert).map_err(invalid))?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build();
+    in_file(&paths[0], verifier.map_err(invalid))
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == name,
+    }
+}
+
+// the certificate for the name the client asked for with SNI
+struct Identities {
+    default: Reloadable<CertifiedKey>,
+    server_names: Vec<(String, Reloadable<CertifiedKey>)>,
+}
+
+impl fmt::Debug for Identities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.server_names.iter().map(|(name, _)| name);
+        f.debug_struct("Identities").field("server_names", &names.collect::<Vec<_>>()).finish()
+    }
+}
+
+impl ResolvesServerCert for Identities {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().map(|name| name.to_ascii_lowercase());
+        let matching = name.and_then(|name| self.server_names.iter().find(|(pattern, _)| name_matches(pattern, &name)));
+        Some(matching.map_or(&self.default, |(_, identity)| identity).get())
+    }
+}
+
+type Verifier = Arc<dyn ClientCertVerifier>;
+
+struct Shared {
+    identities: Arc<Identities>,
+    client_ca: Option<Reloadable<Verifier>>,
+    alpn: Vec<Vec<u8>>,
+    // the config for the client CA loaded last, built again once it is reloaded
+    config: Mutex<(Option<Arc<Verifier>>, Arc<ServerConfig>)>,
+}
+
+fn server_config(identities: &Arc<Identities>, verifier: Option<&Verifier>, alpn: &[Vec<u8>]) -> Arc<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("the ring provider supports TLS 1.3");
+    let builder = match verifier {
+        Some(verifier) => builder.with_client_cert_verifier(Arc::clone(verifier)),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::clone(identities) as Arc<dyn ResolvesServerCert>);
+    config.alpn_protocols = alpn.to_vec();
+    Arc::new(config)
+}
+
+impl Shared {
+    fn config(&self) -> Arc<ServerConfig> {
+        let verifier = self.client_ca.as_ref().map(|ca| ca.get());
+        let mut config = self.config.lock().unwrap();
+        let current = match (&config.0, &verifier) {
+            (Some(built), Some(verifier)) => Arc::ptr_eq(built, verifier),
+            (built, verifier) => built.is_none() && verifier.is_none(),
+        };
+        if !current {
+            let built = server_config(&self.identities, verifier.as_deref(), &self.alpn);
+            *config = (verifier, built);
+        }
+        Arc::clone(&config.1)
+    }
+}
+
+// Loads the certificates of a listener and wraps its connections.
+#[derive(Clone)]
+pub struct TlsAcceptor(Arc<Shared>);
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsConfig) -> io::Result<Self> {
+        let interval = config.reload_interval;
+        let identity = |cert: &PathBuf, key: &PathBuf| {
+            Reloadable::new(vec![cert.clone(), key.clone()], load_identity, interval)
+        };
+        let server_names = config
+            .server_names
+            .iter()
+            .map(|(name, cert, key)| Ok((name.clone(), identity(cert, key)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let client_ca = match &config.client_ca {
+            Some(path) => Some(Reloadable::new(vec![path.clone()], load_verifier, interval)?),
+            None => None,
+        };
+        let identities = Arc::new(Identities { default: identity(&config.cert, &config.key)?, server_names });
+        let verifier = client_ca.as_ref().map(|ca| ca.get());
+        let built = server_config(&identities, verifier.as_deref(), &config.alpn);
+        let shared = Shared {
+            identities,
+            client_ca,
+            alpn: config.alpn.clone(),
+            config: Mutex::new((verifier, built)),
+        };
+        Ok(TlsAcceptor(Arc::new(shared)))
+    }
+
+    // the handshake happens later, see TlsStream::handshake
+    pub fn accept<S>(&self, socket: S) -> TlsStream<S> {
+        let conn = ServerConnection::new(self.0.config()).expect("a valid server config");
+        TlsStream {
+            socket,
+            conn: Mutex::new(conn),
+            sending: Mutex::new(()),
+            received: Mutex::new(Vec::new()),
+            session: OnceLock::new(),
+        }
+    }
+}
+
+// what the handshake settled
+struct Session {
+    alpn: Option<Vec<u8>>,
+    server_name: Option<String>,
+    client_certificates: Vec<Vec<u8>>,
+}
+
+// A server connection. Reads and writes can happen from different threads: the
+// rustls state is only locked while records are sealed or opened, never while the
+// socket blocks.
+pub struct TlsStream<S> {
+    socket: S,
+    conn: Mutex<ServerConnection>,
+    // held while sealed records go out, so that they reach the socket in order
+    sending: Mutex<()>,
+    // ciphertext read from the socket that rustls did not take yet
+    received: Mutex<Vec<u8>>,
+    session: OnceLock<Session>,
+}
+
+impl<S: Socket> TlsStream<S> {
+    // Runs the handshake, on failure the peer gets an alert if the error calls for one.
+    pub fn handshake(&self) -> io::Result<()> {
+        let _sending = self.sending.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let mut io = Io(&self.socket);
+        while conn.is_handshaking() {
+            conn.complete_io(&mut io)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut io)?;
+        }
+        let session = Session {
+            alpn: conn.alpn_protocol().map(<[u8]>::to_vec),
+            server_name: conn.server_name().map(str::to_string),
+            client_certificates: conn.peer_certificates().unwrap_or_default().iter().map(|cert| cert.to_vec()).collect(),
+        };
+        let _ = self.session.set(session);
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    // the protocol agreed on with ALPN
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.session.get().and_then(|session| session.alpn.as_deref())
+    }
+
+    // the name the client asked for with SNI
+    #[allow(dead_code)]
+    pub fn server_name(&self) -> Option<&str> {
+        self.session.get().and_then(|session| session.server_name.as_deref())
+    }
+
+    // the client's certificate chain in DER, leaf first, when client certificates are required
+    #[allow(dead_code)]
+    pub fn client_certificates(&self) -> Vec<&[u8]> {
+        self.session.get().map_or(Vec::new(), |session| {
+            session.client_certificates.iter().map(Vec::as_slice).collect()
+        })
+    }
+
+    // tells the peer nothing more will be written
+    pub fn close_notify(&self) -> io::Result<()> {
+        let _sending = self.sending.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        if conn.is_handshaking() {
+            return Ok(());
+        }
+        conn.send_close_notify();
+        self.send_pending(conn)
+    }
+
+    fn established(&self) -> io::Result<()> {
+        match self.session.get() {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "TLS handshake not done")),
+        }
+    }
+
+    // Writes out the records rustls has sealed. The caller holds the sending lock,
+    // the state is unlocked before the socket write.
+    fn send_pending(&self, mut conn: MutexGuard<'_, ServerConnection>) -> io::Result<()> {
+        let mut out = Vec::new();
+        while conn.wants_write() {
+            conn.write_tls(&mut out)?;
+        }
+        drop(conn);
+        Io(&self.socket).write_all(&out)
+    }
+}
+
+impl<S: Socket> Read for &TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.established()?;
+        let mut received = self.received.lock().unwrap();
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                // closed without close_notify, HTTP framing tells a truncated message anyway
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            if received.is_empty() {
+                received.resize(RECEIVE_SIZE, 0);
+                let n = self.socket.recv(&mut received);
+                received.truncate(*n.as_ref().unwrap_or(&0));
+                n?;
+            }
+            let _sending = self.sending.lock().unwrap();
+            let mut conn = self.conn.lock().unwrap();
+            // rustls takes what fits its buffers, the rest waits for the next round.
+            // An empty read tells it the peer closed.
+            let n = conn.read_tls(&mut &received[..])?;
+            received.drain(..n);
+            let processed = conn.process_new_packets();
+            // alerts, and the key update the peer may have asked for
+            self.send_pending(conn)?;
+            processed.map_err(invalid)?;
+        }
+    }
+}
+
+impl<S: Socket> Write for &TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.established()?;
+        let _sending = self.sending.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        self.send_pending(conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub fn testdata_path(name: &str) -> String {
+    format!("{}/src/tls/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[cfg(test)]
+fn testdata(name: &str) -> String {
+    fs::read_to_string(testdata_path(name)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_client::{Client, ClientOptions};
+    use super::*;
+    use rustls::crypto::ring::cipher_suite::{TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    fn config() -> TlsConfig {
+        TlsConfig::new(testdata_path("server.pem"), testdata_path("server.key"))
+    }
+
+    // accepts one connection, echoes one read back and closes it
+    fn serve(config: &TlsConfig) -> (SocketAddr, JoinHandle<io::Result<TlsStream<TcpStream>>>) {
+        let acceptor = TlsAcceptor::new(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let tls = acceptor.accept(listener.accept()?.0);
+            tls.handshake()?;
+            let mut buf = [0; 64];
+            let n = (&tls).read(&mut buf)?;
+            (&tls).write_all(&buf[..n])?;
+            tls.close_notify()?;
+            Ok(tls)
+        });
+        (addr, handle)
+    }
+
+    fn connect(addr: SocketAddr, options: &ClientOptions) -> io::Result<Client> {
+        Client::connect(TcpStream::connect(addr)?, options)
+    }
+
+    fn echo(client: &mut Client) {
+        client.send(b"ping").unwrap();
+        assert_eq!(b"ping", &client.receive().unwrap()[..]);
+        assert!(client.receive().unwrap().is_empty());
+    }
+
+    fn leaf(name: &str) -> Vec<u8> {
+        CertificateDer::pem_slice_iter(testdata(name).as_bytes()).next().unwrap().unwrap().to_vec()
+    }
+
+    #[test]
+    fn handshake_test() {
+        for suite in [TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256] {
+            let (addr, server) = serve(&config());
+            let options = ClientOptions { suites: vec![suite], alpn: vec![b"h2", b"http/1.1"], ..Default::default() };
+            let mut client = connect(addr, &options).unwrap();
+            assert_eq!(suite.suite(), client.suite.suite());
+            assert_eq!(Some(b"h2".to_vec()), client.alpn);
+            assert_eq!(leaf("server.pem"), client.server_chain[0]);
+            echo(&mut client);
+            let tls = server.join().unwrap().unwrap();
+            assert_eq!(Some(&b"h2"[..]), tls.alpn_protocol());
+            assert_eq!(Some("localhost"), tls.server_name());
+            assert!(tls.client_certificates().is_empty());
+        }
+    }
+
+    #[test]
+    fn server_name_test() {
+        let config =
+            config().server_name("*.other.test".to_string(), testdata_path("other.pem"), testdata_path("other.key"));
+        for (name, expected) in [
+            (Some("a.other.test"), "other.pem"),
+            (Some("A.Other.Test"), "other.pem"),
+            (Some("other.test"), "server.pem"),
+            (Some("a.b.other.test"), "server.pem"),
+            (None, "server.pem"),
+        ] {
+            let (addr, server) = serve(&config);
+            let mut client = connect(addr, &ClientOptions { server_name: name, ..Default::default() }).unwrap();
+            assert_eq!(leaf(expected), client.server_chain[0], "{:?}", name);
+            echo(&mut client);
+            server.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn alpn_test() {
+        let (addr, server) = serve(&config());
+        let result = connect(addr, &ClientOptions { alpn: vec![b"spdy/3"], ..Default::default() });
+        assert!(result.is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn client_certificate_test() {
+        let config = config().client_ca(testdata_path("ca.pem"));
+        let (addr, server) = serve(&config);
+        let mut client = connect(addr, &ClientOptions { identity: Some(("client.pem", "client.key")), ..Default::default() }).unwrap();
+        echo(&mut client);
+        let tls = server.join().unwrap().unwrap();
+        assert_eq!(vec![&leaf("client.pem")[..]], tls.client_certificates());
+
+        // not issued by the CA, issued for servers only, an unknown critical extension, none at all
+        for identity in [
+            Some(("untrusted.pem", "untrusted.key")),
+            Some(("server_auth.pem", "server_auth.key")),
+            Some(("critical.pem", "critical.key")),
+            None,
+        ] {
+            let (addr, server) = serve(&config);
+            let result = connect(addr, &ClientOptions { identity, ..Default::default() }).and_then(|mut client| client.receive());
+            assert!(result.is_err(), "{:?}", identity);
+            assert!(server.join().unwrap().is_err(), "{:?}", identity);
+        }
+    }
+
+    #[test]
+    fn key_update_test() {
+        let (addr, server) = serve(&config());
+        let mut client = connect(addr, &ClientOptions::default()).unwrap();
+        client.key_update().unwrap();
+        echo(&mut client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn reload_test() {
+        let dir = std::env::temp_dir().join("lightio_tls_reload");
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, testdata("server.pem")).unwrap();
+        fs::write(&key, testdata("server.key")).unwrap();
+        let config = TlsConfig::new(cert.display().to_string(), key.display().to_string()).reload_interval(Duration::ZERO);
+        let acceptor = TlsAcceptor::new(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            for socket in listener.incoming().take(3) {
+                let _ = acceptor.accept(socket.unwrap()).handshake();
+            }
+        });
+        let served = || connect(addr, &ClientOptions::default()).unwrap().server_chain[0].clone();
+        assert_eq!(leaf("server.pem"), served());
+
+        // a later modification time, the test may run within the file system's granularity
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::write(&cert, testdata("other.pem")).unwrap();
+        fs::write(&key, testdata("other.key")).unwrap();
+        for path in [&cert, &key] {
+            fs::File::options().write(true).open(path).unwrap().set_modified(later).unwrap();
+        }
+        assert_eq!(leaf("other.pem"), served());
+
+        // a key that does not match keeps the previous pair in use
+        fs::write(&key, testdata("server.key")).unwrap();
+        fs::File::options().write(true).open(&key).unwrap().set_modified(later + Duration::from_secs(10)).unwrap();
+        assert_eq!(leaf("other.pem"), served());
+        server.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
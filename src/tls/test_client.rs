@@ -0,0 +1,150 @@
+// A TLS client to test the server with, on rustls. It takes any server
+// certificate, the tests compare the chain they got instead.
+
+use super::{provider, testdata};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, SupportedCipherSuite};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+pub struct ClientOptions {
+    pub server_name: Option<&'static str>,
+    // all the provider has when empty
+    pub suites: Vec<SupportedCipherSuite>,
+    pub alpn: Vec<&'static [u8]>,
+    // test data file names of the certificate and key to offer when asked
+    pub identity: Option<(&'static str, &'static str)>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            server_name: Some("localhost"),
+            suites: Vec::new(),
+            alpn: Vec::new(),
+            identity: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AnyServer(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyServer {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub struct Client {
+    conn: ClientConnection,
+    socket: TcpStream,
+    pub suite: SupportedCipherSuite,
+    pub alpn: Option<Vec<u8>>,
+    // DER, leaf first
+    pub server_chain: Vec<Vec<u8>>,
+}
+
+fn other(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+impl Client {
+    pub fn connect(mut socket: TcpStream, options: &ClientOptions) -> io::Result<Client> {
+        let mut provider = Arc::unwrap_or_clone(provider());
+        if !options.suites.is_empty() {
+            provider.cipher_suites = options.suites.clone();
+        }
+        let provider = Arc::new(provider);
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyServer(provider)));
+        let mut config = match options.identity {
+            Some((cert, key)) => {
+                let chain = CertificateDer::pem_slice_iter(testdata(cert).as_bytes()).collect::<Result<_, _>>().unwrap();
+                let key = PrivateKeyDer::from_pem_slice(testdata(key).as_bytes()).unwrap();
+                builder.with_client_auth_cert(chain, key).map_err(other)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = options.alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        config.enable_sni = options.server_name.is_some();
+        let name = ServerName::try_from(options.server_name.unwrap_or("localhost")).unwrap().to_owned();
+        let mut conn = ClientConnection::new(Arc::new(config), name).map_err(other)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        Ok(Client {
+            suite: conn.negotiated_cipher_suite().expect("handshake done"),
+            alpn: conn.alpn_protocol().map(<[u8]>::to_vec),
+            server_chain: conn.peer_certificates().unwrap_or_default().iter().map(|cert| cert.to_vec()).collect(),
+            conn,
+            socket,
+        })
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.conn.writer().write_all(data)?;
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+
+    // what the server sent next, empty once it closed
+    pub fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            match self.conn.reader().read(&mut buf) {
+                Ok(n) => return Ok(buf[..n].to_vec()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            self.conn.complete_io(&mut self.socket)?;
+        }
+    }
+
+    // switches to new sending keys and asks the server to do the same
+    pub fn key_update(&mut self) -> io::Result<()> {
+        self.conn.refresh_traffic_keys().map_err(other)?;
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}